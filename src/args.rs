@@ -13,4 +13,34 @@ pub struct ServiceArguments {
 
     #[arg(long)]
     pub replicaof: Option<String>,
+
+    #[arg(long, num_args = 1..)]
+    pub bind: Vec<String>,
+
+    #[arg(long)]
+    pub unixsocket: Option<String>,
+
+    #[arg(long)]
+    pub hz: Option<usize>,
+
+    #[arg(long)]
+    pub ignore_rdb_checksum: bool,
+
+    #[arg(long)]
+    pub maxmemory: Option<usize>,
+
+    #[arg(long)]
+    pub maxmemory_policy: Option<String>,
+
+    #[arg(long)]
+    pub requirepass: Option<String>,
+
+    #[arg(long)]
+    pub timeout: Option<u64>,
+
+    #[arg(long)]
+    pub maxclients: Option<usize>,
+
+    #[arg(long)]
+    pub repl_backlog_size: Option<usize>,
 }
@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+
+use tokio::time::Instant;
+
+use crate::connection::{Connection, ConnectionKind};
+
+// what CLIENT LIST shows about a connection, refreshed after every command
+pub struct ClientInfo {
+    pub id: usize,
+    addr: String,
+    name: Option<String>,
+    db: usize,
+    flags: &'static str,
+    sub: usize,
+    psub: usize,
+    // the number of queued commands, None outside of MULTI
+    multi: Option<usize>,
+    connected_at: Instant,
+    last_interaction: Instant,
+    // lowercase, like in Redis
+    last_command: String,
+}
+
+// every connected client by id
+pub type Clients = HashMap<usize, ClientInfo>;
+
+impl ClientInfo {
+    pub fn of(conn: &Connection, command: &str) -> Self {
+        let flags = if conn.kind == ConnectionKind::Replica {
+            "S"
+        } else if conn.subscription_count() > 0 {
+            "P"
+        } else if conn.queued.is_some() {
+            "x"
+        } else {
+            "N"
+        };
+
+        ClientInfo {
+            id: conn.id,
+            addr: match conn.peer_addr {
+                Some(addr) => addr.to_string(),
+                None => "/unix".to_string(),
+            },
+            name: conn.name.clone(),
+            db: conn.db_index,
+            flags,
+            sub: conn.subscriptions.len(),
+            psub: conn.pattern_subscriptions.len(),
+            multi: conn.queued.as_ref().map(|queued| queued.len()),
+            connected_at: conn.connected_at,
+            last_interaction: Instant::now(),
+            last_command: command.to_lowercase(),
+        }
+    }
+
+    // one line of CLIENT LIST, with a subset of the fields Redis has
+    pub fn line(&self) -> String {
+        let multi = match self.multi {
+            Some(queued) => queued.to_string(),
+            None => "-1".to_string(),
+        };
+
+        format!(
+            "id={} addr={} name={} age={} idle={} flags={} db={} sub={} psub={} multi={} cmd={}",
+            self.id,
+            self.addr,
+            self.name.as_deref().unwrap_or_default(),
+            self.connected_at.elapsed().as_secs(),
+            self.last_interaction.elapsed().as_secs(),
+            self.flags,
+            self.db,
+            self.sub,
+            self.psub,
+            multi,
+            self.last_command,
+        )
+    }
+}
+
+// client names end up in CLIENT LIST, which is split on spaces and newlines
+pub fn is_valid_name(name: &str) -> bool {
+    name.chars().all(|c| ('!'..='~').contains(&c))
+}
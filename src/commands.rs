@@ -0,0 +1,198 @@
+// every command the server implements, as reported by COMMAND
+// and used to check the number of arguments before dispatching
+pub struct CommandSpec {
+    pub name: &'static str,
+    // same convention as Redis: the number of arguments including
+    // the command name, negative when that's only the minimum
+    pub arity: i64,
+    pub flags: &'static [&'static str],
+}
+
+pub const WRITE: &str = "write";
+pub const READONLY: &str = "readonly";
+pub const BLOCKING: &str = "blocking";
+pub const PUBSUB: &str = "pubsub";
+pub const ADMIN: &str = "admin";
+// may take more memory, refused once maxmemory is reached
+pub const DENY_OOM: &str = "denyoom";
+// refused inside MULTI, like Redis's no-multi flag
+pub const NO_MULTI: &str = "no_multi";
+
+pub const COMMANDS: &[CommandSpec] = &[
+    command("PING", -1, &[]),
+    command("ECHO", 2, &[]),
+    command("HELLO", -1, &[]),
+    command("AUTH", -2, &[]),
+    command("CLIENT", -2, &[]),
+    command("RESET", 1, &[]),
+    command("QUIT", -1, &[]),
+    command("COMMAND", -1, &[]),
+    command("MULTI", 1, &[]),
+    command("EXEC", 1, &[]),
+    command("DISCARD", 1, &[]),
+    command("WATCH", -2, &[]),
+    command("UNWATCH", 1, &[]),
+    command("SUBSCRIBE", -2, &[PUBSUB, NO_MULTI]),
+    command("UNSUBSCRIBE", -1, &[PUBSUB, NO_MULTI]),
+    command("PSUBSCRIBE", -2, &[PUBSUB, NO_MULTI]),
+    command("PUNSUBSCRIBE", -1, &[PUBSUB, NO_MULTI]),
+    command("PUBLISH", 3, &[PUBSUB]),
+    command("SET", -3, &[WRITE, DENY_OOM]),
+    command("SETNX", 3, &[WRITE, DENY_OOM]),
+    command("SETEX", 4, &[WRITE, DENY_OOM]),
+    command("GET", 2, &[READONLY]),
+    command("MSET", -3, &[WRITE, DENY_OOM]),
+    command("MGET", -2, &[READONLY]),
+    command("APPEND", 3, &[WRITE, DENY_OOM]),
+    command("STRLEN", 2, &[READONLY]),
+    command("GETSET", 3, &[WRITE, DENY_OOM]),
+    command("GETDEL", 2, &[WRITE]),
+    command("DEL", -2, &[WRITE]),
+    command("EXISTS", -2, &[READONLY]),
+    command("INCR", 2, &[WRITE, DENY_OOM]),
+    command("DECR", 2, &[WRITE, DENY_OOM]),
+    command("INCRBY", 3, &[WRITE, DENY_OOM]),
+    command("DECRBY", 3, &[WRITE, DENY_OOM]),
+    command("EXPIRE", -3, &[WRITE]),
+    command("PEXPIRE", -3, &[WRITE]),
+    command("EXPIREAT", -3, &[WRITE]),
+    command("PEXPIREAT", -3, &[WRITE]),
+    command("PERSIST", 2, &[WRITE]),
+    command("TYPE", 2, &[READONLY]),
+    command("OBJECT", -2, &[READONLY]),
+    command("COPY", -3, &[WRITE, DENY_OOM]),
+    command("TTL", 2, &[READONLY]),
+    command("PTTL", 2, &[READONLY]),
+    command("LPUSH", -3, &[WRITE, DENY_OOM]),
+    command("RPUSH", -3, &[WRITE, DENY_OOM]),
+    command("LPOP", -2, &[WRITE]),
+    command("RPOP", -2, &[WRITE]),
+    command("BLPOP", -3, &[WRITE, BLOCKING, NO_MULTI]),
+    command("BRPOP", -3, &[WRITE, BLOCKING, NO_MULTI]),
+    command("LLEN", 2, &[READONLY]),
+    command("LINDEX", 3, &[READONLY]),
+    command("LRANGE", 4, &[READONLY]),
+    command("HSET", -4, &[WRITE, DENY_OOM]),
+    command("HGET", 3, &[READONLY]),
+    command("HGETALL", 2, &[READONLY]),
+    command("HDEL", -3, &[WRITE]),
+    command("HINCRBY", 4, &[WRITE, DENY_OOM]),
+    command("HKEYS", 2, &[READONLY]),
+    command("HVALS", 2, &[READONLY]),
+    command("HLEN", 2, &[READONLY]),
+    command("HEXISTS", 3, &[READONLY]),
+    command("SADD", -3, &[WRITE, DENY_OOM]),
+    command("SREM", -3, &[WRITE]),
+    command("SMEMBERS", 2, &[READONLY]),
+    command("SISMEMBER", 3, &[READONLY]),
+    command("SCARD", 2, &[READONLY]),
+    command("SINTER", -2, &[READONLY]),
+    command("SUNION", -2, &[READONLY]),
+    command("SDIFF", -2, &[READONLY]),
+    command("ZADD", -4, &[WRITE, DENY_OOM]),
+    command("ZSCORE", 3, &[READONLY]),
+    command("ZRANK", -3, &[READONLY]),
+    command("ZRANGE", -4, &[READONLY]),
+    command("ZRANGEBYSCORE", -4, &[READONLY]),
+    command("SELECT", 2, &[]),
+    command("DBSIZE", 1, &[READONLY]),
+    command("FLUSHDB", -1, &[WRITE]),
+    command("FLUSHALL", -1, &[WRITE]),
+    command("SCAN", -2, &[READONLY]),
+    command("HSCAN", -3, &[READONLY]),
+    command("SSCAN", -3, &[READONLY]),
+    command("KEYS", 2, &[READONLY]),
+    command("SAVE", 1, &[ADMIN]),
+    command("BGSAVE", -1, &[ADMIN]),
+    command("SHUTDOWN", -1, &[ADMIN]),
+    command("CONFIG", -2, &[ADMIN]),
+    command("INFO", -1, &[]),
+    command("DEBUG", -2, &[ADMIN]),
+    command("REPLCONF", -2, &[ADMIN]),
+    command("PSYNC", -3, &[ADMIN, NO_MULTI]),
+    command("WAIT", 3, &[NO_MULTI]),
+];
+
+const fn command(name: &'static str, arity: i64, flags: &'static [&'static str]) -> CommandSpec {
+    CommandSpec { name, arity, flags }
+}
+
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS
+        .iter()
+        .find(|spec| spec.name.eq_ignore_ascii_case(name))
+}
+
+// string values and published messages are kept as they are, so they
+// may be any bytes. Every other argument has to be valid UTF-8
+pub fn is_binary_arg(name: &str, index: usize) -> bool {
+    match name {
+        "SET" | "SETNX" | "APPEND" | "GETSET" | "PUBLISH" => index == 1,
+        "SETEX" => index == 2,
+        "MSET" => index % 2 == 1,
+        "ECHO" => index == 0,
+        _ => false,
+    }
+}
+
+impl CommandSpec {
+    pub fn arity_matches(&self, len: usize) -> bool {
+        match self.arity {
+            arity if arity < 0 => len as i64 >= -arity,
+            arity => len as i64 == arity,
+        }
+    }
+
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.contains(&flag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_ignores_case() {
+        assert_eq!(lookup("get").unwrap().name, "GET");
+        assert!(lookup("NOSUCHCOMMAND").is_none());
+    }
+
+    #[test]
+    fn fixed_and_minimum_arity() {
+        let get = lookup("GET").unwrap();
+        assert!(!get.arity_matches(1));
+        assert!(get.arity_matches(2));
+        assert!(!get.arity_matches(3));
+
+        let del = lookup("DEL").unwrap();
+        assert!(!del.arity_matches(1));
+        assert!(del.arity_matches(2));
+        assert!(del.arity_matches(10));
+    }
+
+    #[test]
+    fn names_are_unique_and_uppercase() {
+        for (index, spec) in COMMANDS.iter().enumerate() {
+            assert_eq!(spec.name, spec.name.to_uppercase());
+            assert!(spec.arity != 0, "{}", spec.name);
+            assert!(
+                COMMANDS[index + 1..]
+                    .iter()
+                    .all(|other| other.name != spec.name),
+                "{} is listed twice",
+                spec.name
+            );
+        }
+    }
+
+    #[test]
+    fn only_values_and_messages_are_binary() {
+        assert!(is_binary_arg("SET", 1));
+        assert!(!is_binary_arg("SET", 0));
+        assert!(is_binary_arg("MSET", 3));
+        assert!(!is_binary_arg("MSET", 2));
+        assert!(is_binary_arg("SETEX", 2));
+        assert!(!is_binary_arg("LPUSH", 1));
+    }
+}
@@ -1,31 +1,199 @@
 use crate::args::ServiceArguments;
+use crate::glob;
+use anyhow::Result;
+use std::path::{Path, PathBuf};
 
 const DEFAULT_PORT: usize = 6379;
+const DEFAULT_BIND: &str = "127.0.0.1";
+const DEFAULT_DIR: &str = ".";
+const DEFAULT_DBFILENAME: &str = "dump.rdb";
+const DEFAULT_HZ: usize = 10;
+const DEFAULT_MAXCLIENTS: usize = 10000;
+const DEFAULT_REPL_BACKLOG_SIZE: usize = 1024 * 1024;
+// the same bounds Redis clamps hz to
+const MIN_HZ: usize = 1;
+const MAX_HZ: usize = 500;
+
+// every parameter known to CONFIG GET
+const PARAMETERS: [&str; 11] = [
+    "dir",
+    "dbfilename",
+    "port",
+    "bind",
+    "hz",
+    "maxmemory",
+    "maxmemory-policy",
+    "requirepass",
+    "timeout",
+    "maxclients",
+    "repl-backlog-size",
+];
+
+// what happens to a write that would take memory above maxmemory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MaxmemoryPolicy {
+    // the write is refused
+    #[default]
+    NoEviction,
+    // the least recently used keys make room for it
+    AllKeysLru,
+}
+
+impl MaxmemoryPolicy {
+    fn parse(policy: &str) -> Result<Self> {
+        match policy.to_lowercase().as_str() {
+            "noeviction" => Ok(MaxmemoryPolicy::NoEviction),
+            "allkeys-lru" => Ok(MaxmemoryPolicy::AllKeysLru),
+            _ => Err(anyhow::anyhow!("unsupported maxmemory-policy '{}'", policy)),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            MaxmemoryPolicy::NoEviction => "noeviction",
+            MaxmemoryPolicy::AllKeysLru => "allkeys-lru",
+        }
+    }
+}
 
 pub struct Config {
     pub port: usize,
+    // space separated addresses to listen on, like in redis.conf
+    pub bind: String,
+    pub unixsocket: Option<String>,
     pub dbfilename: Option<String>,
     pub dir: Option<String>,
     pub replicaof: Option<String>,
+    // how many times a second expired keys get evicted in the background
+    pub hz: usize,
+    // load RDB files even when their checksum doesn't match
+    pub ignore_rdb_checksum: bool,
+    // in bytes, 0 means no limit
+    pub maxmemory: usize,
+    pub maxmemory_policy: MaxmemoryPolicy,
+    // the password of the default user, None lets everyone in
+    pub requirepass: Option<String>,
+    // seconds a client may stay idle before it's disconnected, 0 means forever
+    pub timeout: u64,
+    // connections beyond this many are turned away
+    pub maxclients: usize,
+    // in bytes, how far a replica may fall behind before it has to be sent
+    // a new snapshot: more costs memory, less means more full resyncs
+    pub repl_backlog_size: usize,
 }
 
 impl Config {
-    pub fn from_args(args: ServiceArguments) -> Config {
-        Config {
+    pub fn from_args(args: ServiceArguments) -> Result<Config> {
+        let maxmemory_policy = match args.maxmemory_policy {
+            Some(policy) => MaxmemoryPolicy::parse(&policy)?,
+            None => MaxmemoryPolicy::default(),
+        };
+
+        Ok(Config {
             port: args.port.unwrap_or(DEFAULT_PORT),
+            bind: reformat_bind(args.bind),
+            unixsocket: args.unixsocket,
             dbfilename: args.dbfilename,
             dir: args.dir,
             replicaof: reformat_replicaof(args.replicaof),
-        }
+            hz: args.hz.unwrap_or(DEFAULT_HZ).clamp(MIN_HZ, MAX_HZ),
+            ignore_rdb_checksum: args.ignore_rdb_checksum,
+            maxmemory: args.maxmemory.unwrap_or(0),
+            maxmemory_policy,
+            requirepass: args.requirepass.filter(|password| !password.is_empty()),
+            timeout: args.timeout.unwrap_or(0),
+            maxclients: args.maxclients.unwrap_or(DEFAULT_MAXCLIENTS),
+            repl_backlog_size: args
+                .repl_backlog_size
+                .unwrap_or(DEFAULT_REPL_BACKLOG_SIZE)
+                .max(1),
+        })
     }
 
     pub fn get(&self, config_name: String) -> Option<String> {
         match config_name.as_str() {
             "dir" => self.dir.clone(),
             "dbfilename" => self.dbfilename.clone(),
+            "port" => Some(self.port.to_string()),
+            "bind" => Some(self.bind.clone()),
+            "hz" => Some(self.hz.to_string()),
+            "maxmemory" => Some(self.maxmemory.to_string()),
+            "maxmemory-policy" => Some(self.maxmemory_policy.name().to_string()),
+            "requirepass" => Some(self.requirepass.clone().unwrap_or_default()),
+            "timeout" => Some(self.timeout.to_string()),
+            "maxclients" => Some(self.maxclients.to_string()),
+            "repl-backlog-size" => Some(self.repl_backlog_size.to_string()),
             _ => None,
         }
     }
+
+    // where SAVE writes the dataset, falling back to the redis defaults
+    pub fn rdb_path(&self) -> PathBuf {
+        let dir = self.dir.as_deref().unwrap_or(DEFAULT_DIR);
+        let dbfilename = self.dbfilename.as_deref().unwrap_or(DEFAULT_DBFILENAME);
+
+        Path::new(dir).join(dbfilename)
+    }
+
+    // only the default user exists, and with no requirepass it takes any password
+    pub fn password_matches(&self, username: &str, password: &str) -> bool {
+        username == "default"
+            && self
+                .requirepass
+                .as_ref()
+                .is_none_or(|requirepass| requirepass == password)
+    }
+
+    pub fn get_matching(&self, pattern: &str) -> Vec<(String, String)> {
+        PARAMETERS
+            .iter()
+            .filter(|name| glob::matches(pattern, name))
+            .map(|name| {
+                let value = self.get(name.to_string()).unwrap_or_default();
+                (name.to_string(), value)
+            })
+            .collect()
+    }
+
+    // names are case-insensitive, CONFIG GET lowercases its patterns too
+    pub fn set(&mut self, config_name: String, value: String) -> Result<()> {
+        match config_name.to_lowercase().as_str() {
+            "dir" => self.dir = Some(value),
+            "dbfilename" => self.dbfilename = Some(value),
+            "hz" => {
+                let hz = value.parse::<usize>().map_err(|_| {
+                    anyhow::anyhow!("Invalid argument '{}' for CONFIG SET 'hz'", value)
+                })?;
+                self.hz = hz.clamp(MIN_HZ, MAX_HZ);
+            }
+            "maxmemory" => {
+                self.maxmemory = value.parse::<usize>().map_err(|_| {
+                    anyhow::anyhow!("Invalid argument '{}' for CONFIG SET 'maxmemory'", value)
+                })?;
+            }
+            "maxmemory-policy" => self.maxmemory_policy = MaxmemoryPolicy::parse(&value)?,
+            // an empty password turns authentication off, like in Redis
+            "requirepass" => self.requirepass = Some(value).filter(|password| !password.is_empty()),
+            "timeout" => {
+                self.timeout = value.parse::<u64>().map_err(|_| {
+                    anyhow::anyhow!("Invalid argument '{}' for CONFIG SET 'timeout'", value)
+                })?;
+            }
+            "maxclients" => {
+                self.maxclients = value.parse::<usize>().map_err(|_| {
+                    anyhow::anyhow!("Invalid argument '{}' for CONFIG SET 'maxclients'", value)
+                })?;
+            }
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Unknown option or number of arguments for CONFIG SET - '{}'",
+                    config_name
+                ))
+            }
+        }
+
+        Ok(())
+    }
 }
 
 fn reformat_replicaof(replicaof: Option<String>) -> Option<String> {
@@ -35,3 +203,95 @@ fn reformat_replicaof(replicaof: Option<String>) -> Option<String> {
 
     None
 }
+
+// --bind accepts both `--bind a b` and `--bind "a b"`
+fn reformat_bind(bind: Vec<String>) -> String {
+    let addresses: Vec<&str> = bind.iter().flat_map(|b| b.split_whitespace()).collect();
+
+    match addresses.is_empty() {
+        true => DEFAULT_BIND.to_string(),
+        false => addresses.join(" "),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn config(args: &[&str]) -> Config {
+        let args = std::iter::once("redis").chain(args.iter().copied());
+
+        Config::from_args(ServiceArguments::parse_from(args)).unwrap()
+    }
+
+    #[test]
+    fn defaults() {
+        let config = config(&[]);
+
+        assert_eq!(config.port, DEFAULT_PORT);
+        assert_eq!(config.bind, DEFAULT_BIND);
+        assert_eq!(config.rdb_path(), Path::new(".").join("dump.rdb"));
+        assert!(config.password_matches("default", "anything"));
+    }
+
+    #[test]
+    fn arguments_are_reformatted() {
+        let config = config(&[
+            "--replicaof",
+            "localhost 6380",
+            "--bind",
+            "127.0.0.1 ::1",
+            "--hz",
+            "1000",
+            "--requirepass",
+            "",
+        ]);
+
+        assert_eq!(config.replicaof.as_deref(), Some("localhost:6380"));
+        assert_eq!(config.bind, "127.0.0.1 ::1");
+        assert_eq!(config.hz, MAX_HZ);
+        assert_eq!(config.requirepass, None);
+    }
+
+    #[test]
+    fn set_then_get() {
+        let mut config = config(&[]);
+
+        config.set("DIR".to_string(), "/tmp".to_string()).unwrap();
+        config
+            .set("dbfilename".to_string(), "x.rdb".to_string())
+            .unwrap();
+        config.set("hz".to_string(), "0".to_string()).unwrap();
+
+        assert_eq!(config.get("dir".to_string()).as_deref(), Some("/tmp"));
+        assert_eq!(config.rdb_path(), Path::new("/tmp/x.rdb"));
+        assert_eq!(config.hz, MIN_HZ);
+    }
+
+    #[test]
+    fn set_rejects_unknown_names_and_bad_values() {
+        let mut config = config(&[]);
+
+        assert!(config.set("port".to_string(), "1".to_string()).is_err());
+        assert!(config.set("nope".to_string(), "1".to_string()).is_err());
+        assert!(config.set("timeout".to_string(), "-1".to_string()).is_err());
+        assert!(config
+            .set("maxmemory-policy".to_string(), "random".to_string())
+            .is_err());
+    }
+
+    #[test]
+    fn get_matching_globs_over_the_parameters() {
+        let config = config(&["--dir", "/data"]);
+
+        assert_eq!(
+            config.get_matching("d*"),
+            vec![
+                ("dir".to_string(), "/data".to_string()),
+                ("dbfilename".to_string(), String::new()),
+            ]
+        );
+        assert_eq!(config.get_matching("*").len(), PARAMETERS.len());
+    }
+}
@@ -1,55 +1,340 @@
-use crate::frame::Frame;
+use crate::frame::{Frame, Incomplete, ProtocolError};
 use anyhow::Result;
-use bytes::BytesMut;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use bytes::{Buf, BytesMut};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+static NEXT_CONNECTION_ID: AtomicUsize = AtomicUsize::new(1);
+
+// anything a client can talk to us over: a TCP or a Unix socket
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+// who is on the other end of a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionKind {
+    Client,
+    // a replica's connection to its master, carrying the replication stream
+    MasterLink,
+    // the master's side of a replica's connection, once it sent PSYNC
+    Replica,
+}
+
+impl ConnectionKind {
+    // the writes coming over the master link are applied without a reply
+    pub fn replies(&self) -> bool {
+        *self != ConnectionKind::MasterLink
+    }
+}
+
+// set with CLIENT REPLY
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplyMode {
+    On,
+    Off,
+    // only the reply to the next command is left out
+    Skip,
+}
 
 pub struct Connection {
-    pub stream: TcpStream,
+    pub id: usize,
+    pub kind: ConnectionKind,
+    stream: Box<dyn Stream>,
+    // None for clients connected over the Unix socket
+    pub peer_addr: Option<SocketAddr>,
+    pub db_index: usize,
+    // from CLIENT SETNAME
+    pub name: Option<String>,
+    pub connected_at: Instant,
+    // RESP version negotiated with HELLO
+    pub protocol: u8,
+    // set on the master side once the peer turns out to be a replica
+    pub listening_port: Option<usize>,
+    pub replica_id: Option<usize>,
+    // commands queued after MULTI, None outside of a transaction
+    pub queued: Option<Vec<Frame>>,
+    // keys from WATCH: database index, key and the version seen then
+    pub watched: Vec<(usize, String, u64)>,
+    // where messages for this connection's subscriptions are sent,
+    // set by the server when it starts handling the connection
+    pub messages: Option<UnboundedSender<Frame>>,
+    // subscribed channels along with the task forwarding their messages
+    pub subscriptions: HashMap<String, JoinHandle<()>>,
+    // same for the patterns from PSUBSCRIBE
+    pub pattern_subscriptions: HashMap<String, JoinHandle<()>>,
+    // how long a read may wait for the client to send anything, set by
+    // the server from the timeout config, None waits forever
+    pub idle_timeout: Option<Duration>,
+    // cleared by the server for clients that have to AUTH first
+    pub authenticated: bool,
+    pub reply_mode: ReplyMode,
+    // whether replies to the command being run are dropped, see begin_command
+    pub muted: bool,
+    // while EXEC runs, replies are collected here instead of being sent
+    pub captured: Option<Vec<Frame>>,
+    // set by QUIT, the server closes the connection after the command
+    pub quit: bool,
+    // whether the next thing read is an RDB snapshot rather than a frame
+    expecting_rdb: bool,
     buffer: BytesMut,
 }
 
 impl Connection {
     pub fn new(stream: TcpStream) -> Self {
+        let peer_addr = stream.peer_addr().ok();
+        Connection::with_stream(Box::new(stream), peer_addr)
+    }
+
+    pub fn from_unix(stream: UnixStream) -> Self {
+        Connection::with_stream(Box::new(stream), None)
+    }
+
+    fn with_stream(stream: Box<dyn Stream>, peer_addr: Option<SocketAddr>) -> Self {
         Connection {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            kind: ConnectionKind::Client,
             stream,
+            peer_addr,
+            db_index: 0,
+            name: None,
+            connected_at: Instant::now(),
+            protocol: 2,
+            listening_port: None,
+            replica_id: None,
+            queued: None,
+            watched: vec![],
+            messages: None,
+            subscriptions: HashMap::new(),
+            pattern_subscriptions: HashMap::new(),
+            idle_timeout: None,
+            authenticated: true,
+            reply_mode: ReplyMode::On,
+            muted: false,
+            captured: None,
+            quit: false,
+            expecting_rdb: false,
             buffer: BytesMut::with_capacity(512),
         }
     }
 
     pub async fn read_frames(&mut self) -> Result<Option<Vec<(Frame, usize)>>> {
-        let bytes_read = self.stream.read_buf(&mut self.buffer).await?;
+        // subscribers only listen, so they're never idle
+        let idle_timeout = self.idle_timeout.filter(|_| self.subscription_count() == 0);
+
+        loop {
+            let read = self.stream.read_buf(&mut self.buffer);
+            let bytes_read = match idle_timeout {
+                Some(idle_timeout) => tokio::time::timeout(idle_timeout, read)
+                    .await
+                    .map_err(|_| anyhow::anyhow!("idle for more than {:?}", idle_timeout))??,
+                None => read.await?,
+            };
 
-        if bytes_read == 0 {
-            return Ok(None);
+            if bytes_read == 0 {
+                return Ok(None);
+            }
+
+            let frames = self.parse_frames()?;
+
+            // if only a part of a frame has arrived so far, keep reading
+            if !frames.is_empty() {
+                return Ok(Some(frames));
+            }
         }
+    }
 
+    fn parse_frames(&mut self) -> Result<Vec<(Frame, usize)>> {
         let mut frames: Vec<(Frame, usize)> = vec![];
         let mut consumed_bytes = 0;
 
-        while consumed_bytes != bytes_read {
-            let (frame, bytes) =
-                Frame::parse_message(BytesMut::from(&self.buffer[consumed_bytes..]))?;
+        while consumed_bytes < self.buffer.len() {
+            let buffer = &self.buffer[consumed_bytes..];
+            let parsed = match self.expecting_rdb {
+                true => Frame::parse_rdb_contents(buffer),
+                false => Frame::parse_message(buffer),
+            };
 
-            frames.push((frame, bytes));
-            consumed_bytes += bytes;
+            match parsed {
+                Ok((frame, bytes)) => {
+                    // only the master link is sent a snapshot, right after FULLRESYNC
+                    self.expecting_rdb = self.kind == ConnectionKind::MasterLink
+                        && matches!(&frame, Frame::SimpleString(reply) if reply.starts_with("FULLRESYNC"));
+                    frames.push((frame, bytes));
+                    consumed_bytes += bytes;
+                }
+                Err(e) if e.is::<Incomplete>() => break,
+                Err(e) if e.is::<ProtocolError>() => return Err(e),
+                // e.g. a length that isn't a number
+                Err(e) => return Err(ProtocolError(e.to_string()).into()),
+            }
         }
 
-        self.buffer.clear();
-        Ok(Some(frames))
+        // keep the trailing bytes of a partial frame for the next read
+        self.buffer.advance(consumed_bytes);
+        Ok(frames)
+    }
+
+    // called before running every command, so CLIENT REPLY can take effect
+    pub fn begin_command(&mut self) {
+        self.muted = self.reply_mode != ReplyMode::On;
+
+        if self.reply_mode == ReplyMode::Skip {
+            self.reply_mode = ReplyMode::On;
+        }
+    }
+
+    pub fn subscription_count(&self) -> usize {
+        self.subscriptions.len() + self.pattern_subscriptions.len()
     }
 
     pub async fn write_frame(&mut self, frame: &Frame) -> Result<()> {
-        self.stream.write_all(frame.serialize().as_bytes()).await?;
+        if let Some(captured) = &mut self.captured {
+            captured.push(frame.clone());
+            return Ok(());
+        }
+
+        if self.muted {
+            return Ok(());
+        }
+
+        let serialized = match self.protocol {
+            2 => frame.to_resp2().serialize(),
+            _ => frame.serialize(),
+        };
+
+        self.stream.write_all(&serialized).await?;
         self.stream.flush().await?;
 
         Ok(())
     }
 
     pub async fn write(&mut self, contents: &[u8]) -> Result<()> {
-        self.stream.write(contents).await?;
+        self.stream.write_all(contents).await?;
         self.stream.flush().await?;
 
         Ok(())
     }
 }
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let subscriptions = self.subscriptions.values();
+
+        for forwarder in subscriptions.chain(self.pattern_subscriptions.values()) {
+            forwarder.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn reassembles_a_frame_sent_a_byte_at_a_time() {
+        let (client, server) = duplex(64);
+        let mut conn = Connection::with_stream(Box::new(server), None);
+
+        let writer = tokio::spawn(async move {
+            let mut client = client;
+            for byte in b"*2\r\n$4\r\nECHO\r\n$5\r\nhello\r\n" {
+                client.write_all(&[*byte]).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+            client
+        });
+
+        let frames = conn.read_frames().await.unwrap().unwrap();
+        writer.await.unwrap();
+
+        assert_eq!(
+            frames,
+            vec![(
+                Frame::Array(vec![
+                    Frame::BulkString("ECHO".into()),
+                    Frame::BulkString("hello".into()),
+                ]),
+                25
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_the_rest_of_a_partial_frame() {
+        let (mut client, server) = duplex(64);
+        let mut conn = Connection::with_stream(Box::new(server), None);
+
+        client.write_all(b"+OK\r\n:12").await.unwrap();
+        let frames = conn.read_frames().await.unwrap().unwrap();
+        assert_eq!(frames, vec![(Frame::SimpleString("OK".to_string()), 5)]);
+
+        client.write_all(b"3\r\n").await.unwrap();
+        let frames = conn.read_frames().await.unwrap().unwrap();
+        assert_eq!(frames, vec![(Frame::Integer(123), 6)]);
+    }
+
+    #[tokio::test]
+    async fn the_master_link_reads_a_snapshot_after_fullresync() {
+        let (mut master, replica) = duplex(64);
+        let mut conn = Connection::with_stream(Box::new(replica), None);
+        conn.kind = ConnectionKind::MasterLink;
+
+        master
+            .write_all(b"+FULLRESYNC id 0\r\n$9\r\nREDIS0011*1\r\n$4\r\nPING\r\n")
+            .await
+            .unwrap();
+        let frames = conn.read_frames().await.unwrap().unwrap();
+
+        assert_eq!(
+            frames,
+            vec![
+                (Frame::SimpleString("FULLRESYNC id 0".to_string()), 18),
+                (Frame::RDBContents("REDIS0011".into()), 13),
+                (Frame::Array(vec![Frame::BulkString("PING".into())]), 14),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_client_value_may_look_like_a_snapshot() {
+        let (mut client, server) = duplex(64);
+        let mut conn = Connection::with_stream(Box::new(server), None);
+
+        client
+            .write_all(b"+FULLRESYNC id 0\r\n$11\r\nREDIS123456\r\n")
+            .await
+            .unwrap();
+        let frames = conn.read_frames().await.unwrap().unwrap();
+
+        assert_eq!(frames[1], (Frame::BulkString("REDIS123456".into()), 18));
+    }
+
+    #[tokio::test]
+    async fn garbage_is_a_protocol_error() {
+        let (mut client, server) = duplex(64);
+        let mut conn = Connection::with_stream(Box::new(server), None);
+
+        client.write_all(b"*x\r\n").await.unwrap();
+        let result = conn.read_frames().await;
+
+        assert!(result.is_err_and(|e| e.is::<ProtocolError>()));
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_closes_a_silent_client() {
+        let (_client, server) = duplex(64);
+        let mut conn = Connection::with_stream(Box::new(server), None);
+        conn.idle_timeout = Some(Duration::from_millis(10));
+
+        assert!(conn.read_frames().await.is_err());
+    }
+}
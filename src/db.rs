@@ -1,29 +1,336 @@
-use std::collections::HashMap;
+use crate::sorted_set::SortedSet;
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{Mutex, MutexGuard, Notify};
 use tokio::time::Instant;
 
-#[derive(Debug)]
+// everything a key can hold, one variant per Redis data type
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(Bytes),
+    List(VecDeque<String>),
+    Hash(HashMap<String, String>),
+    Set(HashSet<String>),
+    SortedSet(SortedSet),
+}
+
+// what allocations and bookkeeping add to every key and every
+// element of a collection, on top of the bytes of the strings
+pub const ENTRY_OVERHEAD: usize = 48;
+
+// the thresholds below which Redis keeps values in a compact encoding
+const EMBSTR_MAX_LEN: usize = 44;
+const LISTPACK_MAX_ENTRIES: usize = 128;
+const LISTPACK_MAX_VALUE_LEN: usize = 64;
+const INTSET_MAX_ENTRIES: usize = 512;
+
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(1);
+
+// every write to a key gives it a new version, which is how
+// EXEC notices that a WATCHed key has changed
+pub fn next_version() -> u64 {
+    NEXT_VERSION.fetch_add(1, Ordering::Relaxed)
+}
+
+#[derive(Clone, Debug)]
 pub struct DbItem {
-    pub value: String,
-    pub created: Instant,
-    pub expires: usize,
+    pub value: Value,
+    // None for a key that never expires
+    pub expires_at: Option<Instant>,
+    pub version: u64,
+    // when the key was last written or read with GET, for maxmemory eviction
+    pub last_access: Instant,
 }
 
 impl DbItem {
-    pub fn new(value: String, created: Instant, expires: usize) -> Self {
+    pub fn new(value: Value, expires_at: Option<Instant>) -> Self {
         Self {
             value,
-            created,
-            expires,
+            expires_at,
+            version: next_version(),
+            last_access: Instant::now(),
         }
     }
 
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| Instant::now() > expires_at)
+    }
+
+    // the name reported by the TYPE command
+    pub fn type_name(&self) -> &'static str {
+        match self.value {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "zset",
+        }
+    }
+
+    // the name reported by OBJECT ENCODING, which is the encoding
+    // Redis would use for the value rather than anything we do
+    pub fn encoding(&self) -> &'static str {
+        match &self.value {
+            Value::String(value)
+                if value.len() <= 20
+                    && std::str::from_utf8(value).is_ok_and(|v| v.parse::<i64>().is_ok()) =>
+            {
+                "int"
+            }
+            Value::String(value) if value.len() <= EMBSTR_MAX_LEN => "embstr",
+            Value::String(_) => "raw",
+            Value::List(list) if fits_listpack(list.len(), list.iter()) => "listpack",
+            Value::List(_) => "quicklist",
+            Value::Hash(hash)
+                if fits_listpack(hash.len(), hash.iter().flat_map(|(f, v)| [f, v])) =>
+            {
+                "listpack"
+            }
+            Value::Hash(_) => "hashtable",
+            Value::Set(set)
+                if set.len() <= INTSET_MAX_ENTRIES
+                    && set.iter().all(|m| m.parse::<i64>().is_ok()) =>
+            {
+                "intset"
+            }
+            Value::Set(set) if fits_listpack(set.len(), set.iter()) => "listpack",
+            Value::Set(_) => "hashtable",
+            Value::SortedSet(sorted_set)
+                if fits_listpack(sorted_set.len(), sorted_set.iter().map(|(m, _)| m)) =>
+            {
+                "listpack"
+            }
+            Value::SortedSet(_) => "skiplist",
+        }
+    }
+
+    // a rough guess of the memory the key takes, good enough for maxmemory
+    pub fn estimated_size(&self, key: &str) -> usize {
+        let value_size = match &self.value {
+            Value::String(value) => value.len(),
+            Value::List(list) => list.iter().map(|e| element_size(e)).sum(),
+            Value::Hash(hash) => hash
+                .iter()
+                .map(|(field, value)| field.len() + value.len() + ENTRY_OVERHEAD)
+                .sum(),
+            Value::Set(set) => set.iter().map(|m| element_size(m)).sum(),
+            // a sorted set holds every member twice
+            Value::SortedSet(sorted_set) => sorted_set
+                .iter()
+                .map(|(member, _)| 2 * member.len() + ENTRY_OVERHEAD)
+                .sum(),
+        };
+
+        key.len() + value_size + ENTRY_OVERHEAD
+    }
+
     fn _default() -> Self {
         Self {
-            value: String::default(),
-            expires: 0,
-            created: Instant::now(),
+            value: Value::String(Bytes::new()),
+            expires_at: None,
+            version: next_version(),
+            last_access: Instant::now(),
         }
     }
 }
 
 pub type Db = HashMap<String, DbItem>;
+
+// what one element of a list or set adds to the size of the key
+pub fn element_size(element: &str) -> usize {
+    element.len() + ENTRY_OVERHEAD
+}
+
+// the memory the given keys take, the ones that don't exist take none
+pub fn keys_size(db: &Db, keys: &[String]) -> usize {
+    keys.iter()
+        .filter_map(|key| db.get(key).map(|db_item| db_item.estimated_size(key)))
+        .sum()
+}
+
+// the memory every key of the db takes, expired ones included
+pub fn db_size(db: &Db) -> usize {
+    db.iter()
+        .map(|(key, db_item)| db_item.estimated_size(key))
+        .sum()
+}
+
+// whether a collection is small enough for Redis to keep it as a listpack
+fn fits_listpack<'a>(len: usize, mut values: impl Iterator<Item = &'a String>) -> bool {
+    len <= LISTPACK_MAX_ENTRIES && values.all(|value| value.len() <= LISTPACK_MAX_VALUE_LEN)
+}
+
+// the version of a live key, None when there's no such key
+pub fn key_version(db: &Db, key: &str) -> Option<u64> {
+    db.get(key)
+        .filter(|db_item| !db_item.is_expired())
+        .map(|db_item| db_item.version)
+}
+
+// what WATCH remembers about a key: the version of a live key, or for a missing
+// one the version given out when a key was last deleted from the db, so that
+// creating and deleting it again still counts as a change
+pub fn watch_version(db: &Db, deleted: &AtomicU64, key: &str) -> u64 {
+    key_version(db, key).unwrap_or_else(|| deleted.load(Ordering::Relaxed))
+}
+
+// the keys of a db that were given a TTL, in the order the active expiry
+// goes through them. A key that was deleted or persisted since is only
+// dropped once its turn comes, so nothing else has to keep this up to date
+#[derive(Default)]
+pub struct ExpiryRing {
+    keys: VecDeque<String>,
+    tracked: HashSet<String>,
+}
+
+impl ExpiryRing {
+    pub fn track(&mut self, key: &str) {
+        if self.tracked.insert(key.to_string()) {
+            self.keys.push_back(key.to_string());
+        }
+    }
+}
+
+// checks the next `sample` keys of the ring and removes the expired
+// ones, taking what they used off `used`; returns how many were removed
+pub fn evict_expired(db: &mut Db, ring: &mut ExpiryRing, sample: usize, used: &mut usize) -> usize {
+    let mut removed = 0;
+
+    for _ in 0..sample.min(ring.keys.len()) {
+        let key = ring.keys.pop_front().unwrap();
+
+        match db.get(&key) {
+            Some(db_item) if db_item.is_expired() => {
+                *used = used.saturating_sub(db_item.estimated_size(&key));
+                db.remove(&key);
+                removed += 1;
+            }
+            // not due yet, back to the end of the line
+            Some(db_item) if db_item.expires_at.is_some() => {
+                ring.keys.push_back(key);
+                continue;
+            }
+            _ => {}
+        }
+
+        ring.tracked.remove(&key);
+    }
+
+    removed
+}
+
+pub const NUM_DATABASES: usize = 16;
+
+// always in index order, so two callers can't deadlock each other
+pub async fn lock_all(dbs: &[Arc<Mutex<Db>>]) -> Vec<MutexGuard<'_, Db>> {
+    let mut guards = Vec::with_capacity(dbs.len());
+    for db in dbs {
+        guards.push(db.lock().await);
+    }
+
+    guards
+}
+
+// clients waiting in BLPOP/BRPOP, by database index and list key
+pub type BlockedClients = HashMap<(usize, String), Vec<Arc<Notify>>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Duration;
+
+    fn string(value: &str, expires_at: Option<Instant>) -> DbItem {
+        DbItem::new(Value::String(value.to_string().into()), expires_at)
+    }
+
+    #[tokio::test]
+    async fn is_expired_flips_after_the_ttl() {
+        let db_item = string("v", Some(Instant::now() + Duration::from_millis(20)));
+        assert!(!db_item.is_expired());
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(db_item.is_expired());
+
+        assert!(!string("v", None).is_expired());
+    }
+
+    #[test]
+    fn string_encodings() {
+        assert_eq!(string("12345", None).encoding(), "int");
+        assert_eq!(string("-9223372036854775808", None).encoding(), "int");
+        assert_eq!(string("99999999999999999999", None).encoding(), "embstr");
+        assert_eq!(
+            string(&"a".repeat(EMBSTR_MAX_LEN), None).encoding(),
+            "embstr"
+        );
+        assert_eq!(
+            string(&"a".repeat(EMBSTR_MAX_LEN + 1), None).encoding(),
+            "raw"
+        );
+    }
+
+    #[test]
+    fn collection_encodings() {
+        let small: VecDeque<String> = (0..LISTPACK_MAX_ENTRIES).map(|i| i.to_string()).collect();
+        let mut large = small.clone();
+        large.push_back("one more".to_string());
+
+        assert_eq!(DbItem::new(Value::List(small), None).encoding(), "listpack");
+        assert_eq!(
+            DbItem::new(Value::List(large), None).encoding(),
+            "quicklist"
+        );
+
+        let numbers: HashSet<String> = (0..10).map(|i| i.to_string()).collect();
+        let words: HashSet<String> = ["a", "b"].iter().map(|w| w.to_string()).collect();
+        assert_eq!(DbItem::new(Value::Set(numbers), None).encoding(), "intset");
+        assert_eq!(DbItem::new(Value::Set(words), None).encoding(), "listpack");
+    }
+
+    #[test]
+    fn watch_version_changes_on_delete() {
+        let deleted = AtomicU64::new(0);
+        let mut db = Db::new();
+        let absent = watch_version(&db, &deleted, "k");
+
+        db.insert("k".to_string(), string("v", None));
+        let present = watch_version(&db, &deleted, "k");
+        assert_ne!(present, absent);
+
+        db.remove("k");
+        deleted.store(next_version(), Ordering::Relaxed);
+        assert_ne!(watch_version(&db, &deleted, "k"), absent);
+    }
+
+    #[test]
+    fn evict_expired_goes_around_the_ring() {
+        let past = Some(Instant::now() - Duration::from_secs(1));
+        let future = Some(Instant::now() + Duration::from_secs(60));
+        let mut db = Db::new();
+        let mut ring = ExpiryRing::default();
+
+        for i in 0..5 {
+            db.insert(format!("expired{i}"), string("v", past));
+            ring.track(&format!("expired{i}"));
+        }
+        db.insert("later".to_string(), string("v", future));
+        ring.track("later");
+        // deleted since it was tracked
+        ring.track("gone");
+        db.insert("forever".to_string(), string("v", None));
+
+        let mut used = db_size(&db);
+        assert_eq!(evict_expired(&mut db, &mut ring, 3, &mut used), 3);
+        assert_eq!(evict_expired(&mut db, &mut ring, 10, &mut used), 2);
+        assert_eq!(used, db_size(&db));
+
+        let mut keys: Vec<&String> = db.keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["forever", "later"]);
+        // only the key that's not due yet is left to look at
+        assert_eq!(ring.keys, vec!["later".to_string()]);
+    }
+}
@@ -1,68 +1,231 @@
 use anyhow::Result;
-use bytes::BytesMut;
+use bytes::Bytes;
+use thiserror::Error;
 
-#[derive(Clone, Debug)]
+// same limit as Redis, so a client that never sends CRLF
+// can't make us buffer forever
+const MAX_INLINE_SIZE: usize = 64 * 1024;
+
+// also from Redis, a declared length is never trusted beyond this
+const MAX_MULTIBULK_LENGTH: i64 = 1024 * 1024;
+
+// replies nest a few levels at most, anything deeper is refused
+// before the recursion can run out of stack
+const MAX_NESTING_DEPTH: usize = 32;
+
+// returned when the buffer holds only a part of a frame
+// and we should wait for more bytes before parsing again
+#[derive(Debug, Error)]
+#[error("Incomplete frame")]
+pub struct Incomplete;
+
+// the bytes can't be a frame no matter what follows them,
+// the client gets told so before its connection is closed
+#[derive(Debug, Error)]
+#[error("Protocol error: {0}")]
+pub struct ProtocolError(pub String);
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Frame {
     SimpleString(String),
-    BulkString(String),
+    Error(String),
+    BulkString(Bytes),
+    Integer(i64),
+    Double(f64),
     Array(Vec<Frame>),
-    RDBContents(),
+    Map(Vec<(Frame, Frame)>),
+    // RESP3 out-of-band data, e.g. pub/sub messages, an array in RESP2
+    Push(Vec<Frame>),
+    RDBContents(Bytes),
     NullBulkString,
+    NullArray,
 }
 
 impl Frame {
-    pub fn serialize(&self) -> String {
+    pub fn serialize(&self) -> Vec<u8> {
+        match self {
+            Frame::SimpleString(s) => format!("+{}\r\n", s).into_bytes(),
+            Frame::Error(msg) => format!("-{}\r\n", msg).into_bytes(),
+            Frame::BulkString(s) => {
+                let mut bytes = format!("${}\r\n", s.len()).into_bytes();
+                bytes.extend_from_slice(s);
+                bytes.extend_from_slice(b"\r\n");
+                bytes
+            }
+            Frame::Integer(n) => format!(":{}\r\n", n).into_bytes(),
+            Frame::Double(n) => format!(",{}\r\n", format_double(*n)).into_bytes(),
+            Frame::RDBContents(contents) => {
+                let mut bytes = format!("${}\r\n", contents.len()).into_bytes();
+                bytes.extend_from_slice(contents);
+                bytes
+            }
+            Frame::NullBulkString => b"$-1\r\n".to_vec(),
+            Frame::NullArray => b"*-1\r\n".to_vec(),
+            Frame::Array(values) => {
+                let mut bytes = format!("*{}\r\n", values.len()).into_bytes();
+                for val in values {
+                    bytes.extend(val.serialize());
+                }
+                bytes
+            }
+            Frame::Push(values) => {
+                let mut bytes = format!(">{}\r\n", values.len()).into_bytes();
+                for val in values {
+                    bytes.extend(val.serialize());
+                }
+                bytes
+            }
+            Frame::Map(entries) => {
+                let mut bytes = format!("%{}\r\n", entries.len()).into_bytes();
+                for (key, value) in entries {
+                    bytes.extend(key.serialize());
+                    bytes.extend(value.serialize());
+                }
+                bytes
+            }
+        }
+    }
+
+    // RESP2 clients don't know the RESP3 types, so those get
+    // replaced by their closest RESP2 equivalent
+    pub fn to_resp2(&self) -> Frame {
         match self {
-            Frame::SimpleString(s) => format!("+{}\r\n", s),
-            Frame::BulkString(s) => format!("${}\r\n{}\r\n", s.chars().count(), s),
-            Frame::RDBContents() => format!("$1\r\nrdbcontents\r\n"),
-            Frame::NullBulkString => "$-1\r\n".to_string(),
-            Frame::Array(values) => format!(
-                "*{}\r\n{}",
-                values.len(),
-                values
-                    .into_iter()
-                    .map(|val| val.serialize())
-                    .collect::<Vec<String>>()
-                    .join("")
+            Frame::Array(values) | Frame::Push(values) => {
+                Frame::Array(values.iter().map(Frame::to_resp2).collect())
+            }
+            Frame::Map(entries) => Frame::Array(
+                entries
+                    .iter()
+                    .flat_map(|(key, value)| [key.to_resp2(), value.to_resp2()])
+                    .collect(),
             ),
+            Frame::Double(n) => Frame::BulkString(format_double(*n).into()),
+            frame => frame.clone(),
         }
     }
 
-    pub fn parse_message(buffer: BytesMut) -> Result<(Frame, usize)> {
-        match buffer[0] as char {
-            '+' => parse_simple_string(buffer),
-            '*' => parse_array(buffer),
-            '$' => parse_bulk_string(buffer),
-            _ => Err(anyhow::anyhow!("Not a known value type {:?}", buffer)),
+    pub fn parse_message(buffer: &[u8]) -> Result<(Frame, usize)> {
+        parse_frame(buffer, 0)
+    }
+
+    // what follows FULLRESYNC: a bulk string header and the snapshot,
+    // without the CRLF a bulk string would end with
+    pub fn parse_rdb_contents(buffer: &[u8]) -> Result<(Frame, usize)> {
+        match buffer.first() {
+            None => return Err(Incomplete.into()),
+            Some(b'$') => {}
+            Some(_) => return Err(ProtocolError("expected an RDB snapshot".to_string()).into()),
+        }
+
+        let (length, bytes_consumed) = read_length(buffer)?;
+        let length =
+            usize::try_from(length).map_err(|_| ProtocolError("invalid RDB length".to_string()))?;
+
+        let end_of_contents = bytes_consumed + length;
+        if buffer.len() < end_of_contents {
+            return Err(Incomplete.into());
         }
+
+        let contents = Bytes::copy_from_slice(&buffer[bytes_consumed..end_of_contents]);
+        Ok((Frame::RDBContents(contents), end_of_contents))
+    }
+}
+
+fn parse_frame(buffer: &[u8], depth: usize) -> Result<(Frame, usize)> {
+    if buffer.is_empty() {
+        return Err(Incomplete.into());
+    }
+
+    match buffer[0] as char {
+        '+' => parse_simple_string(buffer),
+        '-' => parse_error(buffer),
+        '*' => parse_array(buffer, depth),
+        '>' => parse_push(buffer, depth),
+        '%' => parse_map(buffer, depth),
+        '$' => parse_bulk_string(buffer),
+        ':' => parse_integer(buffer),
+        ',' => parse_double(buffer),
+        _ => parse_inline(buffer),
+    }
+}
+
+// commands typed by hand, e.g. over telnet: "SET foo bar\r\n"
+fn parse_inline(buffer: &[u8]) -> Result<(Frame, usize)> {
+    if let Some((line, len)) = read_until_crlf(buffer) {
+        let line = String::from_utf8(line.to_vec())?;
+        let items = line
+            .split_whitespace()
+            .map(|item| Frame::BulkString(item.to_string().into()))
+            .collect();
+
+        return Ok((Frame::Array(items), len));
     }
+
+    if buffer.len() > MAX_INLINE_SIZE {
+        return Err(ProtocolError("too big inline request".to_string()).into());
+    }
+
+    Err(Incomplete.into())
 }
 
-fn parse_simple_string(buffer: BytesMut) -> Result<(Frame, usize)> {
+fn parse_simple_string(buffer: &[u8]) -> Result<(Frame, usize)> {
     if let Some((line, len)) = read_until_crlf(&buffer[1..]) {
-        let string = String::from_utf8(line.to_vec()).unwrap();
+        let string = String::from_utf8(line.to_vec())?;
 
         return Ok((Frame::SimpleString(string), len + 1));
     }
 
-    Err(anyhow::anyhow!("Invalid string {:?}", buffer))
+    Err(Incomplete.into())
+}
+
+fn parse_error(buffer: &[u8]) -> Result<(Frame, usize)> {
+    if let Some((line, len)) = read_until_crlf(&buffer[1..]) {
+        let message = String::from_utf8(line.to_vec())?;
+
+        return Ok((Frame::Error(message), len + 1));
+    }
+
+    Err(Incomplete.into())
 }
 
-fn parse_array(buffer: BytesMut) -> Result<(Frame, usize)> {
-    let (array_length, mut bytes_consumed) =
-        if let Some((line, len)) = read_until_crlf(&buffer[1..]) {
-            let array_length = parse_int(line)?;
+fn parse_integer(buffer: &[u8]) -> Result<(Frame, usize)> {
+    if let Some((line, len)) = read_until_crlf(&buffer[1..]) {
+        let integer = parse_int(line)?;
+
+        return Ok((Frame::Integer(integer), len + 1));
+    }
+
+    Err(Incomplete.into())
+}
 
-            (array_length, len + 1)
-        } else {
-            return Err(anyhow::anyhow!("Invalid array format {:?}", buffer));
+fn parse_double(buffer: &[u8]) -> Result<(Frame, usize)> {
+    if let Some((line, len)) = read_until_crlf(&buffer[1..]) {
+        let double = match String::from_utf8(line.to_vec())?.to_lowercase().as_str() {
+            "inf" | "+inf" => f64::INFINITY,
+            "-inf" => f64::NEG_INFINITY,
+            "nan" => f64::NAN,
+            number => number.parse::<f64>()?,
         };
 
-    let mut items = Vec::with_capacity(array_length as usize);
+        return Ok((Frame::Double(double), len + 1));
+    }
+
+    Err(Incomplete.into())
+}
+
+fn parse_array(buffer: &[u8], depth: usize) -> Result<(Frame, usize)> {
+    check_depth(depth)?;
+    let (array_length, mut bytes_consumed) = read_length(buffer)?;
+
+    if array_length == -1 {
+        return Ok((Frame::NullArray, bytes_consumed));
+    }
+
+    let array_length = check_multibulk_length(array_length)?;
+    let mut items = Vec::with_capacity(array_length.min(1024));
 
     for _ in 0..array_length {
-        let (array_item, len) = Frame::parse_message(BytesMut::from(&buffer[bytes_consumed..]))?;
+        let (array_item, len) = parse_frame(&buffer[bytes_consumed..], depth + 1)?;
         items.push(array_item);
         bytes_consumed += len;
     }
@@ -70,27 +233,88 @@ fn parse_array(buffer: BytesMut) -> Result<(Frame, usize)> {
     Ok((Frame::Array(items), bytes_consumed))
 }
 
-// this could either be a bulk string or RDB file contents
-// they look very similar (e.g. start with $)
-fn parse_bulk_string(buffer: BytesMut) -> Result<(Frame, usize)> {
-    let (bulk_str_len, bytes_consumed) = if let Some((line, len)) = read_until_crlf(&buffer[1..]) {
-        let bulk_str_len = parse_int(line)?;
+// laid out like an array, only the type byte differs
+fn parse_push(buffer: &[u8], depth: usize) -> Result<(Frame, usize)> {
+    match parse_array(buffer, depth)? {
+        (Frame::Array(items), bytes_consumed) => Ok((Frame::Push(items), bytes_consumed)),
+        _ => Err(ProtocolError("invalid push length".to_string()).into()),
+    }
+}
+
+fn parse_map(buffer: &[u8], depth: usize) -> Result<(Frame, usize)> {
+    check_depth(depth)?;
+    let (map_length, mut bytes_consumed) = read_length(buffer)?;
 
-        (bulk_str_len, len + 1)
-    } else {
-        return Err(anyhow::anyhow!("Invalid array format: {:?}", buffer));
-    };
+    let map_length = check_multibulk_length(map_length)?;
+    let mut entries = Vec::with_capacity(map_length.min(1024));
+
+    for _ in 0..map_length {
+        let (key, len) = parse_frame(&buffer[bytes_consumed..], depth + 1)?;
+        bytes_consumed += len;
+        let (value, len) = parse_frame(&buffer[bytes_consumed..], depth + 1)?;
+        bytes_consumed += len;
+        entries.push((key, value));
+    }
+
+    Ok((Frame::Map(entries), bytes_consumed))
+}
 
+fn parse_bulk_string(buffer: &[u8]) -> Result<(Frame, usize)> {
+    let (bulk_str_len, bytes_consumed) = read_length(buffer)?;
+
+    if bulk_str_len == -1 {
+        return Ok((Frame::NullBulkString, bytes_consumed));
+    }
+
+    if bulk_str_len < 0 {
+        return Err(ProtocolError("invalid bulk length".to_string()).into());
+    }
+
+    // because bulk strings contain \r\n at the end
     let end_of_bulk_str = bytes_consumed + bulk_str_len as usize;
+    if buffer.len() < end_of_bulk_str + 2 {
+        return Err(Incomplete.into());
+    }
+
     let content = &buffer[bytes_consumed..end_of_bulk_str];
+    Ok((
+        Frame::BulkString(Bytes::copy_from_slice(content)),
+        end_of_bulk_str + 2,
+    ))
+}
 
-    if content.len() >= 9 && content.starts_with(b"REDIS") {
-        Ok((Frame::RDBContents(), end_of_bulk_str))
+// the number after the type byte of an aggregate or a bulk
+// string, along with how many bytes that header took
+fn read_length(buffer: &[u8]) -> Result<(i64, usize)> {
+    match read_until_crlf(&buffer[1..]) {
+        Some((line, len)) => Ok((parse_int(line)?, len + 1)),
+        None => Err(Incomplete.into()),
+    }
+}
+
+fn check_depth(depth: usize) -> Result<()> {
+    if depth >= MAX_NESTING_DEPTH {
+        return Err(ProtocolError("too deeply nested multibulk".to_string()).into());
+    }
+
+    Ok(())
+}
+
+// the element count of an array or a map, which we preallocate for
+fn check_multibulk_length(length: i64) -> Result<usize> {
+    if !(0..=MAX_MULTIBULK_LENGTH).contains(&length) {
+        return Err(ProtocolError("invalid multibulk length".to_string()).into());
+    }
+
+    Ok(length as usize)
+}
+
+// Rust prints NaN as "NaN" but Redis spells it "nan"
+fn format_double(n: f64) -> String {
+    if n.is_nan() {
+        "nan".to_string()
     } else {
-        Ok((
-            Frame::BulkString(String::from_utf8(content.to_vec())?),
-            end_of_bulk_str + 2, // because bulk strings contain \r\n at the end
-        ))
+        n.to_string()
     }
 }
 
@@ -107,3 +331,192 @@ fn read_until_crlf(buffer: &[u8]) -> Option<(&[u8], usize)> {
 fn parse_int(buffer: &[u8]) -> Result<i64> {
     Ok(String::from_utf8(buffer.to_vec())?.parse::<i64>()?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(bytes: &[u8]) -> Result<(Frame, usize)> {
+        Frame::parse_message(bytes)
+    }
+
+    // parses the bytes as one whole frame and serializes it back
+    fn round_trip(bytes: &[u8]) -> Frame {
+        let (frame, len) = parse(bytes).unwrap();
+        assert_eq!(len, bytes.len());
+        assert_eq!(frame.serialize(), bytes);
+        frame
+    }
+
+    fn is_protocol_error(result: Result<(Frame, usize)>) -> bool {
+        result.is_err_and(|e| e.is::<ProtocolError>())
+    }
+
+    #[test]
+    fn integer_round_trip() {
+        assert_eq!(round_trip(b":-1000\r\n"), Frame::Integer(-1000));
+        assert_eq!(round_trip(b":0\r\n"), Frame::Integer(0));
+        assert_eq!(
+            round_trip(b":9223372036854775807\r\n"),
+            Frame::Integer(i64::MAX)
+        );
+    }
+
+    #[test]
+    fn bulk_string_length_counts_bytes() {
+        let frame = Frame::BulkString("héllo 👋".into());
+
+        assert_eq!(frame.serialize(), "$11\r\nhéllo 👋\r\n".as_bytes());
+        assert_eq!(round_trip("$11\r\nhéllo 👋\r\n".as_bytes()), frame);
+    }
+
+    #[test]
+    fn bulk_string_with_nul_byte() {
+        let frame = Frame::BulkString(Bytes::from_static(b"a\0b\xff"));
+
+        assert_eq!(frame.serialize(), b"$4\r\na\0b\xff\r\n");
+        assert_eq!(round_trip(b"$4\r\na\0b\xff\r\n"), frame);
+    }
+
+    #[test]
+    fn every_prefix_is_incomplete() {
+        let bytes = b"*2\r\n$3\r\nGET\r\n$5\r\nhello\r\n";
+
+        for end in 0..bytes.len() {
+            let result = parse(&bytes[..end]);
+            assert!(result.is_err_and(|e| e.is::<Incomplete>()), "{end}");
+        }
+        round_trip(bytes);
+    }
+
+    #[test]
+    fn inline_command() {
+        let (frame, len) = parse(b"SET foo bar\r\n").unwrap();
+
+        assert_eq!(len, 13);
+        assert_eq!(
+            frame,
+            Frame::Array(vec![
+                Frame::BulkString("SET".into()),
+                Frame::BulkString("foo".into()),
+                Frame::BulkString("bar".into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn inline_command_without_crlf_is_capped() {
+        assert!(parse(b"SET foo").is_err_and(|e| e.is::<Incomplete>()));
+        assert!(is_protocol_error(parse(&[b'a'; MAX_INLINE_SIZE + 1])));
+    }
+
+    #[test]
+    fn map_round_trip() {
+        let frame = round_trip(b"%2\r\n+a\r\n:1\r\n$1\r\nb\r\n:2\r\n");
+
+        assert_eq!(
+            frame,
+            Frame::Map(vec![
+                (Frame::SimpleString("a".to_string()), Frame::Integer(1)),
+                (Frame::BulkString("b".into()), Frame::Integer(2)),
+            ])
+        );
+        assert_eq!(
+            frame.to_resp2().serialize(),
+            b"*4\r\n+a\r\n:1\r\n$1\r\nb\r\n:2\r\n"
+        );
+    }
+
+    #[test]
+    fn double_round_trip() {
+        assert_eq!(round_trip(b",1.5\r\n"), Frame::Double(1.5));
+        assert_eq!(round_trip(b",-2.25\r\n"), Frame::Double(-2.25));
+        assert_eq!(round_trip(b",inf\r\n"), Frame::Double(f64::INFINITY));
+        assert_eq!(round_trip(b",-inf\r\n"), Frame::Double(f64::NEG_INFINITY));
+        assert_eq!(Frame::Double(f64::NAN).serialize(), b",nan\r\n");
+        assert_eq!(
+            Frame::Double(1.5).to_resp2(),
+            Frame::BulkString("1.5".into())
+        );
+    }
+
+    #[test]
+    fn null_array_round_trip() {
+        assert_eq!(round_trip(b"*-1\r\n"), Frame::NullArray);
+        assert_eq!(round_trip(b"$-1\r\n"), Frame::NullBulkString);
+    }
+
+    #[test]
+    fn push_is_an_array_in_resp2() {
+        let frame = Frame::Push(vec![Frame::BulkString("message".into())]);
+
+        assert_eq!(frame.serialize(), b">1\r\n$7\r\nmessage\r\n");
+        assert_eq!(frame.to_resp2().serialize(), b"*1\r\n$7\r\nmessage\r\n");
+    }
+
+    #[test]
+    fn push_round_trip() {
+        assert_eq!(
+            round_trip(b">2\r\n$7\r\nmessage\r\n:1\r\n"),
+            Frame::Push(vec![Frame::BulkString("message".into()), Frame::Integer(1)])
+        );
+        assert!(is_protocol_error(parse(b">-1\r\n")));
+    }
+
+    #[test]
+    fn rdb_contents_have_no_trailing_crlf() {
+        let (frame, len) = Frame::parse_rdb_contents(b"$9\r\nREDIS0011+OK\r\n").unwrap();
+
+        assert_eq!(frame, Frame::RDBContents("REDIS0011".into()));
+        assert_eq!(len, 13);
+        assert!(Frame::parse_rdb_contents(b"$9\r\nREDIS").is_err_and(|e| e.is::<Incomplete>()));
+    }
+
+    #[test]
+    fn a_bulk_string_may_look_like_a_snapshot() {
+        assert_eq!(
+            round_trip(b"$11\r\nREDIS123456\r\n"),
+            Frame::BulkString("REDIS123456".into())
+        );
+    }
+
+    #[test]
+    fn deep_nesting_is_a_protocol_error() {
+        let mut bytes = b"*1\r\n".repeat(100_000);
+        bytes.extend(b"$1\r\na\r\n");
+        assert!(is_protocol_error(parse(&bytes)));
+
+        // and nesting within the limit is fine
+        let mut bytes = b"*1\r\n".repeat(MAX_NESTING_DEPTH);
+        bytes.extend(b"$1\r\na\r\n");
+        assert!(parse(&bytes).is_ok());
+    }
+
+    #[test]
+    fn many_elements_parse_in_linear_time() {
+        let mut bytes = b"*200000\r\n".to_vec();
+        bytes.extend(b"$1\r\na\r\n".repeat(200_000));
+
+        let started = std::time::Instant::now();
+        let (frame, len) = parse(&bytes).unwrap();
+
+        assert_eq!(len, bytes.len());
+        assert!(matches!(frame, Frame::Array(items) if items.len() == 200_000));
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn invalid_lengths_are_protocol_errors() {
+        assert!(is_protocol_error(parse(b"*1000000000000\r\n")));
+        assert!(is_protocol_error(parse(b"*-5\r\n")));
+        assert!(is_protocol_error(parse(b"%-3\r\n")));
+        assert!(is_protocol_error(parse(b"*1\r\n$-5\r\n")));
+        assert!(is_protocol_error(parse(b"$-2\r\n")));
+    }
+
+    #[test]
+    fn garbage_length_is_an_error() {
+        assert!(parse(b"*x\r\n").is_err());
+        assert!(parse(b":12a\r\n").is_err());
+    }
+}
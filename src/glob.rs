@@ -0,0 +1,146 @@
+// glob-style matching as used by KEYS, SCAN and friends:
+// `*` matches any sequence, `?` matches a single character,
+// `[abc]`, `[^abc]` and `[a-z]` match character classes,
+// `\` escapes the next character
+pub fn matches(pattern: &str, string: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let string: Vec<char> = string.chars().collect();
+
+    matches_from(&pattern, &string)
+}
+
+fn matches_from(pattern: &[char], string: &[char]) -> bool {
+    let (mut p, mut s) = (0, 0);
+
+    while p < pattern.len() {
+        match pattern[p] {
+            '*' => {
+                // collapse consecutive stars, they mean the same thing
+                while p < pattern.len() && pattern[p] == '*' {
+                    p += 1;
+                }
+
+                if p == pattern.len() {
+                    return true;
+                }
+
+                return (s..=string.len()).any(|i| matches_from(&pattern[p..], &string[i..]));
+            }
+            '?' => {
+                if s == string.len() {
+                    return false;
+                }
+                s += 1;
+                p += 1;
+            }
+            '[' => {
+                if s == string.len() {
+                    return false;
+                }
+
+                let (matched, class_len) = match_class(&pattern[p + 1..], string[s]);
+                if !matched {
+                    return false;
+                }
+                s += 1;
+                p += class_len + 1;
+            }
+            c => {
+                let c = if c == '\\' && p + 1 < pattern.len() {
+                    p += 1;
+                    pattern[p]
+                } else {
+                    c
+                };
+
+                if s == string.len() || string[s] != c {
+                    return false;
+                }
+                s += 1;
+                p += 1;
+            }
+        }
+    }
+
+    s == string.len()
+}
+
+// matches `c` against a class whose body starts right after the `[`,
+// returning whether it matched and the length of the class including `]`
+fn match_class(class: &[char], c: char) -> (bool, usize) {
+    let mut i = 0;
+    let negate = class.first() == Some(&'^');
+    if negate {
+        i += 1;
+    }
+
+    let mut matched = false;
+
+    while i < class.len() && class[i] != ']' {
+        if class[i] == '\\' && i + 1 < class.len() {
+            i += 1;
+            matched |= class[i] == c;
+            i += 1;
+        } else if i + 2 < class.len() && class[i + 1] == '-' && class[i + 2] != ']' {
+            let (start, end) = if class[i] <= class[i + 2] {
+                (class[i], class[i + 2])
+            } else {
+                (class[i + 2], class[i])
+            };
+            matched |= start <= c && c <= end;
+            i += 3;
+        } else {
+            matched |= class[i] == c;
+            i += 1;
+        }
+    }
+
+    // an unterminated class consumes the rest of the pattern
+    let class_len = if i < class.len() { i + 1 } else { i };
+
+    (matched != negate, class_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+
+    #[test]
+    fn star() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("user:*", "user:1000"));
+        assert!(matches("a**b", "axxb"));
+        assert!(!matches("user:*", "users"));
+    }
+
+    #[test]
+    fn question_mark() {
+        assert!(matches("h?llo", "hello"));
+        assert!(matches("h?llo", "hallo"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(!matches("h?llo", "heello"));
+        assert!(matches("?", "é"));
+    }
+
+    #[test]
+    fn character_classes() {
+        assert!(matches("h[ae]llo", "hello"));
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-b]llo", "hbllo"));
+        assert!(matches("h[b-a]llo", "hallo"));
+        assert!(!matches("h[a-b]llo", "hcllo"));
+    }
+
+    #[test]
+    fn escaping() {
+        assert!(matches("h\\*llo", "h*llo"));
+        assert!(!matches("h\\*llo", "hello"));
+        assert!(matches("h\\?llo", "h?llo"));
+        assert!(!matches("h\\?llo", "hello"));
+        assert!(matches("[\\]]", "]"));
+    }
+}
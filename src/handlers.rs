@@ -1,228 +1,3176 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::RangeInclusive;
+use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
+use std::time::SystemTime;
 
-use crate::connection::Connection;
-use crate::db::{Db, DbItem};
+use crate::clients::{self, ClientInfo, Clients};
+use crate::commands::{self, CommandSpec};
+use crate::connection::{Connection, ReplyMode};
+use crate::db::{
+    element_size, lock_all, watch_version, BlockedClients, Db, DbItem, Value, NUM_DATABASES,
+};
 use crate::frame::Frame;
-use crate::replication::{ReplRole, ReplicationConfig};
+use crate::glob;
+use crate::pubsub::{self, Channels};
+use crate::rdb;
+use crate::replication::{
+    replconf_ack, PsyncReply, ReplRole, ReplicationConfig, SharedReplicationConfig,
+};
+use crate::sorted_set::SortedSet;
 use crate::Config;
 use anyhow::Result;
-use tokio::sync::broadcast::Sender;
-use tokio::sync::Mutex;
-use tokio::time::Instant;
+use bytes::Bytes;
+use tokio::sync::broadcast::{error::RecvError, Receiver, Sender};
+use tokio::sync::{Mutex, Notify};
+use tokio::time::{Duration, Instant};
+
+const REDIS_VERSION: &str = "7.2.0";
 
 pub async fn handle_echo(conn: &mut Connection, what: Frame) {
     conn.write_frame(&what).await.unwrap();
 }
 
-pub async fn handle_ping(conn: &mut Connection, reply: bool) {
-    if reply {
-        conn.write_frame(&Frame::SimpleString("PONG".to_string()))
-            .await
-            .unwrap();
-    }
+pub async fn handle_ping(conn: &mut Connection, reply: bool) {
+    // in subscribe mode the reply has to look like a message
+    let resp_frame = match conn.protocol == 2 && conn.subscription_count() > 0 {
+        true => Frame::Array(vec![
+            Frame::BulkString("pong".into()),
+            Frame::BulkString("".into()),
+        ]),
+        false => Frame::SimpleString("PONG".to_string()),
+    };
+
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+// COMMAND DOCS stays empty, redis-cli only needs a well-formed reply to it
+pub async fn handle_command(conn: &mut Connection, args: &[Frame]) {
+    let subcommand = match args.first() {
+        Some(subcommand) => unpack_bulk_str(subcommand.clone()).unwrap().to_uppercase(),
+        None => String::new(),
+    };
+
+    let resp_frame = match subcommand.as_str() {
+        "COUNT" => Frame::Integer(commands::COMMANDS.len() as i64),
+        "" => Frame::Array(commands::COMMANDS.iter().map(command_info).collect()),
+        // an unknown name gets a nil in its place
+        "INFO" => Frame::Array(
+            args[1..]
+                .iter()
+                .map(|name| {
+                    let name = unpack_bulk_str(name.clone()).unwrap();
+                    commands::lookup(&name).map_or(Frame::NullArray, command_info)
+                })
+                .collect(),
+        ),
+        "DOCS" => Frame::Array(vec![]),
+        _ => Frame::Error(format!(
+            "ERR unknown subcommand '{}'. Try COMMAND HELP.",
+            subcommand
+        )),
+    };
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+// only what test harnesses tend to rely on, the rest of DEBUG has no use here
+pub async fn handle_debug(conn: &mut Connection, args: &[Frame]) {
+    let subcommand = unpack_bulk_str(args[0].clone()).unwrap().to_uppercase();
+
+    let resp_frame = match subcommand.as_str() {
+        "SLEEP" => {
+            let seconds = args.get(1).cloned().map_or(Some(0.0), parse_f64_arg);
+
+            // negative, not finite or too long for a Duration
+            match seconds.and_then(|seconds| Duration::try_from_secs_f64(seconds).ok()) {
+                Some(duration) => {
+                    tokio::time::sleep(duration).await;
+                    Frame::SimpleString("OK".to_string())
+                }
+                None => Frame::Error("ERR value is not a valid float".to_string()),
+            }
+        }
+        "JMAP" | "SET-ACTIVE-EXPIRE" => Frame::SimpleString("OK".to_string()),
+        _ => Frame::Error(format!(
+            "ERR unknown subcommand '{}'. Try DEBUG HELP.",
+            subcommand
+        )),
+    };
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+fn command_info(spec: &CommandSpec) -> Frame {
+    Frame::Array(vec![
+        Frame::BulkString(spec.name.to_lowercase().into()),
+        Frame::Integer(spec.arity),
+        Frame::Array(
+            spec.flags
+                .iter()
+                .map(|flag| Frame::SimpleString(flag.to_string()))
+                .collect(),
+        ),
+    ])
+}
+
+pub async fn handle_client(conn: &mut Connection, clients: Arc<Mutex<Clients>>, args: &[Frame]) {
+    let subcommand = unpack_bulk_str(args[0].clone()).unwrap().to_uppercase();
+
+    let resp_frame = match (subcommand.as_str(), args.get(1)) {
+        ("ID", _) => Frame::Integer(conn.id as i64),
+        ("GETNAME", _) => match &conn.name {
+            Some(name) => Frame::BulkString(name.clone().into()),
+            None => Frame::NullBulkString,
+        },
+        ("SETNAME", Some(name)) if args.len() == 2 => {
+            let name = unpack_bulk_str(name.clone()).unwrap();
+
+            match clients::is_valid_name(&name) {
+                true => {
+                    // an empty name removes the one set before
+                    conn.name = Some(name).filter(|name| !name.is_empty());
+                    Frame::SimpleString("OK".to_string())
+                }
+                false => Frame::Error(
+                    "ERR Client names cannot contain spaces, newlines or special characters."
+                        .to_string(),
+                ),
+            }
+        }
+        ("SETNAME", _) => wrong_arity("client|setname"),
+        ("REPLY", Some(mode)) => {
+            let mode = unpack_bulk_str(mode.clone()).unwrap().to_uppercase();
+
+            match mode.as_str() {
+                "ON" => conn.reply_mode = ReplyMode::On,
+                "OFF" => conn.reply_mode = ReplyMode::Off,
+                "SKIP" => conn.reply_mode = ReplyMode::Skip,
+                _ => {
+                    let resp_frame = Frame::Error("ERR syntax error".to_string());
+                    return conn.write_frame(&resp_frame).await.unwrap();
+                }
+            }
+
+            // OFF and SKIP aren't replied to either
+            conn.muted = conn.reply_mode != ReplyMode::On;
+            Frame::SimpleString("OK".to_string())
+        }
+        // accepted for the sake of client libraries, neither applies here
+        ("NO-EVICT" | "NO-TOUCH", Some(switch)) => {
+            let switch = unpack_bulk_str(switch.clone()).unwrap().to_uppercase();
+
+            match switch.as_str() {
+                "ON" | "OFF" => Frame::SimpleString("OK".to_string()),
+                _ => Frame::Error("ERR syntax error".to_string()),
+            }
+        }
+        ("REPLY" | "NO-EVICT" | "NO-TOUCH", None) => {
+            wrong_arity(&format!("client|{}", subcommand.to_lowercase()))
+        }
+        ("LIST", _) => {
+            let mut clients = clients.lock().await;
+            clients.insert(conn.id, ClientInfo::of(conn, "client|list"));
+
+            let mut clients: Vec<&ClientInfo> = clients.values().collect();
+            clients.sort_by_key(|client| client.id);
+
+            let list: String = clients.iter().map(|client| client.line() + "\n").collect();
+            Frame::BulkString(list.into())
+        }
+        _ => Frame::Error(format!(
+            "ERR unknown subcommand '{}'. Try CLIENT HELP.",
+            subcommand
+        )),
+    };
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+pub async fn handle_multi(conn: &mut Connection) {
+    let resp_frame = match conn.queued {
+        Some(_) => Frame::Error("ERR MULTI calls can not be nested".to_string()),
+        None => {
+            conn.queued = Some(vec![]);
+            Frame::SimpleString("OK".to_string())
+        }
+    };
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+pub async fn handle_discard(conn: &mut Connection) {
+    conn.watched.clear();
+
+    let resp_frame = match conn.queued.take() {
+        Some(_) => Frame::SimpleString("OK".to_string()),
+        None => Frame::Error("ERR DISCARD without MULTI".to_string()),
+    };
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+// the server closes the connection once the reply is out
+pub async fn handle_quit(conn: &mut Connection) {
+    conn.write_frame(&Frame::SimpleString("OK".to_string()))
+        .await
+        .unwrap();
+    conn.quit = true;
+}
+
+// back to how a new connection starts, without the replies UNSUBSCRIBE would send
+pub async fn handle_reset(
+    conn: &mut Connection,
+    channels: Arc<Mutex<Channels>>,
+    patterns: Arc<Mutex<Channels>>,
+    config: Arc<Mutex<Config>>,
+) {
+    conn.queued = None;
+    conn.watched.clear();
+
+    let mut channels = channels.lock().await;
+    for (channel, forwarder) in conn.subscriptions.drain() {
+        pubsub::unsubscribe(&mut channels, &channel, forwarder).await;
+    }
+    drop(channels);
+
+    let mut patterns = patterns.lock().await;
+    for (pattern, forwarder) in conn.pattern_subscriptions.drain() {
+        pubsub::unsubscribe(&mut patterns, &pattern, forwarder).await;
+    }
+    drop(patterns);
+
+    conn.db_index = 0;
+    conn.protocol = 2;
+    conn.name = None;
+    conn.reply_mode = ReplyMode::On;
+    conn.muted = false;
+    conn.authenticated = config.lock().await.requirepass.is_none();
+
+    conn.write_frame(&Frame::SimpleString("RESET".to_string()))
+        .await
+        .unwrap();
+}
+
+pub async fn handle_watch(
+    conn: &mut Connection,
+    db: Arc<Mutex<Db>>,
+    deleted: &AtomicU64,
+    keys: &[Frame],
+) {
+    if conn.queued.is_some() {
+        let resp_frame = Frame::Error("ERR WATCH inside MULTI is not allowed".to_string());
+        return conn.write_frame(&resp_frame).await.unwrap();
+    }
+
+    let db = db.lock().await;
+
+    for key in keys {
+        let key = unpack_bulk_str(key.clone()).unwrap();
+        let version = watch_version(&db, deleted, &key);
+
+        conn.watched.push((conn.db_index, key, version));
+    }
+
+    conn.write_frame(&Frame::SimpleString("OK".to_string()))
+        .await
+        .unwrap();
+}
+
+pub async fn handle_unwatch(conn: &mut Connection) {
+    conn.watched.clear();
+
+    conn.write_frame(&Frame::SimpleString("OK".to_string()))
+        .await
+        .unwrap();
+}
+
+pub async fn handle_subscribe(
+    conn: &mut Connection,
+    channels: Arc<Mutex<Channels>>,
+    args: &[Frame],
+) {
+    subscribe_all(conn, channels, args, false).await
+}
+
+pub async fn handle_psubscribe(
+    conn: &mut Connection,
+    patterns: Arc<Mutex<Channels>>,
+    args: &[Frame],
+) {
+    subscribe_all(conn, patterns, args, true).await
+}
+
+// without arguments, unsubscribes from every channel
+pub async fn handle_unsubscribe(
+    conn: &mut Connection,
+    channels: Arc<Mutex<Channels>>,
+    args: &[Frame],
+) {
+    unsubscribe_all(conn, channels, args, false).await
+}
+
+// without arguments, unsubscribes from every pattern
+pub async fn handle_punsubscribe(
+    conn: &mut Connection,
+    patterns: Arc<Mutex<Channels>>,
+    args: &[Frame],
+) {
+    unsubscribe_all(conn, patterns, args, true).await
+}
+
+// SUBSCRIBE and PSUBSCRIBE, replying once per channel or pattern
+// with the number of all subscriptions the connection has
+async fn subscribe_all(
+    conn: &mut Connection,
+    channels: Arc<Mutex<Channels>>,
+    args: &[Frame],
+    pattern: bool,
+) {
+    let mut channels = channels.lock().await;
+    let kind = if pattern { "psubscribe" } else { "subscribe" };
+
+    for channel in args {
+        let channel = unpack_bulk_str(channel.clone()).unwrap();
+        let messages = conn.messages.clone().unwrap();
+
+        let subscriptions = match pattern {
+            true => &mut conn.pattern_subscriptions,
+            false => &mut conn.subscriptions,
+        };
+
+        if !subscriptions.contains_key(&channel) {
+            let forwarder = pubsub::subscribe(&mut channels, &channel, messages);
+            subscriptions.insert(channel.clone(), forwarder);
+        }
+
+        let resp_frame = Frame::Push(vec![
+            Frame::BulkString(kind.into()),
+            Frame::BulkString(channel.into()),
+            Frame::Integer(conn.subscription_count() as i64),
+        ]);
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+// UNSUBSCRIBE and PUNSUBSCRIBE
+async fn unsubscribe_all(
+    conn: &mut Connection,
+    channels: Arc<Mutex<Channels>>,
+    args: &[Frame],
+    pattern: bool,
+) {
+    let mut channels = channels.lock().await;
+    let kind = if pattern {
+        "punsubscribe"
+    } else {
+        "unsubscribe"
+    };
+
+    let names: Vec<String> = match (args.is_empty(), pattern) {
+        (true, true) => conn.pattern_subscriptions.keys().cloned().collect(),
+        (true, false) => conn.subscriptions.keys().cloned().collect(),
+        (false, _) => args
+            .iter()
+            .map(|channel| unpack_bulk_str(channel.clone()).unwrap())
+            .collect(),
+    };
+
+    if names.is_empty() {
+        let resp_frame = Frame::Push(vec![
+            Frame::BulkString(kind.into()),
+            Frame::NullBulkString,
+            Frame::Integer(conn.subscription_count() as i64),
+        ]);
+        return conn.write_frame(&resp_frame).await.unwrap();
+    }
+
+    for channel in names {
+        let subscriptions = match pattern {
+            true => &mut conn.pattern_subscriptions,
+            false => &mut conn.subscriptions,
+        };
+
+        if let Some(forwarder) = subscriptions.remove(&channel) {
+            pubsub::unsubscribe(&mut channels, &channel, forwarder).await;
+        }
+
+        let resp_frame = Frame::Push(vec![
+            Frame::BulkString(kind.into()),
+            Frame::BulkString(channel.into()),
+            Frame::Integer(conn.subscription_count() as i64),
+        ]);
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+pub async fn handle_publish(
+    conn: &mut Connection,
+    channels: Arc<Mutex<Channels>>,
+    patterns: Arc<Mutex<Channels>>,
+    channel: Frame,
+    message: Frame,
+) {
+    let channel = unpack_bulk_str(channel).unwrap();
+    let message = unpack_bulk_bytes(message).unwrap();
+
+    let receivers = pubsub::publish(
+        &*channels.lock().await,
+        &*patterns.lock().await,
+        &channel,
+        message,
+    );
+
+    conn.write_frame(&Frame::Integer(receivers as i64))
+        .await
+        .unwrap();
+}
+
+pub async fn handle_hello(
+    conn: &mut Connection,
+    repl_conf: SharedReplicationConfig,
+    config: Arc<Mutex<Config>>,
+    args: &[Frame],
+) {
+    let protocol = match args.first() {
+        Some(protover) => match parse_i64_arg(protover.clone()) {
+            Some(protover) if protover == 2 || protover == 3 => protover as u8,
+            Some(_) => {
+                let resp_frame = Frame::Error("NOPROTO unsupported protocol version".to_string());
+                return conn.write_frame(&resp_frame).await.unwrap();
+            }
+            None => {
+                let resp_frame = Frame::Error(
+                    "ERR Protocol version is not an integer or out of range".to_string(),
+                );
+                return conn.write_frame(&resp_frame).await.unwrap();
+            }
+        },
+        None => conn.protocol,
+    };
+
+    let mut credentials = None;
+    let mut options = args.iter().skip(1).cloned();
+    while let Some(option) = options.next() {
+        let option = unpack_bulk_str(option).unwrap();
+
+        match (
+            option.to_uppercase().as_str(),
+            options.next(),
+            options.next(),
+        ) {
+            ("AUTH", Some(username), Some(password)) => {
+                let username = unpack_bulk_str(username).unwrap();
+                let password = unpack_bulk_str(password).unwrap();
+                credentials = Some((username, password));
+            }
+            _ => {
+                let resp_frame =
+                    Frame::Error(format!("ERR Syntax error in HELLO option '{}'", option));
+                return conn.write_frame(&resp_frame).await.unwrap();
+            }
+        }
+    }
+
+    if let Some((username, password)) = credentials {
+        if !config.lock().await.password_matches(&username, &password) {
+            return conn.write_frame(&wrong_pass()).await.unwrap();
+        }
+        conn.authenticated = true;
+    }
+
+    if !conn.authenticated {
+        let resp_frame = Frame::Error(
+            "NOAUTH HELLO must be called with the client already authenticated, otherwise \
+             the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the \
+             client and select the RESP protocol version at the same time"
+                .to_string(),
+        );
+        return conn.write_frame(&resp_frame).await.unwrap();
+    }
+
+    conn.protocol = protocol;
+
+    let role = match repl_conf.lock().await.role {
+        ReplRole::Master => "master",
+        ReplRole::Slave => "replica",
+    };
+
+    let resp_frame = Frame::Map(vec![
+        (
+            Frame::BulkString("server".into()),
+            Frame::BulkString("redis".into()),
+        ),
+        (
+            Frame::BulkString("version".into()),
+            Frame::BulkString(REDIS_VERSION.to_string().into()),
+        ),
+        (
+            Frame::BulkString("proto".into()),
+            Frame::Integer(protocol as i64),
+        ),
+        (
+            Frame::BulkString("id".into()),
+            Frame::Integer(conn.id as i64),
+        ),
+        (
+            Frame::BulkString("mode".into()),
+            Frame::BulkString("standalone".into()),
+        ),
+        (
+            Frame::BulkString("role".into()),
+            Frame::BulkString(role.to_string().into()),
+        ),
+        (Frame::BulkString("modules".into()), Frame::Array(vec![])),
+    ]);
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+pub async fn handle_auth(conn: &mut Connection, config: Arc<Mutex<Config>>, args: &[Frame]) {
+    let (username, password) = match args {
+        [password] => ("default".to_string(), password.clone()),
+        [username, password] => (unpack_bulk_str(username.clone()).unwrap(), password.clone()),
+        _ => {
+            let resp_frame = Frame::Error("ERR syntax error".to_string());
+            return conn.write_frame(&resp_frame).await.unwrap();
+        }
+    };
+    let password = unpack_bulk_str(password).unwrap();
+
+    let config = config.lock().await;
+    let resp_frame = if args.len() == 1 && config.requirepass.is_none() {
+        Frame::Error(
+            "ERR AUTH <password> called without any password configured for the default user. \
+             Are you sure your configuration is correct?"
+                .to_string(),
+        )
+    } else if config.password_matches(&username, &password) {
+        conn.authenticated = true;
+        Frame::SimpleString("OK".to_string())
+    } else {
+        wrong_pass()
+    };
+    drop(config);
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+// a replica that reconnects asks for the stream after what it got so far,
+// Redis style: the offset of the next byte it wants. "? -1" asks for a snapshot
+pub fn psync_resume_point(args: &[Frame]) -> Option<(String, usize)> {
+    let replid = unpack_bulk_str(args[0].clone()).ok()?;
+
+    match parse_i64_arg(args[1].clone()) {
+        Some(offset) if offset > 0 => Some((replid, offset as usize - 1)),
+        _ => None,
+    }
+}
+
+// returns true when the replica fell behind and needs a new snapshot
+pub async fn handle_psync(
+    conn: &mut Connection,
+    repl_conf: SharedReplicationConfig,
+    sync: PsyncReply,
+    mut receiver: Receiver<Frame>,
+) -> bool {
+    let ip = match conn.peer_addr {
+        Some(addr) => addr.ip().to_string(),
+        None => "?".to_string(),
+    };
+
+    let (master_replid, replica_id) = {
+        let mut guard = repl_conf.lock().await;
+        let replica_id = guard.register_replica(ip, conn.listening_port);
+        (guard.master_replid.clone().unwrap(), replica_id)
+    };
+    conn.replica_id = Some(replica_id);
+
+    match sync {
+        PsyncReply::Full { rdb, offset } => {
+            // the replica counts the stream from where the snapshot left off
+            let resp = format!("FULLRESYNC {} {}", master_replid, offset);
+            let resp_frame = Frame::SimpleString(resp);
+
+            conn.write_frame(&resp_frame).await.unwrap();
+
+            conn.write(format!("${}\r\n", rdb.len()).as_bytes())
+                .await
+                .unwrap();
+            conn.write(&rdb).await.unwrap();
+        }
+        PsyncReply::Partial { missed } => {
+            let resp_frame = Frame::SimpleString(format!("CONTINUE {}", master_replid));
+            conn.write_frame(&resp_frame).await.unwrap();
+
+            for f in missed {
+                conn.write_frame(&f).await.unwrap();
+            }
+        }
+    }
+
+    // from now on this connection is a replica link: propagate writes to it
+    // and listen for the REPLCONF ACKs it sends back
+    let lagged = loop {
+        tokio::select! {
+            propagated = receiver.recv() => match propagated {
+                Ok(f) => {
+                    if conn.write_frame(&f).await.is_err() {
+                        break false;
+                    }
+                }
+                // carrying on would leave the replica without the missed writes
+                Err(RecvError::Lagged(missed)) => {
+                    println!("replica {replica_id} missed {missed} writes, resyncing");
+                    break true;
+                }
+                Err(RecvError::Closed) => break false,
+            },
+            frames = conn.read_frames() => {
+                let Ok(Some(frames)) = frames else { break false };
+
+                for (frame, _) in frames {
+                    let Ok((command, args)) = extract_command(frame) else { continue };
+
+                    if command.eq_ignore_ascii_case("REPLCONF") {
+                        handle_replconf(conn, Arc::clone(&repl_conf), &args, false).await;
+                    }
+                }
+            }
+        }
+    };
+
+    repl_conf.lock().await.unregister_replica(replica_id);
+    lagged
+}
+
+pub async fn handle_wait(
+    conn: &mut Connection,
+    repl_conf: SharedReplicationConfig,
+    sender: Arc<Sender<Frame>>,
+    numreplicas: Frame,
+    timeout: Frame,
+) {
+    let (Some(numreplicas), Some(timeout)) = (parse_i64_arg(numreplicas), parse_i64_arg(timeout))
+    else {
+        return conn.write_frame(&not_an_integer()).await.unwrap();
+    };
+    if timeout < 0 {
+        let resp_frame = Frame::Error("ERR timeout is negative".to_string());
+        return conn.write_frame(&resp_frame).await.unwrap();
+    }
+
+    let target_offset = repl_conf.lock().await.master_repl_offset.unwrap_or(0);
+
+    let acked = repl_conf.lock().await.acked_replicas(target_offset);
+    if acked as i64 >= numreplicas || target_offset == 0 {
+        return conn
+            .write_frame(&Frame::Integer(acked as i64))
+            .await
+            .unwrap();
+    }
+
+    let getack = Frame::Array(vec![
+        Frame::BulkString("REPLCONF".into()),
+        Frame::BulkString("GETACK".into()),
+        Frame::BulkString("*".into()),
+    ]);
+    propagate(&sender, &repl_conf, getack).await;
+
+    // a timeout of 0 means waiting until enough replicas acknowledged
+    let deadline = (timeout > 0).then(|| Instant::now() + Duration::from_millis(timeout as u64));
+
+    let acked = loop {
+        let acked = repl_conf.lock().await.acked_replicas(target_offset);
+
+        if acked as i64 >= numreplicas || deadline.is_some_and(|d| Instant::now() >= d) {
+            break acked;
+        }
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+
+    conn.write_frame(&Frame::Integer(acked as i64))
+        .await
+        .unwrap();
+}
+
+// sends a write to the connected replicas and advances the master offset
+pub async fn propagate(sender: &Sender<Frame>, repl_conf: &Mutex<ReplicationConfig>, frame: Frame) {
+    let frame_len = frame.serialize().len();
+
+    // held while sending, so that PSYNC sees the backlog and
+    // the stream in the same state
+    let mut repl_conf = repl_conf.lock().await;
+
+    // sending only fails when no replica is subscribed, which is fine
+    if sender.send(frame.clone()).is_err() {
+        println!("No replicas to propagate to");
+    }
+
+    repl_conf.record_propagated(frame, frame_len);
+}
+
+pub async fn handle_replconf(
+    conn: &mut Connection,
+    repl_conf: SharedReplicationConfig,
+    args: &[Frame],
+    respond: bool,
+) {
+    let slave_repl_offset = {
+        let guard = repl_conf.lock().await;
+        guard.slave_repl_offset.unwrap_or(0)
+    };
+
+    let Some(subcommand) = args
+        .first()
+        .and_then(|arg| unpack_bulk_str(arg.clone()).ok())
+    else {
+        if respond {
+            let resp_frame = Frame::Error("ERR syntax error".to_string());
+            conn.write_frame(&resp_frame).await.unwrap();
+        }
+        return;
+    };
+
+    match subcommand.to_uppercase().as_str() {
+        "LISTENING-PORT" => {
+            conn.listening_port = args
+                .get(1)
+                .and_then(|port| unpack_bulk_str(port.clone()).ok())
+                .and_then(|port| port.parse().ok());
+
+            if respond {
+                let resp_frame = Frame::SimpleString("OK".to_string());
+                conn.write_frame(&resp_frame).await.unwrap();
+            }
+        }
+        "ACK" => {
+            let offset = args
+                .get(1)
+                .and_then(|offset| unpack_bulk_str(offset.clone()).ok())
+                .and_then(|offset| offset.parse().ok());
+
+            if let (Some(replica_id), Some(offset)) = (conn.replica_id, offset) {
+                let mut guard = repl_conf.lock().await;
+                guard.update_replica_offset(replica_id, offset);
+            }
+        }
+        "GETACK" => {
+            let resp_frame = replconf_ack(slave_repl_offset);
+            conn.write_frame(&resp_frame).await.unwrap();
+        }
+        _ => {
+            if respond {
+                let resp_frame = Frame::SimpleString("OK".to_string());
+                conn.write_frame(&resp_frame).await.unwrap();
+            }
+        }
+    }
+}
+
+pub async fn handle_info(
+    conn: &mut Connection,
+    replication_config: SharedReplicationConfig,
+    config: Arc<Mutex<Config>>,
+    args: &[Frame],
+) {
+    let section = match args.first() {
+        Some(section) => unpack_bulk_str(section.clone()).unwrap().to_lowercase(),
+        None => "all".to_string(),
+    };
+
+    let sections = match section.as_str() {
+        "all" | "default" | "everything" => vec!["Server", "Replication"],
+        "server" => vec!["Server"],
+        "replication" => vec!["Replication"],
+        _ => vec![],
+    };
+
+    let mut result_sections = vec![];
+    for section in sections {
+        let section_values = match section {
+            "Server" => info_server(&replication_config, &config).await,
+            _ => info_replication(&replication_config).await,
+        };
+
+        result_sections.push(format!("# {}\r\n{}", section, section_values.join("\r\n")));
+    }
+
+    let resp_frame = Frame::BulkString(result_sections.join("\r\n\r\n").into());
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+async fn info_server(
+    replication_config: &Mutex<ReplicationConfig>,
+    config: &Mutex<Config>,
+) -> Vec<String> {
+    let run_id = replication_config.lock().await.run_id.clone();
+    let tcp_port = config.lock().await.port;
+
+    vec![
+        format!("redis_version:{}", REDIS_VERSION),
+        format!("os:{} {}", std::env::consts::OS, std::env::consts::ARCH),
+        format!("tcp_port:{}", tcp_port),
+        format!("run_id:{}", run_id),
+    ]
+}
+
+async fn info_replication(replication_config: &Mutex<ReplicationConfig>) -> Vec<String> {
+    let repl_conf = replication_config.lock().await;
+    let mut result_values = vec![format!("role:{}", repl_conf.role)];
+
+    match repl_conf.role {
+        ReplRole::Master => {
+            let master_replid = repl_conf.master_replid.as_ref().unwrap();
+            let master_repl_offset = repl_conf.master_repl_offset.as_ref().unwrap();
+
+            result_values.push(format!("connected_slaves:{}", repl_conf.replicas.len()));
+            for (i, replica) in repl_conf.replicas.iter().enumerate() {
+                result_values.push(format!(
+                    "slave{}:ip={},port={},state=online,offset={},lag=0",
+                    i,
+                    replica.ip,
+                    replica.listening_port.unwrap_or(0),
+                    replica.offset
+                ));
+            }
+            result_values.push("master_failover_state:no-failover".to_string());
+            result_values.push(format!("master_replid:{}", master_replid));
+            result_values.push(format!("master_replid2:{}", "0".repeat(40)));
+            result_values.push(format!("master_repl_offset:{}", master_repl_offset));
+            result_values.push("second_repl_offset:-1".to_string());
+        }
+        ReplRole::Slave => {
+            let master_host = repl_conf.master_host.as_deref().unwrap_or("");
+            let master_port = repl_conf.master_port.unwrap_or(0);
+            let slave_repl_offset = repl_conf.slave_repl_offset.unwrap_or(0);
+
+            result_values.push(format!("master_host:{}", master_host));
+            result_values.push(format!("master_port:{}", master_port));
+            let master_link_status = if repl_conf.master_link_up {
+                "up"
+            } else {
+                "down"
+            };
+            result_values.push(format!("master_link_status:{}", master_link_status));
+            result_values.push(format!("slave_repl_offset:{}", slave_repl_offset));
+            result_values.push("slave_read_only:1".to_string());
+            result_values.push("connected_slaves:0".to_string());
+            result_values.push("master_failover_state:no-failover".to_string());
+        }
+    }
+
+    result_values
+}
+
+pub async fn handle_select(conn: &mut Connection, index: Frame, reply: bool) {
+    let resp_frame = match parse_i64_arg(index) {
+        Some(index) if (0..NUM_DATABASES as i64).contains(&index) => {
+            conn.db_index = index as usize;
+            Frame::SimpleString("OK".to_string())
+        }
+        Some(_) => Frame::Error("ERR DB index is out of range".to_string()),
+        None => not_an_integer(),
+    };
+
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+pub async fn handle_dbsize(conn: &mut Connection, db: Arc<Mutex<Db>>) {
+    let db = db.lock().await;
+
+    let size = db.values().filter(|db_item| !db_item.is_expired()).count();
+
+    conn.write_frame(&Frame::Integer(size as i64))
+        .await
+        .unwrap();
+}
+
+pub async fn handle_flushdb(conn: &mut Connection, db: Arc<Mutex<Db>>, reply: bool) {
+    db.lock().await.clear();
+
+    if reply {
+        conn.write_frame(&Frame::SimpleString("OK".to_string()))
+            .await
+            .unwrap();
+    }
+}
+
+pub async fn handle_flushall(conn: &mut Connection, dbs: &[Arc<Mutex<Db>>], reply: bool) {
+    for db in dbs {
+        db.lock().await.clear();
+    }
+
+    if reply {
+        conn.write_frame(&Frame::SimpleString("OK".to_string()))
+            .await
+            .unwrap();
+    }
+}
+
+pub async fn handle_save(
+    conn: &mut Connection,
+    dbs: &[Arc<Mutex<Db>>],
+    config: Arc<Mutex<Config>>,
+    rdb_last_save: Arc<Mutex<SystemTime>>,
+) {
+    let rdb_path = config.lock().await.rdb_path();
+    let dbs = lock_all(dbs).await;
+    let dbs: Vec<&Db> = dbs.iter().map(|db| &**db).collect();
+
+    let resp_frame = match rdb::write_rdb_file(rdb_path, &dbs).await {
+        Ok(()) => {
+            *rdb_last_save.lock().await = SystemTime::now();
+            Frame::SimpleString("OK".to_string())
+        }
+        Err(e) => Frame::Error(format!("ERR {}", e)),
+    };
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+// returns whether the server should go down, there's no reply then.
+// Without SAVE nothing is saved, since there are no save points
+pub async fn handle_shutdown(
+    conn: &mut Connection,
+    dbs: &[Arc<Mutex<Db>>],
+    config: Arc<Mutex<Config>>,
+    rdb_last_save: Arc<Mutex<SystemTime>>,
+    args: &[Frame],
+) -> bool {
+    let mut save = false;
+    for arg in args {
+        match unpack_bulk_str(arg.clone())
+            .unwrap()
+            .to_uppercase()
+            .as_str()
+        {
+            "SAVE" => save = true,
+            "NOSAVE" => save = false,
+            _ => {
+                let resp_frame = Frame::Error("ERR syntax error".to_string());
+                conn.write_frame(&resp_frame).await.unwrap();
+                return false;
+            }
+        }
+    }
+
+    if save {
+        let rdb_path = config.lock().await.rdb_path();
+
+        let dbs = lock_all(dbs).await;
+        let dbs: Vec<&Db> = dbs.iter().map(|db| &**db).collect();
+
+        if let Err(e) = rdb::write_rdb_file(rdb_path, &dbs).await {
+            let resp_frame = Frame::Error(format!("ERR Errors trying to SHUTDOWN: {}", e));
+            conn.write_frame(&resp_frame).await.unwrap();
+            return false;
+        }
+
+        *rdb_last_save.lock().await = SystemTime::now();
+    }
+
+    true
+}
+
+pub async fn handle_bgsave(
+    conn: &mut Connection,
+    dbs: &[Arc<Mutex<Db>>],
+    config: Arc<Mutex<Config>>,
+    rdb_last_save: Arc<Mutex<SystemTime>>,
+) {
+    let rdb_path = config.lock().await.rdb_path();
+
+    // snapshot the dbs so the locks aren't held while writing to disk
+    let snapshot: Vec<Db> = lock_all(dbs)
+        .await
+        .iter()
+        .map(|db| (**db).clone())
+        .collect();
+
+    tokio::spawn(async move {
+        let dbs: Vec<&Db> = snapshot.iter().collect();
+        match rdb::write_rdb_file(rdb_path, &dbs).await {
+            Ok(()) => {
+                *rdb_last_save.lock().await = SystemTime::now();
+                println!("Background saving terminated with success");
+            }
+            Err(e) => println!("Background saving error: {e}"),
+        }
+    });
+
+    conn.write_frame(&Frame::SimpleString(
+        "Background saving started".to_string(),
+    ))
+    .await
+    .unwrap();
+}
+
+pub async fn handle_keys(conn: &mut Connection, db: Arc<Mutex<Db>>, pattern: Frame) {
+    let db = db.lock().await;
+    let pattern = unpack_bulk_str(pattern).unwrap();
+
+    let resp_frame = Frame::Array(
+        db.iter()
+            .filter(|(key, db_item)| !db_item.is_expired() && glob::matches(&pattern, key))
+            .map(|(key, _)| Frame::BulkString(key.clone().into()))
+            .collect(),
+    );
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+pub async fn handle_scan(conn: &mut Connection, db: Arc<Mutex<Db>>, args: &[Frame]) {
+    let options = match ScanOptions::parse(args) {
+        Ok(options) => options,
+        Err(e) => {
+            let resp_frame = Frame::Error(format!("ERR {}", e));
+            return conn.write_frame(&resp_frame).await.unwrap();
+        }
+    };
+
+    let mut keys: Vec<String> = db
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, db_item)| !db_item.is_expired())
+        .map(|(key, _)| key.clone())
+        .collect();
+    keys.sort();
+
+    let (next_cursor, keys) = options.page(keys);
+    let resp_frame = scan_reply(next_cursor, keys);
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+pub async fn handle_hscan(conn: &mut Connection, db: Arc<Mutex<Db>>, args: &[Frame]) {
+    let options = match ScanOptions::parse(&args[1..]) {
+        Ok(options) => options,
+        Err(e) => {
+            let resp_frame = Frame::Error(format!("ERR {}", e));
+            return conn.write_frame(&resp_frame).await.unwrap();
+        }
+    };
+
+    let resp_frame = inspect_hash(db, args[0].clone(), |hash| {
+        let mut fields: Vec<String> = hash.keys().cloned().collect();
+        fields.sort();
+
+        let (next_cursor, fields) = options.page(fields);
+
+        scan_reply(
+            next_cursor,
+            fields
+                .into_iter()
+                .flat_map(|field| {
+                    let value = hash[&field].clone();
+                    [field, value]
+                })
+                .collect(),
+        )
+    })
+    .await;
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+pub async fn handle_sscan(conn: &mut Connection, db: Arc<Mutex<Db>>, args: &[Frame]) {
+    let options = match ScanOptions::parse(&args[1..]) {
+        Ok(options) => options,
+        Err(e) => {
+            let resp_frame = Frame::Error(format!("ERR {}", e));
+            return conn.write_frame(&resp_frame).await.unwrap();
+        }
+    };
+
+    let resp_frame = inspect_set(db, args[0].clone(), |set| {
+        let mut members: Vec<String> = set.iter().cloned().collect();
+        members.sort();
+
+        let (next_cursor, members) = options.page(members);
+
+        scan_reply(next_cursor, members)
+    })
+    .await;
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+// [next_cursor, [items...]]
+fn scan_reply(next_cursor: usize, items: Vec<String>) -> Frame {
+    Frame::Array(vec![
+        Frame::BulkString(next_cursor.to_string().into()),
+        Frame::Array(
+            items
+                .into_iter()
+                .map(|item| Frame::BulkString(item.into()))
+                .collect(),
+        ),
+    ])
+}
+
+// the cursor and [MATCH pattern] [COUNT count] of the SCAN family
+#[derive(Debug, PartialEq, Eq)]
+pub struct ScanOptions {
+    pub cursor: usize,
+    pub pattern: Option<String>,
+    pub count: usize,
+}
+
+impl ScanOptions {
+    pub fn parse(args: &[Frame]) -> Result<ScanOptions> {
+        let cursor = args
+            .first()
+            .cloned()
+            .and_then(|cursor| unpack_bulk_str(cursor).ok()?.parse::<usize>().ok())
+            .ok_or_else(|| anyhow::anyhow!("invalid cursor"))?;
+
+        let mut options = ScanOptions {
+            cursor,
+            pattern: None,
+            count: 10,
+        };
+        let mut args = args[1..].iter();
+
+        while let Some(arg) = args.next() {
+            let option = unpack_bulk_str(arg.clone())?.to_uppercase();
+            let value = args
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("syntax error"))?
+                .clone();
+
+            match option.as_str() {
+                "MATCH" => options.pattern = Some(unpack_bulk_str(value)?),
+                "COUNT" => {
+                    options.count = unpack_bulk_str(value)?
+                        .parse::<usize>()
+                        .map_err(|_| anyhow::anyhow!("value is not an integer or out of range"))?;
+
+                    if options.count == 0 {
+                        return Err(anyhow::anyhow!("syntax error"));
+                    }
+                }
+                _ => return Err(anyhow::anyhow!("syntax error")),
+            }
+        }
+
+        Ok(options)
+    }
+
+    // the cursor is an index into the sorted snapshot, COUNT of
+    // the items after it are looked at and MATCH filters those;
+    // the next cursor is 0 once the end is reached
+    pub fn page(&self, items: Vec<String>) -> (usize, Vec<String>) {
+        let end = self.cursor.saturating_add(self.count);
+        let next_cursor = if end < items.len() { end } else { 0 };
+
+        let page = items
+            .into_iter()
+            .skip(self.cursor)
+            .take(self.count)
+            .filter(|item| match &self.pattern {
+                Some(pattern) => glob::matches(pattern, item),
+                None => true,
+            })
+            .collect();
+
+        (next_cursor, page)
+    }
+}
+
+pub async fn handle_config(conn: &mut Connection, config: Arc<Mutex<Config>>, args: &[Frame]) {
+    let config_command = unpack_bulk_str(args[0].clone()).unwrap();
+
+    let resp_frame = match config_command.to_uppercase().as_str() {
+        "GET" if args.len() < 2 => wrong_arity("config|get"),
+        "SET" if args.len() != 3 => wrong_arity("config|set"),
+        "GET" => {
+            let config = config.lock().await;
+            let mut parameters: Vec<(String, String)> = vec![];
+
+            for pattern in &args[1..] {
+                let pattern = unpack_bulk_str(pattern.clone()).unwrap().to_lowercase();
+
+                for (name, value) in config.get_matching(&pattern) {
+                    if !parameters.iter().any(|(seen, _)| *seen == name) {
+                        parameters.push((name, value));
+                    }
+                }
+            }
+
+            Frame::Map(
+                parameters
+                    .into_iter()
+                    .map(|(name, value)| {
+                        (
+                            Frame::BulkString(name.into()),
+                            Frame::BulkString(value.into()),
+                        )
+                    })
+                    .collect(),
+            )
+        }
+        "SET" => {
+            let config_key_name = unpack_bulk_str(args[1].clone()).unwrap();
+            let value = unpack_bulk_str(args[2].clone()).unwrap();
+
+            match config.lock().await.set(config_key_name, value) {
+                Ok(()) => Frame::SimpleString("OK".to_string()),
+                Err(e) => Frame::Error(format!("ERR {}", e)),
+            }
+        }
+        _ => Frame::Error(format!(
+            "ERR unknown subcommand '{}'. Try CONFIG HELP.",
+            config_command
+        )),
+    };
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub enum SetCondition {
+    #[default]
+    Always,
+    IfAbsent,
+    IfPresent,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SetOptions {
+    pub expires: usize,
+    pub condition: SetCondition,
+    pub keep_ttl: bool,
+    pub get: bool,
+}
+
+impl SetOptions {
+    pub fn parse(args: &[Frame]) -> Result<SetOptions> {
+        let mut options = SetOptions::default();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            let option = unpack_bulk_str(arg.clone())?.to_uppercase();
+
+            match option.as_str() {
+                "EX" | "PX" if options.expires == 0 && !options.keep_ttl => {
+                    let amount = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("syntax error"))?
+                        .clone();
+                    let amount = unpack_bulk_str(amount)?
+                        .parse::<usize>()
+                        .map_err(|_| anyhow::anyhow!("value is not an integer or out of range"))?;
+
+                    if amount == 0 {
+                        return Err(anyhow::anyhow!("invalid expire time in 'set' command"));
+                    }
+
+                    options.expires = match option.as_str() {
+                        "EX" => amount.checked_mul(1000).ok_or_else(|| {
+                            anyhow::anyhow!("invalid expire time in 'set' command")
+                        })?,
+                        _ => amount,
+                    };
+                }
+                "NX" if options.condition == SetCondition::Always => {
+                    options.condition = SetCondition::IfAbsent
+                }
+                "XX" if options.condition == SetCondition::Always => {
+                    options.condition = SetCondition::IfPresent
+                }
+                "KEEPTTL" if options.expires == 0 => options.keep_ttl = true,
+                "GET" => options.get = true,
+                _ => return Err(anyhow::anyhow!("syntax error")),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+pub async fn handle_set(conn: &mut Connection, db: Arc<Mutex<Db>>, frame: Frame, reply: bool) {
+    let mut db = db.lock().await;
+    let (_, args) = extract_command(frame.clone()).unwrap();
+
+    let key = unpack_bulk_str(args[0].clone()).unwrap();
+    let value = unpack_bulk_bytes(args[1].clone()).unwrap();
+
+    let options = match SetOptions::parse(&args[2..]) {
+        Ok(options) => options,
+        Err(e) => {
+            if reply {
+                let resp_frame = Frame::Error(format!("ERR {}", e));
+                conn.write_frame(&resp_frame).await.unwrap();
+            }
+            return;
+        }
+    };
+
+    // GET has to return the old value, so that must be a string
+    if options.get && holds_other_type(&db, &key) {
+        if reply {
+            conn.write_frame(&wrong_type()).await.unwrap();
+        }
+        return;
+    }
+
+    let (should_set, old_value) = set_value(&mut db, key, value, &options);
+
+    if reply {
+        let resp_frame = match (options.get, should_set) {
+            (true, _) => old_value,
+            (false, true) => Frame::SimpleString("OK".to_string()),
+            (false, false) => Frame::NullBulkString,
+        };
+
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+pub async fn handle_setnx(
+    conn: &mut Connection,
+    db: Arc<Mutex<Db>>,
+    key: Frame,
+    value: Frame,
+    reply: bool,
+) {
+    let mut db = db.lock().await;
+    let key = unpack_bulk_str(key).unwrap();
+    let value = unpack_bulk_bytes(value).unwrap();
+
+    let options = SetOptions {
+        condition: SetCondition::IfAbsent,
+        ..SetOptions::default()
+    };
+    let (was_set, _) = set_value(&mut db, key, value, &options);
+
+    if reply {
+        conn.write_frame(&Frame::Integer(was_set as i64))
+            .await
+            .unwrap();
+    }
+}
+
+pub async fn handle_setex(conn: &mut Connection, db: Arc<Mutex<Db>>, args: &[Frame], reply: bool) {
+    let key = unpack_bulk_str(args[0].clone()).unwrap();
+    let value = unpack_bulk_bytes(args[2].clone()).unwrap();
+
+    let expires = |seconds: i64| (seconds > 0).then(|| (seconds as usize).checked_mul(1000))?;
+
+    let resp_frame = match parse_i64_arg(args[1].clone()) {
+        None => not_an_integer(),
+        Some(seconds) => match expires(seconds) {
+            None => Frame::Error("ERR invalid expire time in 'setex' command".to_string()),
+            Some(expires) => {
+                let options = SetOptions {
+                    expires,
+                    ..SetOptions::default()
+                };
+                set_value(&mut *db.lock().await, key, value, &options);
+
+                Frame::SimpleString("OK".to_string())
+            }
+        },
+    };
+
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+// the part of SET shared with SETNX and SETEX, returns whether
+// the value was stored along with the previous value
+fn set_value(db: &mut Db, key: String, value: Bytes, options: &SetOptions) -> (bool, Frame) {
+    let existing = db.get(&key).filter(|db_item| !db_item.is_expired());
+
+    let old_value = match existing.map(|db_item| &db_item.value) {
+        Some(Value::String(value)) => Frame::BulkString(value.clone()),
+        _ => Frame::NullBulkString,
+    };
+
+    let should_set = match options.condition {
+        SetCondition::Always => true,
+        SetCondition::IfAbsent => existing.is_none(),
+        SetCondition::IfPresent => existing.is_some(),
+    };
+
+    if should_set {
+        let item = match existing {
+            Some(db_item) if options.keep_ttl => DbItem {
+                expires_at: db_item.expires_at,
+                ..DbItem::new(Value::String(value), None)
+            },
+            _ => {
+                let expires_at = (options.expires > 0)
+                    .then(|| Instant::now() + Duration::from_millis(options.expires as u64));
+                DbItem::new(Value::String(value), expires_at)
+            }
+        };
+
+        db.insert(key, item);
+    }
+
+    (should_set, old_value)
+}
+
+pub async fn handle_get(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame) {
+    let mut db = db.lock().await;
+    let key = unpack_bulk_str(key).unwrap();
+
+    let frame = match db.get_mut(&key).filter(|db_item| !db_item.is_expired()) {
+        Some(DbItem {
+            value: Value::String(value),
+            last_access,
+            ..
+        }) => {
+            *last_access = Instant::now();
+            Frame::BulkString(value.clone())
+        }
+        Some(_) => wrong_type(),
+        None => Frame::NullBulkString,
+    };
+
+    conn.write_frame(&frame).await.unwrap();
+}
+
+pub async fn handle_mset(conn: &mut Connection, db: Arc<Mutex<Db>>, args: &[Frame], reply: bool) {
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        if reply {
+            let resp_frame = wrong_arity("mset");
+            conn.write_frame(&resp_frame).await.unwrap();
+        }
+        return;
+    }
+
+    let mut db = db.lock().await;
+
+    for pair in args.chunks(2) {
+        let key = unpack_bulk_str(pair[0].clone()).unwrap();
+        let value = unpack_bulk_bytes(pair[1].clone()).unwrap();
+
+        db.insert(key, DbItem::new(Value::String(value), None));
+    }
+
+    if reply {
+        conn.write_frame(&Frame::SimpleString("OK".to_string()))
+            .await
+            .unwrap();
+    }
+}
+
+pub async fn handle_mget(conn: &mut Connection, db: Arc<Mutex<Db>>, keys: &[Frame]) {
+    let db = db.lock().await;
+
+    let values = keys
+        .iter()
+        .map(|key| {
+            let key = unpack_bulk_str(key.clone()).unwrap();
+
+            // anything that isn't a string counts as missing here
+            match db.get(&key).filter(|db_item| !db_item.is_expired()) {
+                Some(DbItem {
+                    value: Value::String(value),
+                    ..
+                }) => Frame::BulkString(value.clone()),
+                _ => Frame::NullBulkString,
+            }
+        })
+        .collect();
+
+    conn.write_frame(&Frame::Array(values)).await.unwrap();
+}
+
+pub async fn handle_append(
+    conn: &mut Connection,
+    db: Arc<Mutex<Db>>,
+    key: Frame,
+    value: Frame,
+    reply: bool,
+) {
+    let mut db = db.lock().await;
+    let key = unpack_bulk_str(key).unwrap();
+    let value = unpack_bulk_bytes(value).unwrap();
+
+    let resp_frame = match db.get_mut(&key).filter(|db_item| !db_item.is_expired()) {
+        // the key keeps whatever TTL it had
+        Some(DbItem {
+            value: Value::String(existing),
+            ..
+        }) => {
+            *existing = [existing.as_ref(), value.as_ref()].concat().into();
+            Frame::Integer(existing.len() as i64)
+        }
+        Some(_) => wrong_type(),
+        None => {
+            let length = value.len();
+            db.insert(key, DbItem::new(Value::String(value), None));
+            Frame::Integer(length as i64)
+        }
+    };
+
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+pub async fn handle_strlen(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame) {
+    let db = db.lock().await;
+    let key = unpack_bulk_str(key).unwrap();
+
+    let resp_frame = match db.get(&key).filter(|db_item| !db_item.is_expired()) {
+        Some(DbItem {
+            value: Value::String(value),
+            ..
+        }) => Frame::Integer(value.len() as i64),
+        Some(_) => wrong_type(),
+        None => Frame::Integer(0),
+    };
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+pub async fn handle_getset(
+    conn: &mut Connection,
+    db: Arc<Mutex<Db>>,
+    key: Frame,
+    value: Frame,
+    reply: bool,
+) {
+    let mut db = db.lock().await;
+    let key = unpack_bulk_str(key).unwrap();
+    let value = unpack_bulk_bytes(value).unwrap();
+
+    if holds_other_type(&db, &key) {
+        if reply {
+            conn.write_frame(&wrong_type()).await.unwrap();
+        }
+        return;
+    }
+
+    // the new value never inherits the old TTL
+    let old_item = db.insert(key, DbItem::new(Value::String(value), None));
+
+    if reply {
+        let resp_frame = match old_item.filter(|db_item| !db_item.is_expired()) {
+            Some(DbItem {
+                value: Value::String(value),
+                ..
+            }) => Frame::BulkString(value),
+            _ => Frame::NullBulkString,
+        };
+
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+pub async fn handle_getdel(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame, reply: bool) {
+    let mut db = db.lock().await;
+    let key = unpack_bulk_str(key).unwrap();
+
+    if holds_other_type(&db, &key) {
+        if reply {
+            conn.write_frame(&wrong_type()).await.unwrap();
+        }
+        return;
+    }
+
+    let old_item = db.remove(&key);
+
+    if reply {
+        let resp_frame = match old_item.filter(|db_item| !db_item.is_expired()) {
+            Some(DbItem {
+                value: Value::String(value),
+                ..
+            }) => Frame::BulkString(value),
+            _ => Frame::NullBulkString,
+        };
+
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+pub async fn handle_del(conn: &mut Connection, db: Arc<Mutex<Db>>, keys: &[Frame], reply: bool) {
+    let mut db = db.lock().await;
+
+    let mut deleted = 0;
+    for key in keys {
+        let key = unpack_bulk_str(key.clone()).unwrap();
+
+        // an expired key is already gone as far as the client can tell
+        if db.remove(&key).is_some_and(|db_item| !db_item.is_expired()) {
+            deleted += 1;
+        }
+    }
+
+    if reply {
+        conn.write_frame(&Frame::Integer(deleted)).await.unwrap();
+    }
+}
+
+pub async fn handle_exists(conn: &mut Connection, db: Arc<Mutex<Db>>, keys: &[Frame]) {
+    let db = db.lock().await;
+
+    let mut count = 0;
+    for key in keys {
+        let key = unpack_bulk_str(key.clone()).unwrap();
+
+        if let Some(db_item) = db.get(&key) {
+            if !db_item.is_expired() {
+                count += 1;
+            }
+        }
+    }
+
+    conn.write_frame(&Frame::Integer(count)).await.unwrap();
+}
+
+pub async fn handle_incr(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame, reply: bool) {
+    let resp_frame = incr_by(db, key, 1).await;
+
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+pub async fn handle_decr(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame, reply: bool) {
+    let resp_frame = incr_by(db, key, -1).await;
+
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+pub async fn handle_incrby(
+    conn: &mut Connection,
+    db: Arc<Mutex<Db>>,
+    key: Frame,
+    increment: Frame,
+    reply: bool,
+) {
+    let resp_frame = match parse_i64_arg(increment) {
+        Some(delta) => incr_by(db, key, delta).await,
+        None => not_an_integer(),
+    };
+
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+pub async fn handle_decrby(
+    conn: &mut Connection,
+    db: Arc<Mutex<Db>>,
+    key: Frame,
+    decrement: Frame,
+    reply: bool,
+) {
+    let resp_frame = match parse_i64_arg(decrement).and_then(|delta| delta.checked_neg()) {
+        Some(delta) => incr_by(db, key, delta).await,
+        None => not_an_integer(),
+    };
+
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+fn parse_i64_arg(arg: Frame) -> Option<i64> {
+    unpack_bulk_str(arg).ok()?.parse::<i64>().ok()
+}
+
+fn wrong_type() -> Frame {
+    Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
+}
+
+// a live key that holds something other than a string
+fn holds_other_type(db: &Db, key: &str) -> bool {
+    db.get(key)
+        .is_some_and(|db_item| !db_item.is_expired() && !matches!(db_item.value, Value::String(_)))
+}
+
+fn parse_f64_arg(arg: Frame) -> Option<f64> {
+    parse_f64(&unpack_bulk_str(arg).ok()?)
+}
+
+// accepts "inf", "+inf" and "-inf" too, but never NaN
+fn parse_f64(value: &str) -> Option<f64> {
+    value.parse::<f64>().ok().filter(|n| !n.is_nan())
+}
+
+pub fn wrong_arity(command: &str) -> Frame {
+    Frame::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        command.to_lowercase()
+    ))
+}
+
+fn wrong_pass() -> Frame {
+    Frame::Error("WRONGPASS invalid username-password pair or user is disabled.".to_string())
+}
+
+fn not_an_integer() -> Frame {
+    Frame::Error("ERR value is not an integer or out of range".to_string())
+}
+
+async fn incr_by(db: Arc<Mutex<Db>>, key: Frame, delta: i64) -> Frame {
+    let mut db = db.lock().await;
+    let key = unpack_bulk_str(key).unwrap();
+
+    let db_item = db.get_mut(&key).filter(|db_item| !db_item.is_expired());
+
+    let current = match &db_item {
+        Some(DbItem {
+            value: Value::String(value),
+            ..
+        }) => std::str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok()),
+        Some(_) => return wrong_type(),
+        None => Some(0),
+    };
+
+    match current.and_then(|current| current.checked_add(delta)) {
+        Some(new_value) => {
+            // keep the TTL of an existing key, like redis does
+            match db_item {
+                Some(db_item) => db_item.value = Value::String(new_value.to_string().into()),
+                None => {
+                    let value = Value::String(new_value.to_string().into());
+                    db.insert(key, DbItem::new(value, None));
+                }
+            }
+
+            Frame::Integer(new_value)
+        }
+        None => not_an_integer(),
+    }
+}
+
+pub async fn handle_lpush(conn: &mut Connection, db: Arc<Mutex<Db>>, args: &[Frame], reply: bool) {
+    let resp_frame = push(db, args, true).await;
+
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+pub async fn handle_rpush(conn: &mut Connection, db: Arc<Mutex<Db>>, args: &[Frame], reply: bool) {
+    let resp_frame = push(db, args, false).await;
+
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+pub async fn handle_lrange(
+    conn: &mut Connection,
+    db: Arc<Mutex<Db>>,
+    key: Frame,
+    start: Frame,
+    stop: Frame,
+) {
+    let db = db.lock().await;
+    let key = unpack_bulk_str(key).unwrap();
+
+    let resp_frame = match (parse_i64_arg(start), parse_i64_arg(stop)) {
+        (Some(start), Some(stop)) => match db.get(&key).filter(|db_item| !db_item.is_expired()) {
+            Some(DbItem {
+                value: Value::List(list),
+                ..
+            }) => match index_range(start, stop, list.len()) {
+                Some(range) => Frame::Array(
+                    list.range(range)
+                        .map(|element| Frame::BulkString(element.clone().into()))
+                        .collect(),
+                ),
+                None => Frame::Array(vec![]),
+            },
+            Some(_) => wrong_type(),
+            None => Frame::Array(vec![]),
+        },
+        _ => not_an_integer(),
+    };
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+pub async fn handle_lpop(conn: &mut Connection, db: Arc<Mutex<Db>>, args: &[Frame], reply: bool) {
+    let resp_frame = pop(db, args, true).await;
+
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+pub async fn handle_rpop(conn: &mut Connection, db: Arc<Mutex<Db>>, args: &[Frame], reply: bool) {
+    let resp_frame = pop(db, args, false).await;
+
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+pub async fn handle_llen(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame) {
+    let db = db.lock().await;
+    let key = unpack_bulk_str(key).unwrap();
+
+    let resp_frame = match db.get(&key).filter(|db_item| !db_item.is_expired()) {
+        Some(DbItem {
+            value: Value::List(list),
+            ..
+        }) => Frame::Integer(list.len() as i64),
+        Some(_) => wrong_type(),
+        None => Frame::Integer(0),
+    };
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+pub async fn handle_lindex(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame, index: Frame) {
+    let db = db.lock().await;
+    let key = unpack_bulk_str(key).unwrap();
+
+    let Some(index) = parse_i64_arg(index) else {
+        return conn.write_frame(&not_an_integer()).await.unwrap();
+    };
+
+    let resp_frame = match db.get(&key).filter(|db_item| !db_item.is_expired()) {
+        Some(DbItem {
+            value: Value::List(list),
+            ..
+        }) => {
+            // negative indexes count from the end of the list
+            let index = if index < 0 {
+                list.len() as i64 + index
+            } else {
+                index
+            };
+
+            match usize::try_from(index)
+                .ok()
+                .and_then(|index| list.get(index))
+            {
+                Some(element) => Frame::BulkString(element.clone().into()),
+                None => Frame::NullBulkString,
+            }
+        }
+        Some(_) => wrong_type(),
+        None => Frame::NullBulkString,
+    };
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+// returns the key an element was popped from, so that
+// the pop can be propagated as a plain LPOP
+pub async fn handle_blpop(
+    conn: &mut Connection,
+    db: Arc<Mutex<Db>>,
+    used_memory: Arc<Mutex<usize>>,
+    blocked: Arc<Mutex<BlockedClients>>,
+    args: &[Frame],
+) -> Option<String> {
+    blocking_pop(conn, db, used_memory, blocked, args, true).await
+}
+
+pub async fn handle_brpop(
+    conn: &mut Connection,
+    db: Arc<Mutex<Db>>,
+    used_memory: Arc<Mutex<usize>>,
+    blocked: Arc<Mutex<BlockedClients>>,
+    args: &[Frame],
+) -> Option<String> {
+    blocking_pop(conn, db, used_memory, blocked, args, false).await
+}
+
+// BLPOP and BRPOP, args are the keys followed by the timeout in seconds
+async fn blocking_pop(
+    conn: &mut Connection,
+    db: Arc<Mutex<Db>>,
+    used_memory: Arc<Mutex<usize>>,
+    blocked: Arc<Mutex<BlockedClients>>,
+    args: &[Frame],
+    front: bool,
+) -> Option<String> {
+    let (timeout, keys) = args.split_last().unwrap();
+    let keys: Vec<String> = keys
+        .iter()
+        .map(|key| unpack_bulk_str(key.clone()).unwrap())
+        .collect();
+
+    let timeout = match unpack_bulk_str(timeout.clone())
+        .ok()
+        .and_then(|timeout| timeout.parse::<f64>().ok())
+    {
+        Some(timeout) if timeout < 0.0 => {
+            let resp_frame = Frame::Error("ERR timeout is negative".to_string());
+            conn.write_frame(&resp_frame).await.unwrap();
+            return None;
+        }
+        Some(timeout) if timeout.is_finite() => timeout,
+        _ => {
+            let resp_frame = Frame::Error("ERR timeout is not a float or out of range".to_string());
+            conn.write_frame(&resp_frame).await.unwrap();
+            return None;
+        }
+    };
+
+    // a timeout of 0 blocks until something gets pushed
+    let deadline = (timeout > 0.0).then(|| {
+        Duration::try_from_secs_f64(timeout)
+            .ok()
+            .and_then(|timeout| Instant::now().checked_add(timeout))
+    });
+    let deadline = match deadline {
+        // too far in the future to be represented
+        Some(None) => {
+            let resp_frame = Frame::Error("ERR timeout is out of range".to_string());
+            conn.write_frame(&resp_frame).await.unwrap();
+            return None;
+        }
+        deadline => deadline.flatten(),
+    };
+    let db_index = conn.db_index;
+    let notify = Arc::new(Notify::new());
+    let mut registered = false;
+
+    loop {
+        {
+            // the pop is accounted for here, it's not a write the server
+            // can measure around since it happens whenever a push comes
+            let mut used = used_memory.lock().await;
+            let mut db = db.lock().await;
+
+            for key in &keys {
+                let list = match db.get_mut(key).filter(|db_item| !db_item.is_expired()) {
+                    Some(DbItem {
+                        value: Value::List(list),
+                        ..
+                    }) => list,
+                    Some(_) => {
+                        unblock(&blocked, db_index, &keys, &notify).await;
+                        conn.write_frame(&wrong_type()).await.unwrap();
+                        return None;
+                    }
+                    None => continue,
+                };
+
+                let element = match front {
+                    true => list.pop_front(),
+                    false => list.pop_back(),
+                };
+
+                if let Some(element) = element {
+                    let mut freed = element_size(&element);
+                    if list.is_empty() {
+                        freed += db.remove(key).unwrap().estimated_size(key);
+                    }
+                    *used = used.saturating_sub(freed);
+
+                    unblock(&blocked, db_index, &keys, &notify).await;
+
+                    let resp_frame = Frame::Array(vec![
+                        Frame::BulkString(key.clone().into()),
+                        Frame::BulkString(element.into()),
+                    ]);
+                    conn.write_frame(&resp_frame).await.unwrap();
+                    return Some(key.clone());
+                }
+            }
+
+            // registering under the db lock means no push can slip in
+            // between checking the lists and waiting for a notification
+            if !registered {
+                let mut blocked = blocked.lock().await;
+                for key in &keys {
+                    blocked
+                        .entry((db_index, key.clone()))
+                        .or_default()
+                        .push(Arc::clone(&notify));
+                }
+                registered = true;
+            }
+        }
+
+        let timed_out = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, notify.notified())
+                .await
+                .is_err(),
+            None => {
+                notify.notified().await;
+                false
+            }
+        };
+
+        if timed_out {
+            unblock(&blocked, db_index, &keys, &notify).await;
+            conn.write_frame(&Frame::NullArray).await.unwrap();
+            return None;
+        }
+    }
+}
+
+async fn unblock(
+    blocked: &Mutex<BlockedClients>,
+    db_index: usize,
+    keys: &[String],
+    notify: &Arc<Notify>,
+) {
+    let mut blocked = blocked.lock().await;
+
+    for key in keys {
+        let entry = (db_index, key.clone());
+
+        if let Some(waiters) = blocked.get_mut(&entry) {
+            waiters.retain(|waiter| !Arc::ptr_eq(waiter, notify));
+
+            if waiters.is_empty() {
+                blocked.remove(&entry);
+            }
+        }
+    }
+}
+
+// LPOP and RPOP, args are the key and an optional count
+async fn pop(db: Arc<Mutex<Db>>, args: &[Frame], front: bool) -> Frame {
+    let mut db = db.lock().await;
+    let key = unpack_bulk_str(args[0].clone()).unwrap();
+
+    let count = match args.get(1).cloned().map(parse_i64_arg) {
+        Some(Some(count)) if count < 0 => {
+            return Frame::Error("ERR value is out of range, must be positive".to_string())
+        }
+        Some(Some(count)) => Some(count as usize),
+        Some(None) => return not_an_integer(),
+        None => None,
+    };
+
+    let list = match db.get_mut(&key).filter(|db_item| !db_item.is_expired()) {
+        Some(DbItem {
+            value: Value::List(list),
+            ..
+        }) => list,
+        Some(_) => return wrong_type(),
+        None if count.is_some() => return Frame::NullArray,
+        None => return Frame::NullBulkString,
+    };
+
+    let mut popped = vec![];
+    for _ in 0..count.unwrap_or(1) {
+        let element = match front {
+            true => list.pop_front(),
+            false => list.pop_back(),
+        };
+
+        match element {
+            Some(element) => popped.push(Frame::BulkString(element.into())),
+            None => break,
+        }
+    }
+
+    // redis never keeps an empty list around
+    if list.is_empty() {
+        db.remove(&key);
+    }
+
+    match count {
+        Some(_) => Frame::Array(popped),
+        None => popped.pop().unwrap_or(Frame::NullBulkString),
+    }
+}
+
+// start and stop as given to LRANGE or ZRANGE, where negative
+// indexes count from the end; None when nothing is in range
+fn index_range(start: i64, stop: i64, len: usize) -> Option<RangeInclusive<usize>> {
+    let len = len as i64;
+
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+
+    (start <= stop).then_some(start as usize..=stop as usize)
+}
+
+// LPUSH and RPUSH, args are the key followed by the elements
+async fn push(db: Arc<Mutex<Db>>, args: &[Frame], front: bool) -> Frame {
+    let mut db = db.lock().await;
+    let key = unpack_bulk_str(args[0].clone()).unwrap();
+
+    if db.get(&key).is_some_and(|db_item| db_item.is_expired()) {
+        db.remove(&key);
+    }
+
+    let db_item = db
+        .entry(key)
+        .or_insert_with(|| DbItem::new(Value::List(VecDeque::new()), None));
+
+    let Value::List(list) = &mut db_item.value else {
+        return wrong_type();
+    };
+
+    for element in &args[1..] {
+        let element = unpack_bulk_str(element.clone()).unwrap();
+
+        match front {
+            true => list.push_front(element),
+            false => list.push_back(element),
+        }
+    }
+
+    Frame::Integer(list.len() as i64)
+}
+
+pub async fn handle_hset(conn: &mut Connection, db: Arc<Mutex<Db>>, args: &[Frame], reply: bool) {
+    if args.len() < 3 || args.len().is_multiple_of(2) {
+        if reply {
+            let resp_frame = wrong_arity("hset");
+            conn.write_frame(&resp_frame).await.unwrap();
+        }
+        return;
+    }
+
+    let mut db = db.lock().await;
+    let key = unpack_bulk_str(args[0].clone()).unwrap();
+
+    if db.get(&key).is_some_and(|db_item| db_item.is_expired()) {
+        db.remove(&key);
+    }
+
+    let db_item = db
+        .entry(key)
+        .or_insert_with(|| DbItem::new(Value::Hash(HashMap::new()), None));
+
+    let resp_frame = match &mut db_item.value {
+        Value::Hash(hash) => {
+            let mut added = 0;
+            for pair in args[1..].chunks(2) {
+                let field = unpack_bulk_str(pair[0].clone()).unwrap();
+                let value = unpack_bulk_str(pair[1].clone()).unwrap();
+
+                if hash.insert(field, value).is_none() {
+                    added += 1;
+                }
+            }
+
+            Frame::Integer(added)
+        }
+        _ => wrong_type(),
+    };
+
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+pub async fn handle_hget(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame, field: Frame) {
+    let db = db.lock().await;
+    let key = unpack_bulk_str(key).unwrap();
+    let field = unpack_bulk_str(field).unwrap();
+
+    let resp_frame = match db.get(&key).filter(|db_item| !db_item.is_expired()) {
+        Some(DbItem {
+            value: Value::Hash(hash),
+            ..
+        }) => match hash.get(&field) {
+            Some(value) => Frame::BulkString(value.clone().into()),
+            None => Frame::NullBulkString,
+        },
+        Some(_) => wrong_type(),
+        None => Frame::NullBulkString,
+    };
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+// a map for RESP3 clients, write_frame flattens it for RESP2 ones
+pub async fn handle_hgetall(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame) {
+    let db = db.lock().await;
+    let key = unpack_bulk_str(key).unwrap();
+
+    let resp_frame = match db.get(&key).filter(|db_item| !db_item.is_expired()) {
+        Some(DbItem {
+            value: Value::Hash(hash),
+            ..
+        }) => Frame::Map(
+            hash.iter()
+                .map(|(field, value)| {
+                    (
+                        Frame::BulkString(field.clone().into()),
+                        Frame::BulkString(value.clone().into()),
+                    )
+                })
+                .collect(),
+        ),
+        Some(_) => wrong_type(),
+        None => Frame::Map(vec![]),
+    };
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+pub async fn handle_hdel(conn: &mut Connection, db: Arc<Mutex<Db>>, args: &[Frame], reply: bool) {
+    let mut db = db.lock().await;
+    let key = unpack_bulk_str(args[0].clone()).unwrap();
+
+    let resp_frame = match db.get_mut(&key).filter(|db_item| !db_item.is_expired()) {
+        Some(DbItem {
+            value: Value::Hash(hash),
+            ..
+        }) => {
+            let mut deleted = 0;
+            for field in &args[1..] {
+                let field = unpack_bulk_str(field.clone()).unwrap();
+
+                if hash.remove(&field).is_some() {
+                    deleted += 1;
+                }
+            }
+
+            // redis never keeps an empty hash around
+            if hash.is_empty() {
+                db.remove(&key);
+            }
+
+            Frame::Integer(deleted)
+        }
+        Some(_) => wrong_type(),
+        None => Frame::Integer(0),
+    };
+
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+pub async fn handle_hkeys(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame) {
+    let resp_frame = inspect_hash(db, key, |hash| {
+        Frame::Array(
+            hash.keys()
+                .map(|field| Frame::BulkString(field.clone().into()))
+                .collect(),
+        )
+    })
+    .await;
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+pub async fn handle_hvals(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame) {
+    let resp_frame = inspect_hash(db, key, |hash| {
+        Frame::Array(
+            hash.values()
+                .map(|value| Frame::BulkString(value.clone().into()))
+                .collect(),
+        )
+    })
+    .await;
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+pub async fn handle_hlen(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame) {
+    let resp_frame = inspect_hash(db, key, |hash| Frame::Integer(hash.len() as i64)).await;
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+pub async fn handle_hexists(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame, field: Frame) {
+    let field = unpack_bulk_str(field).unwrap();
+    let resp_frame = inspect_hash(db, key, |hash| {
+        Frame::Integer(hash.contains_key(&field) as i64)
+    })
+    .await;
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+pub async fn handle_hincrby(
+    conn: &mut Connection,
+    db: Arc<Mutex<Db>>,
+    args: &[Frame],
+    reply: bool,
+) {
+    let mut db = db.lock().await;
+    let key = unpack_bulk_str(args[0].clone()).unwrap();
+    let field = unpack_bulk_str(args[1].clone()).unwrap();
+
+    let Some(increment) = parse_i64_arg(args[2].clone()) else {
+        if reply {
+            conn.write_frame(&not_an_integer()).await.unwrap();
+        }
+        return;
+    };
+
+    if db.get(&key).is_some_and(|db_item| db_item.is_expired()) {
+        db.remove(&key);
+    }
+
+    let db_item = db
+        .entry(key)
+        .or_insert_with(|| DbItem::new(Value::Hash(HashMap::new()), None));
+
+    let resp_frame = match &mut db_item.value {
+        Value::Hash(hash) => {
+            let current = match hash.get(&field) {
+                Some(value) => value.parse::<i64>().ok(),
+                None => Some(0),
+            };
+
+            match current.and_then(|current| current.checked_add(increment)) {
+                Some(new_value) => {
+                    hash.insert(field, new_value.to_string());
+                    Frame::Integer(new_value)
+                }
+                None => not_an_integer(),
+            }
+        }
+        _ => wrong_type(),
+    };
+
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+// runs a read-only hash command; a missing key behaves like an empty hash
+async fn inspect_hash<F>(db: Arc<Mutex<Db>>, key: Frame, inspect: F) -> Frame
+where
+    F: FnOnce(&HashMap<String, String>) -> Frame,
+{
+    let db = db.lock().await;
+    let key = unpack_bulk_str(key).unwrap();
+
+    match db.get(&key).filter(|db_item| !db_item.is_expired()) {
+        Some(DbItem {
+            value: Value::Hash(hash),
+            ..
+        }) => inspect(hash),
+        Some(_) => wrong_type(),
+        None => inspect(&HashMap::new()),
+    }
+}
+
+pub async fn handle_sadd(conn: &mut Connection, db: Arc<Mutex<Db>>, args: &[Frame], reply: bool) {
+    let mut db = db.lock().await;
+    let key = unpack_bulk_str(args[0].clone()).unwrap();
+
+    if db.get(&key).is_some_and(|db_item| db_item.is_expired()) {
+        db.remove(&key);
+    }
+
+    let db_item = db
+        .entry(key)
+        .or_insert_with(|| DbItem::new(Value::Set(HashSet::new()), None));
+
+    let resp_frame = match &mut db_item.value {
+        Value::Set(set) => {
+            let mut added = 0;
+            for member in &args[1..] {
+                if set.insert(unpack_bulk_str(member.clone()).unwrap()) {
+                    added += 1;
+                }
+            }
+
+            Frame::Integer(added)
+        }
+        _ => wrong_type(),
+    };
+
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+pub async fn handle_srem(conn: &mut Connection, db: Arc<Mutex<Db>>, args: &[Frame], reply: bool) {
+    let mut db = db.lock().await;
+    let key = unpack_bulk_str(args[0].clone()).unwrap();
+
+    let resp_frame = match db.get_mut(&key).filter(|db_item| !db_item.is_expired()) {
+        Some(DbItem {
+            value: Value::Set(set),
+            ..
+        }) => {
+            let mut removed = 0;
+            for member in &args[1..] {
+                if set.remove(&unpack_bulk_str(member.clone()).unwrap()) {
+                    removed += 1;
+                }
+            }
+
+            // redis never keeps an empty set around
+            if set.is_empty() {
+                db.remove(&key);
+            }
+
+            Frame::Integer(removed)
+        }
+        Some(_) => wrong_type(),
+        None => Frame::Integer(0),
+    };
+
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+pub async fn handle_smembers(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame) {
+    let resp_frame = inspect_set(db, key, |set| {
+        Frame::Array(
+            set.iter()
+                .map(|member| Frame::BulkString(member.clone().into()))
+                .collect(),
+        )
+    })
+    .await;
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+pub async fn handle_sismember(
+    conn: &mut Connection,
+    db: Arc<Mutex<Db>>,
+    key: Frame,
+    member: Frame,
+) {
+    let member = unpack_bulk_str(member).unwrap();
+    let resp_frame = inspect_set(db, key, |set| Frame::Integer(set.contains(&member) as i64)).await;
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+pub async fn handle_scard(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame) {
+    let resp_frame = inspect_set(db, key, |set| Frame::Integer(set.len() as i64)).await;
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+pub async fn handle_sinter(conn: &mut Connection, db: Arc<Mutex<Db>>, keys: &[Frame]) {
+    let resp_frame = combine_sets(db, keys, |first, rest| {
+        first
+            .into_iter()
+            .filter(|member| rest.iter().all(|set| set.contains(member)))
+            .collect()
+    })
+    .await;
+
+    conn.write_frame(&resp_frame).await.unwrap();
 }
 
-pub async fn handle_psync(
-    conn: &mut Connection,
-    repl_conf: Arc<Mutex<ReplicationConfig>>,
-    sender: Arc<Sender<Frame>>,
-) {
-    let master_replid = {
-        let guard = repl_conf.lock().await;
-        guard.master_replid.clone().unwrap()
-    };
+pub async fn handle_sunion(conn: &mut Connection, db: Arc<Mutex<Db>>, keys: &[Frame]) {
+    let resp_frame = combine_sets(db, keys, |first, rest| {
+        rest.into_iter().fold(first, |mut union, set| {
+            union.extend(set);
+            union
+        })
+    })
+    .await;
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
 
-    let resp = format!("FULLRESYNC {} 0", master_replid);
-    let resp_frame = Frame::SimpleString(resp);
+pub async fn handle_sdiff(conn: &mut Connection, db: Arc<Mutex<Db>>, keys: &[Frame]) {
+    let resp_frame = combine_sets(db, keys, |first, rest| {
+        first
+            .into_iter()
+            .filter(|member| !rest.iter().any(|set| set.contains(member)))
+            .collect()
+    })
+    .await;
 
     conn.write_frame(&resp_frame).await.unwrap();
+}
 
-    let empty_rdb: Vec<u8> = vec![
-        0x52, 0x45, 0x44, 0x49, 0x53, 0x30, 0x30, 0x31, 0x31, 0xfa, 0x09, 0x72, 0x65, 0x64, 0x69,
-        0x73, 0x2d, 0x76, 0x65, 0x72, 0x05, 0x37, 0x2e, 0x32, 0x2e, 0x30, 0xfa, 0x0a, 0x72, 0x65,
-        0x64, 0x69, 0x73, 0x2d, 0x62, 0x69, 0x74, 0x73, 0xc0, 0x40, 0xfa, 0x05, 0x63, 0x74, 0x69,
-        0x6d, 0x65, 0xc2, 0x6d, 0x08, 0xbc, 0x65, 0xfa, 0x08, 0x75, 0x73, 0x65, 0x64, 0x2d, 0x6d,
-        0x65, 0x6d, 0xc2, 0xb0, 0xc4, 0x10, 0x00, 0xfa, 0x08, 0x61, 0x6f, 0x66, 0x2d, 0x62, 0x61,
-        0x73, 0x65, 0xc0, 0x00, 0xff, 0xf0, 0x6e, 0x3b, 0xfe, 0xc0, 0xff, 0x5a, 0xa2,
-    ];
-    conn.write(format!("${}\r\n", empty_rdb.len(),).as_bytes())
-        .await
-        .unwrap();
-    conn.write(&empty_rdb).await.unwrap();
+// SINTER, SUNION and SDIFF get the first set and the rest of them;
+// missing keys count as empty sets
+async fn combine_sets<F>(db: Arc<Mutex<Db>>, keys: &[Frame], combine: F) -> Frame
+where
+    F: FnOnce(HashSet<String>, Vec<HashSet<String>>) -> HashSet<String>,
+{
+    let db = db.lock().await;
 
-    let mut receiver = sender.subscribe();
+    let mut sets = vec![];
+    for key in keys {
+        let key = unpack_bulk_str(key.clone()).unwrap();
 
-    while let Ok(f) = receiver.recv().await {
-        conn.write_frame(&f).await.unwrap();
+        match db.get(&key).filter(|db_item| !db_item.is_expired()) {
+            Some(DbItem {
+                value: Value::Set(set),
+                ..
+            }) => sets.push(set.clone()),
+            Some(_) => return wrong_type(),
+            None => sets.push(HashSet::new()),
+        }
     }
+
+    let mut sets = sets.into_iter();
+    let first = sets.next().unwrap_or_default();
+
+    Frame::Array(
+        combine(first, sets.collect())
+            .into_iter()
+            .map(|member| Frame::BulkString(member.into()))
+            .collect(),
+    )
 }
 
-pub async fn handle_replconf(
-    conn: &mut Connection,
-    repl_conf: Arc<Mutex<ReplicationConfig>>,
-    args: &[Frame],
-    respond: bool,
-) {
-    let slave_repl_offset = {
-        let guard = repl_conf.lock().await;
-        guard.slave_repl_offset.unwrap_or(0)
-    };
+// runs a read-only set command; a missing key behaves like an empty set
+async fn inspect_set<F>(db: Arc<Mutex<Db>>, key: Frame, inspect: F) -> Frame
+where
+    F: FnOnce(&HashSet<String>) -> Frame,
+{
+    let db = db.lock().await;
+    let key = unpack_bulk_str(key).unwrap();
+
+    match db.get(&key).filter(|db_item| !db_item.is_expired()) {
+        Some(DbItem {
+            value: Value::Set(set),
+            ..
+        }) => inspect(set),
+        Some(_) => wrong_type(),
+        None => inspect(&HashSet::new()),
+    }
+}
 
-    let arg = args.first().clone().unwrap();
+pub async fn handle_zadd(conn: &mut Connection, db: Arc<Mutex<Db>>, args: &[Frame], reply: bool) {
+    let pairs = &args[1..];
 
-    match unpack_bulk_str(arg.to_owned()).unwrap().as_str() {
-        "GETACK" => {
-            let resp_frame = Frame::Array(vec![
-                Frame::SimpleString("REPLCONF".to_string()),
-                Frame::SimpleString("ACK".to_string()),
-                Frame::SimpleString(slave_repl_offset.to_string()),
-            ]);
-            conn.write_frame(&resp_frame).await.unwrap();
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        if reply {
+            conn.write_frame(&Frame::Error("ERR syntax error".to_string()))
+                .await
+                .unwrap();
         }
-        _ => {
-            if respond {
-                let resp_frame = Frame::SimpleString("OK".to_string());
+        return;
+    }
+
+    let mut members = vec![];
+    for pair in pairs.chunks(2) {
+        let Some(score) = parse_f64_arg(pair[0].clone()) else {
+            if reply {
+                let resp_frame = Frame::Error("ERR value is not a valid float".to_string());
                 conn.write_frame(&resp_frame).await.unwrap();
             }
+            return;
+        };
+
+        members.push((unpack_bulk_str(pair[1].clone()).unwrap(), score));
+    }
+
+    let mut db = db.lock().await;
+    let key = unpack_bulk_str(args[0].clone()).unwrap();
+
+    if db.get(&key).is_some_and(|db_item| db_item.is_expired()) {
+        db.remove(&key);
+    }
+
+    let db_item = db
+        .entry(key)
+        .or_insert_with(|| DbItem::new(Value::SortedSet(SortedSet::new()), None));
+
+    let resp_frame = match &mut db_item.value {
+        Value::SortedSet(sorted_set) => {
+            let mut added = 0;
+            for (member, score) in members {
+                if sorted_set.insert(member, score) {
+                    added += 1;
+                }
+            }
+
+            Frame::Integer(added)
         }
+        _ => wrong_type(),
+    };
+
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
     }
 }
 
-pub async fn handle_info(conn: &mut Connection, replication_config: Arc<Mutex<ReplicationConfig>>) {
-    let repl_conf = replication_config.lock().await;
-    let mut result_values = vec![format!("role:{}", repl_conf.role)];
+pub async fn handle_zscore(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame, member: Frame) {
+    let member = unpack_bulk_str(member).unwrap();
+    let resp_frame = inspect_sorted_set(db, key, |sorted_set| match sorted_set.score(&member) {
+        Some(score) => Frame::Double(score),
+        None => Frame::NullBulkString,
+    })
+    .await;
 
-    match repl_conf.role {
-        ReplRole::Master => {
-            let master_replid = repl_conf.master_replid.as_ref().unwrap();
-            let master_repl_offset = repl_conf.master_repl_offset.as_ref().unwrap();
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+pub async fn handle_zrank(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame, member: Frame) {
+    let member = unpack_bulk_str(member).unwrap();
+    let resp_frame = inspect_sorted_set(db, key, |sorted_set| match sorted_set.rank(&member) {
+        Some(rank) => Frame::Integer(rank as i64),
+        None => Frame::NullBulkString,
+    })
+    .await;
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
 
-            result_values.push(format!("master_replid:{}", master_replid.to_string()));
-            result_values.push(format!(
-                "master_repl_offset:{}",
-                master_repl_offset.to_string()
-            ));
+pub async fn handle_zrange(conn: &mut Connection, db: Arc<Mutex<Db>>, args: &[Frame]) {
+    let with_scores = match parse_with_scores(&args[3..]) {
+        Some(with_scores) => with_scores,
+        None => {
+            let resp_frame = Frame::Error("ERR syntax error".to_string());
+            return conn.write_frame(&resp_frame).await.unwrap();
         }
-        ReplRole::Slave => {}
-    }
+    };
+
+    let (Some(start), Some(stop)) = (
+        parse_i64_arg(args[1].clone()),
+        parse_i64_arg(args[2].clone()),
+    ) else {
+        return conn.write_frame(&not_an_integer()).await.unwrap();
+    };
 
-    let resp_frame = Frame::BulkString(result_values.join("\r\n"));
+    let resp_frame = inspect_sorted_set(db, args[0].clone(), |sorted_set| {
+        match index_range(start, stop, sorted_set.len()) {
+            Some(range) => {
+                let (start, count) = (*range.start(), range.count());
+                scored_members(sorted_set.iter().skip(start).take(count), with_scores)
+            }
+            None => Frame::Array(vec![]),
+        }
+    })
+    .await;
 
     conn.write_frame(&resp_frame).await.unwrap();
 }
 
-pub async fn handle_keys(conn: &mut Connection, db: Arc<Mutex<Db>>) {
-    let db = db.lock().await;
+pub async fn handle_zrangebyscore(conn: &mut Connection, db: Arc<Mutex<Db>>, args: &[Frame]) {
+    let with_scores = match parse_with_scores(&args[3..]) {
+        Some(with_scores) => with_scores,
+        None => {
+            let resp_frame = Frame::Error("ERR syntax error".to_string());
+            return conn.write_frame(&resp_frame).await.unwrap();
+        }
+    };
 
-    let resp_frame = Frame::Array(
-        db.keys()
-            .map(|key| Frame::SimpleString(key.to_string()))
-            .collect(),
-    );
+    let (Some(min), Some(max)) = (parse_score_bound(&args[1]), parse_score_bound(&args[2])) else {
+        let resp_frame = Frame::Error("ERR min or max is not a float".to_string());
+        return conn.write_frame(&resp_frame).await.unwrap();
+    };
+
+    let resp_frame = inspect_sorted_set(db, args[0].clone(), |sorted_set| {
+        let members = sorted_set.iter().filter(|(_, score)| {
+            let above_min = if min.1 {
+                *score > min.0
+            } else {
+                *score >= min.0
+            };
+            let below_max = if max.1 {
+                *score < max.0
+            } else {
+                *score <= max.0
+            };
+
+            above_min && below_max
+        });
+
+        scored_members(members, with_scores)
+    })
+    .await;
 
     conn.write_frame(&resp_frame).await.unwrap();
 }
 
-pub async fn handle_config(
+// Some(true) for a trailing WITHSCORES, None for anything else unexpected
+fn parse_with_scores(args: &[Frame]) -> Option<bool> {
+    match args {
+        [] => Some(false),
+        [arg] => unpack_bulk_str(arg.clone())
+            .ok()
+            .filter(|arg| arg.eq_ignore_ascii_case("WITHSCORES"))
+            .map(|_| true),
+        _ => None,
+    }
+}
+
+// a score bound as in ZRANGEBYSCORE: a float, "-inf" or "+inf",
+// prefixed with "(" when it's exclusive; returns the score and
+// whether it's exclusive
+fn parse_score_bound(arg: &Frame) -> Option<(f64, bool)> {
+    let bound = unpack_bulk_str(arg.clone()).ok()?;
+
+    match bound.strip_prefix('(') {
+        Some(score) => Some((parse_f64(score)?, true)),
+        None => Some((parse_f64(&bound)?, false)),
+    }
+}
+
+fn scored_members<'a>(
+    members: impl Iterator<Item = (&'a String, f64)>,
+    with_scores: bool,
+) -> Frame {
+    let mut frames = vec![];
+
+    for (member, score) in members {
+        frames.push(Frame::BulkString(member.clone().into()));
+
+        if with_scores {
+            frames.push(Frame::Double(score));
+        }
+    }
+
+    Frame::Array(frames)
+}
+
+// runs a read-only sorted set command;
+// a missing key behaves like an empty sorted set
+async fn inspect_sorted_set<F>(db: Arc<Mutex<Db>>, key: Frame, inspect: F) -> Frame
+where
+    F: FnOnce(&SortedSet) -> Frame,
+{
+    let db = db.lock().await;
+    let key = unpack_bulk_str(key).unwrap();
+
+    match db.get(&key).filter(|db_item| !db_item.is_expired()) {
+        Some(DbItem {
+            value: Value::SortedSet(sorted_set),
+            ..
+        }) => inspect(sorted_set),
+        Some(_) => wrong_type(),
+        None => inspect(&SortedSet::new()),
+    }
+}
+
+pub async fn handle_expire(
     conn: &mut Connection,
-    config: &Config,
-    config_command: Frame,
-    config_key: Frame,
+    db: Arc<Mutex<Db>>,
+    key: Frame,
+    seconds: Frame,
+    reply: bool,
 ) {
-    let config_command = unpack_bulk_str(config_command).unwrap();
+    let resp_frame = match parse_i64_arg(seconds).and_then(|seconds| seconds.checked_mul(1000)) {
+        Some(ms) => expire_in(db, key, ms).await,
+        None => not_an_integer(),
+    };
 
-    let resp_frame = match config_command.to_uppercase().as_str() {
-        "GET" => {
-            let config_key_name = unpack_bulk_str(config_key.clone()).unwrap();
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
 
-            match config.get(config_key_name) {
-                Some(v) => Frame::Array(vec![config_key, Frame::BulkString(v)]),
-                None => Frame::NullBulkString,
-            }
-        }
-        _ => Frame::NullBulkString,
+pub async fn handle_pexpire(
+    conn: &mut Connection,
+    db: Arc<Mutex<Db>>,
+    key: Frame,
+    ms: Frame,
+    reply: bool,
+) {
+    let resp_frame = match parse_i64_arg(ms) {
+        Some(ms) => expire_in(db, key, ms).await,
+        None => not_an_integer(),
     };
 
-    conn.write_frame(&resp_frame).await.unwrap();
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
 }
 
-pub async fn handle_set(
+pub async fn handle_expireat(
     conn: &mut Connection,
     db: Arc<Mutex<Db>>,
-    frame: Frame,
-    sender: Arc<Sender<Frame>>,
+    key: Frame,
+    timestamp: Frame,
+    reply: bool,
+) {
+    let resp_frame = match parse_i64_arg(timestamp).and_then(|seconds| seconds.checked_mul(1000)) {
+        Some(timestamp_ms) => expire_at(db, key, timestamp_ms).await,
+        None => not_an_integer(),
+    };
+
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+pub async fn handle_pexpireat(
+    conn: &mut Connection,
+    db: Arc<Mutex<Db>>,
+    key: Frame,
+    timestamp_ms: Frame,
     reply: bool,
 ) {
+    let resp_frame = match parse_i64_arg(timestamp_ms) {
+        Some(timestamp_ms) => expire_at(db, key, timestamp_ms).await,
+        None => not_an_integer(),
+    };
+
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+// deadlines are kept as Instants, so the unix timestamp
+// becomes a TTL relative to the current wall clock time
+async fn expire_at(db: Arc<Mutex<Db>>, key: Frame, timestamp_ms: i64) -> Frame {
+    let now_ms = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+
+    expire_in(db, key, timestamp_ms.saturating_sub(now_ms)).await
+}
+
+async fn expire_in(db: Arc<Mutex<Db>>, key: Frame, ms: i64) -> Frame {
     let mut db = db.lock().await;
-    let (_, args) = extract_command(frame.clone()).unwrap();
+    let key = unpack_bulk_str(key).unwrap();
 
-    let key = unpack_bulk_str(args[0].clone()).unwrap();
-    let value = unpack_bulk_str(args[1].clone()).unwrap();
+    let db_item = db.get_mut(&key).filter(|db_item| !db_item.is_expired());
 
-    let expires = if args.len() > 2 {
-        let command_name = unpack_bulk_str(args[2].clone()).unwrap();
-        if command_name != "px" {
-            panic!("wrong precision name!");
+    match db_item {
+        Some(db_item) if ms > 0 => {
+            db_item.expires_at = Some(Instant::now() + Duration::from_millis(ms as u64));
+            Frame::Integer(1)
+        }
+        // a non-positive TTL expires the key right away
+        Some(_) => {
+            db.remove(&key);
+            Frame::Integer(1)
         }
+        None => Frame::Integer(0),
+    }
+}
 
-        let ms_str = unpack_bulk_str(args[3].clone()).unwrap();
-        ms_str.parse::<usize>().unwrap()
-    } else {
-        0
+pub async fn handle_persist(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame, reply: bool) {
+    let mut db = db.lock().await;
+    let key = unpack_bulk_str(key).unwrap();
+
+    let db_item = db.get_mut(&key).filter(|db_item| !db_item.is_expired());
+
+    let resp_frame = match db_item {
+        Some(db_item) if db_item.expires_at.is_some() => {
+            db_item.expires_at = None;
+            Frame::Integer(1)
+        }
+        _ => Frame::Integer(0),
     };
 
-    let item = DbItem {
-        value,
-        expires,
-        created: Instant::now(),
+    if reply {
+        conn.write_frame(&resp_frame).await.unwrap();
+    }
+}
+
+pub async fn handle_type(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame) {
+    let db = db.lock().await;
+    let key = unpack_bulk_str(key).unwrap();
+
+    let type_name = match db.get(&key) {
+        Some(db_item) if !db_item.is_expired() => db_item.type_name(),
+        _ => "none",
     };
 
-    db.insert(key, item);
+    conn.write_frame(&Frame::SimpleString(type_name.to_string()))
+        .await
+        .unwrap();
+}
+
+// returns the database the value was copied to, so list waiters there can be woken up
+pub async fn handle_copy(
+    conn: &mut Connection,
+    dbs: &[Arc<Mutex<Db>>],
+    args: &[Frame],
+    reply: bool,
+) -> Option<usize> {
+    let source = unpack_bulk_str(args[0].clone()).unwrap();
+    let destination = unpack_bulk_str(args[1].clone()).unwrap();
+
+    let (resp_frame, copied_to) = match parse_copy_options(conn.db_index, &args[2..]) {
+        Err(resp_frame) => (resp_frame, None),
+        Ok((db_index, _)) if db_index == conn.db_index && source == destination => {
+            let message = "ERR source and destination objects are the same";
+            (Frame::Error(message.to_string()), None)
+        }
+        Ok((db_index, replace)) => {
+            let (from, to) = (&dbs[conn.db_index], &dbs[db_index]);
+            match copy_value(from, to, &source, destination, replace).await {
+                true => (Frame::Integer(1), Some(db_index)),
+                false => (Frame::Integer(0), None),
+            }
+        }
+    };
 
     if reply {
-        conn.write_frame(&Frame::SimpleString("OK".to_string()))
-            .await
-            .unwrap();
+        conn.write_frame(&resp_frame).await.unwrap();
     }
 
-    sender.send(frame).unwrap();
+    copied_to
 }
 
-pub async fn handle_get(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame) {
-    let db = db.lock().await;
-    let key = unpack_bulk_str(key).unwrap();
+// the destination database and whether to REPLACE
+pub fn parse_copy_options(db_index: usize, options: &[Frame]) -> Result<(usize, bool), Frame> {
+    let mut db_index = db_index;
+    let mut replace = false;
+
+    let mut options = options.iter().cloned();
+    while let Some(option) = options.next() {
+        match unpack_bulk_str(option).unwrap().to_uppercase().as_str() {
+            "REPLACE" => replace = true,
+            "DB" => match options.next().map(parse_i64_arg) {
+                Some(Some(index)) if (0..NUM_DATABASES as i64).contains(&index) => {
+                    db_index = index as usize
+                }
+                Some(Some(_)) => {
+                    return Err(Frame::Error("ERR DB index is out of range".to_string()))
+                }
+                Some(None) => return Err(not_an_integer()),
+                None => return Err(Frame::Error("ERR syntax error".to_string())),
+            },
+            _ => return Err(Frame::Error("ERR syntax error".to_string())),
+        }
+    }
+
+    Ok((db_index, replace))
+}
+
+// the copy is independent of the source and gets a version of its own
+async fn copy_value(
+    from: &Mutex<Db>,
+    to: &Mutex<Db>,
+    source: &str,
+    destination: String,
+    replace: bool,
+) -> bool {
+    // the source is let go of before the destination gets locked,
+    // which is the same database for copies within one
+    let copied = {
+        let db = from.lock().await;
+        db.get(source)
+            .filter(|db_item| !db_item.is_expired())
+            .map(|db_item| DbItem::new(db_item.value.clone(), db_item.expires_at))
+    };
 
-    let frame = match db.get(&key) {
-        Some(db_item) => {
-            let result = Frame::BulkString(db_item.value.to_string());
+    let Some(copied) = copied else {
+        return false;
+    };
+
+    let mut db = to.lock().await;
+    if !replace
+        && db
+            .get(&destination)
+            .is_some_and(|db_item| !db_item.is_expired())
+    {
+        return false;
+    }
+
+    db.insert(destination, copied);
+    true
+}
+
+pub async fn handle_object(conn: &mut Connection, db: Arc<Mutex<Db>>, args: &[Frame]) {
+    let subcommand = unpack_bulk_str(args[0].clone()).unwrap().to_uppercase();
 
-            let is_expired = db_item.expires > 0
-                && db_item.created.elapsed().as_millis() > db_item.expires as u128;
+    let resp_frame = match (subcommand.as_str(), args.get(1)) {
+        ("ENCODING", Some(key)) => {
+            let db = db.lock().await;
+            let key = unpack_bulk_str(key.clone()).unwrap();
 
-            match is_expired {
-                true => Frame::NullBulkString,
-                false => result,
+            match db.get(&key).filter(|db_item| !db_item.is_expired()) {
+                Some(db_item) => Frame::BulkString(db_item.encoding().into()),
+                None => Frame::Error("ERR no such key".to_string()),
             }
         }
-        None => Frame::NullBulkString,
+        ("ENCODING", None) => wrong_arity("object|encoding"),
+        _ => Frame::Error(format!(
+            "ERR unknown subcommand '{}'. Try OBJECT HELP.",
+            subcommand
+        )),
     };
 
-    conn.write_frame(&frame).await.unwrap();
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+pub async fn handle_ttl(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame) {
+    let resp_frame = match remaining_ttl(db, key).await {
+        // round to the closest second like redis does
+        Some(ms) if ms >= 0 => Frame::Integer((ms + 500) / 1000),
+        Some(code) => Frame::Integer(code),
+        None => Frame::Integer(-2),
+    };
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+pub async fn handle_pttl(conn: &mut Connection, db: Arc<Mutex<Db>>, key: Frame) {
+    let resp_frame = match remaining_ttl(db, key).await {
+        Some(ms) => Frame::Integer(ms),
+        None => Frame::Integer(-2),
+    };
+
+    conn.write_frame(&resp_frame).await.unwrap();
+}
+
+// remaining time to live in ms, -1 for a key without expiry
+// and None for a missing or already expired key
+async fn remaining_ttl(db: Arc<Mutex<Db>>, key: Frame) -> Option<i64> {
+    let db = db.lock().await;
+    let key = unpack_bulk_str(key).unwrap();
+
+    let db_item = db.get(&key).filter(|db_item| !db_item.is_expired())?;
+    let Some(expires_at) = db_item.expires_at else {
+        return Some(-1);
+    };
+
+    Some(
+        expires_at
+            .saturating_duration_since(Instant::now())
+            .as_millis() as i64,
+    )
 }
 
 pub fn extract_command(frame: Frame) -> Result<(String, Vec<Frame>)> {
     match frame {
-        Frame::Array(a) => Ok((
-            unpack_bulk_str(a.first().unwrap().clone())?,
+        Frame::Array(a) if !a.is_empty() => Ok((
+            unpack_bulk_str(a[0].clone())?,
             a.into_iter().skip(1).collect(),
         )),
         _ => Err(anyhow::anyhow!("Unexpected command format")),
     }
 }
 
-fn unpack_bulk_str(frame: Frame) -> Result<String> {
+// for values that are stored as they are, so they can be any bytes
+pub fn unpack_bulk_bytes(frame: Frame) -> Result<Bytes> {
+    match frame {
+        Frame::BulkString(bytes) => Ok(bytes),
+        _ => Err(anyhow::anyhow!("Expected a bulk string")),
+    }
+}
+
+pub fn unpack_bulk_str(frame: Frame) -> Result<String> {
     match frame {
-        Frame::BulkString(s) => Ok(s),
+        Frame::BulkString(s) => Ok(String::from_utf8(s.to_vec())?),
         _ => Err(anyhow::anyhow!("Expected command to be a bulk string")),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<Frame> {
+        args.iter()
+            .map(|arg| Frame::BulkString(arg.to_string().into()))
+            .collect()
+    }
+
+    #[test]
+    fn set_options_default_to_a_plain_set() {
+        assert_eq!(SetOptions::parse(&[]).unwrap(), SetOptions::default());
+    }
+
+    #[test]
+    fn set_options_parse_expiry_and_condition() {
+        let options = SetOptions::parse(&args(&["ex", "10", "NX", "GET"])).unwrap();
+
+        assert_eq!(
+            options,
+            SetOptions {
+                expires: 10_000,
+                condition: SetCondition::IfAbsent,
+                keep_ttl: false,
+                get: true,
+            }
+        );
+
+        let options = SetOptions::parse(&args(&["PX", "250", "XX"])).unwrap();
+        assert_eq!(options.expires, 250);
+        assert_eq!(options.condition, SetCondition::IfPresent);
+
+        let options = SetOptions::parse(&args(&["KEEPTTL"])).unwrap();
+        assert!(options.keep_ttl);
+    }
+
+    #[test]
+    fn set_options_reject_conflicts_and_bad_values() {
+        for bad in [
+            &["EX", "10", "PX", "10"][..],
+            &["EX", "10", "KEEPTTL"],
+            &["KEEPTTL", "PX", "10"],
+            &["NX", "XX"],
+            &["EX"],
+            &["EX", "0"],
+            &["EX", "-1"],
+            &["EX", "ten"],
+            &["EX", "18446744073709551615"],
+            &["SOON"],
+        ] {
+            assert!(SetOptions::parse(&args(bad)).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn scan_options_parse() {
+        let options = ScanOptions::parse(&args(&["5", "match", "user:*", "COUNT", "100"])).unwrap();
+
+        assert_eq!(
+            options,
+            ScanOptions {
+                cursor: 5,
+                pattern: Some("user:*".to_string()),
+                count: 100,
+            }
+        );
+
+        assert_eq!(ScanOptions::parse(&args(&["0"])).unwrap().count, 10);
+    }
+
+    #[test]
+    fn scan_options_reject_bad_arguments() {
+        for bad in [
+            &[][..],
+            &["-1"],
+            &["0", "COUNT", "0"],
+            &["0", "COUNT"],
+            &["0", "LIMIT", "5"],
+        ] {
+            assert!(ScanOptions::parse(&args(bad)).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn scan_pages_cover_every_item_once() {
+        let items: Vec<String> = (0..1000).map(|i| format!("key:{i:04}")).collect();
+        let mut options = ScanOptions::parse(&args(&["0", "COUNT", "64"])).unwrap();
+        let mut seen = vec![];
+
+        loop {
+            let (next_cursor, page) = options.page(items.clone());
+            seen.extend(page);
+
+            if next_cursor == 0 {
+                break;
+            }
+            options.cursor = next_cursor;
+        }
+
+        assert_eq!(seen, items);
+    }
+
+    #[test]
+    fn scan_pages_filter_by_pattern() {
+        let items = vec!["a1".to_string(), "b1".to_string(), "a2".to_string()];
+        let options = ScanOptions::parse(&args(&["0", "MATCH", "a*"])).unwrap();
+
+        assert_eq!(
+            options.page(items),
+            (0, vec!["a1".to_string(), "a2".to_string()])
+        );
+    }
+}
@@ -2,71 +2,111 @@ use args::ServiceArguments;
 use clap::Parser;
 use config::Config;
 use connection::Connection;
-use db::DbItem;
+use db::{DbItem, NUM_DATABASES};
+use replication::STREAM_CAPACITY;
 use server::RedisServer;
 use std::collections::HashMap;
+use std::process;
 use std::sync::Arc;
 use tokio::sync::{broadcast, Mutex};
+use tokio::task::JoinSet;
+use tokio::time::{timeout, Duration};
 
 mod args;
+mod clients;
+mod commands;
 mod config;
 mod connection;
 mod db;
 mod frame;
+mod glob;
 mod handlers;
+mod pubsub;
 mod rdb;
 mod replication;
 mod server;
+mod sorted_set;
+
+// how long connections get to wrap up before the process exits anyway,
+// e.g. a client blocked in BLPOP would otherwise hold it up forever
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// how long to wait after a failed accept, which is mostly running out
+// of file descriptors and only goes away as clients disconnect
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
 
 #[tokio::main]
 async fn main() {
     let args = ServiceArguments::parse();
 
-    let config = Config::from_args(args);
-    let db = Arc::new(Mutex::new(HashMap::new()));
-    let server = Arc::new(RedisServer::new(config, db));
+    let config = match Config::from_args(args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e:#}");
+            process::exit(1);
+        }
+    };
+    let dbs = (0..NUM_DATABASES)
+        .map(|_| Arc::new(Mutex::new(HashMap::new())))
+        .collect();
+    let server = Arc::new(RedisServer::new(config, dbs));
 
-    let (sender, _rx) = broadcast::channel(16);
+    let (sender, _) = broadcast::channel(STREAM_CAPACITY);
     let sender = Arc::new(sender);
 
     if server.is_master().await {
         server.load_rdb().await;
     }
 
-    let listener = server.listen().await;
-
-    match server.connect_to_master().await {
-        Ok(stream) => {
-            if let Some(stream) = stream {
-                let mut conn_to_master = Connection::new(stream);
-                let sender_for_handshake = Arc::clone(&sender);
+    let (listeners, unix_listener) = match (server.listen().await, server.listen_unix().await) {
+        (Ok(listeners), Ok(unix_listener)) => (listeners, unix_listener),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("{e:#}");
+            process::exit(1);
+        }
+    };
 
-                server
-                    .handshake_master(&mut conn_to_master, sender_for_handshake)
-                    .await;
+    if !server.is_master().await {
+        let replicating_server = Arc::clone(&server);
+        let sender = Arc::clone(&sender);
+        tokio::spawn(async move { replicating_server.replicate(sender).await });
+    }
 
-                let server = Arc::clone(&server);
-                let sender = Arc::clone(&sender);
+    let expiring_server = Arc::clone(&server);
+    tokio::spawn(async move { expiring_server.expire_keys().await });
 
-                tokio::spawn(async move {
-                    server
-                        .handle_connection(conn_to_master, sender, false)
-                        .await;
-                });
-            }
-        }
-        Err(e) => panic!("Error connecting to master: {e}"),
-    }
+    let mut connections = JoinSet::new();
 
     loop {
-        let (stream, _) = listener.accept().await.unwrap();
+        let accepted = tokio::select! {
+            accepted = server::accept(&listeners) => accepted.map(|(stream, _)| Connection::new(stream)),
+            accepted = server::accept_unix(&unix_listener) => accepted.map(Connection::from_unix),
+            _ = tokio::signal::ctrl_c() => break,
+            _ = server.shutdown_requested() => break,
+            // reap finished connections so they don't pile up
+            Some(_) = connections.join_next() => continue,
+        };
+
+        let conn = match accepted {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("Could not accept a connection: {e}");
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
 
-        let conn = Connection::new(stream);
         let server = Arc::clone(&server);
-        let sender = Arc::clone(&sender);
+        connections.spawn(server.serve(conn, Arc::clone(&sender)));
+    }
 
-        tokio::spawn(async move {
-            server.handle_connection(conn, sender, true).await;
-        });
+    println!("Shutting down...");
+    server.request_shutdown();
+
+    let finished = async { while connections.join_next().await.is_some() {} };
+    if timeout(SHUTDOWN_TIMEOUT, finished).await.is_err() {
+        println!("Some connections didn't finish in time");
     }
+
+    server.remove_unix_socket().await;
 }
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+
+use crate::frame::Frame;
+use crate::glob;
+
+// how far a slow subscriber may fall behind before it starts missing messages
+const CHANNEL_CAPACITY: usize = 1024;
+
+// pub/sub channels by name, or patterns for PSUBSCRIBE,
+// every subscriber holds a receiver
+pub type Channels = HashMap<String, broadcast::Sender<Frame>>;
+
+// forwards everything published to the channel (or pattern) to a subscribed
+// connection, until the returned task is aborted by unsubscribe
+pub fn subscribe(
+    channels: &mut Channels,
+    channel: &str,
+    messages: UnboundedSender<Frame>,
+) -> JoinHandle<()> {
+    let mut receiver = channels
+        .entry(channel.to_string())
+        .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+        .subscribe();
+
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(message) => {
+                    if messages.send(message).is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    println!("subscriber missed {missed} messages");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+pub async fn unsubscribe(channels: &mut Channels, channel: &str, forwarder: JoinHandle<()>) {
+    // wait for the receiver to be dropped, so that the
+    // subscriber count PUBLISH replies with is right
+    forwarder.abort();
+    let _ = forwarder.await;
+
+    if channels
+        .get(channel)
+        .is_some_and(|sender| sender.receiver_count() == 0)
+    {
+        channels.remove(channel);
+    }
+}
+
+// sends the message to the subscribers of the channel and to those of
+// every matching pattern, returns how many subscribers it went out to
+pub fn publish(channels: &Channels, patterns: &Channels, channel: &str, message: Bytes) -> usize {
+    let mut receivers = 0;
+
+    if let Some(sender) = channels.get(channel) {
+        let frame = Frame::Push(vec![
+            Frame::BulkString("message".into()),
+            Frame::BulkString(channel.to_string().into()),
+            Frame::BulkString(message.clone()),
+        ]);
+
+        receivers += sender.send(frame).unwrap_or(0);
+    }
+
+    for (pattern, sender) in patterns {
+        if !glob::matches(pattern, channel) {
+            continue;
+        }
+
+        let frame = Frame::Push(vec![
+            Frame::BulkString("pmessage".into()),
+            Frame::BulkString(pattern.clone().into()),
+            Frame::BulkString(channel.to_string().into()),
+            Frame::BulkString(message.clone()),
+        ]);
+
+        receivers += sender.send(frame).unwrap_or(0);
+    }
+
+    receivers
+}
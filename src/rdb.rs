@@ -1,115 +1,871 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
+    io::{self, Cursor},
     path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll},
     time::{SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::Result;
+use bytes::Bytes;
 use tokio::{
     fs::File,
-    io::{AsyncBufReadExt, AsyncReadExt, BufReader},
-    time::Instant,
+    io::{AsyncRead, AsyncReadExt, BufReader, ReadBuf},
+    time::{Duration, Instant},
 };
 
-pub async fn parse_rdb_file(rdb_file: PathBuf) -> Result<HashMap<String, crate::DbItem>> {
-    if !rdb_file.exists() {
-        return Ok(HashMap::new());
+use crate::db::{Db, Value, NUM_DATABASES};
+use crate::sorted_set::SortedSet;
+
+const RDB_HEADER: &[u8] = b"REDIS0011";
+
+// the file is parsed as it's read, so only this much of it is in memory at once
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+// sizes read from the file are trusted only this far when allocating up front,
+// a corrupt one then fails at the end of the file instead of exhausting memory
+const MAX_PREALLOCATION: usize = 64 * 1024;
+
+// reflected form of the Jones polynomial redis uses for RDB checksums
+const CRC64_POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+// hashes everything read through it, for the checksum at the end of the file
+struct RdbReader {
+    inner: Box<dyn AsyncRead + Unpin + Send>,
+    crc: u64,
+}
+
+impl AsyncRead for RdbReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let already_filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        if poll.is_ready() {
+            self.crc = crc64_update(self.crc, &buf.filled()[already_filled..]);
+        }
+
+        poll
     }
+}
 
+pub async fn parse_rdb_file(rdb_file: PathBuf, verify_checksum: bool) -> Result<Vec<Db>> {
     let file = File::open(rdb_file).await?;
-    let mut reader = BufReader::new(file);
 
-    let mut rdb_contents = HashMap::new();
-    while let Ok(section_type) = reader.read_u8().await {
-        if section_type == 0xFF {
-            break;
+    parse_rdb(
+        BufReader::with_capacity(READ_BUFFER_SIZE, file),
+        verify_checksum,
+    )
+    .await
+}
+
+// what a replica gets from its master during the handshake
+pub async fn parse_rdb_bytes(rdb: Bytes) -> Result<Vec<Db>> {
+    parse_rdb(Cursor::new(rdb), true).await
+}
+
+async fn parse_rdb(
+    inner: impl AsyncRead + Unpin + Send + 'static,
+    verify_checksum: bool,
+) -> Result<Vec<Db>> {
+    let mut reader = RdbReader {
+        inner: Box::new(inner),
+        crc: 0,
+    };
+
+    let mut header = [0; RDB_HEADER.len()];
+    reader.read_exact(&mut header).await?;
+    if !header.starts_with(b"REDIS") {
+        return Err(anyhow::anyhow!("not an RDB file"));
+    }
+    let version: u32 = std::str::from_utf8(&header[5..])?.parse()?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+
+    // one per database index, the keys before any selector go into db 0
+    let mut dbs: Vec<Db> = (0..NUM_DATABASES).map(|_| Db::new()).collect();
+    let mut db_index = 0;
+    // set by an expiry opcode, applies to the key that follows it
+    let mut expiry: Option<u64> = None;
+
+    loop {
+        match reader.read_u8().await? {
+            0xFF => {
+                // files written before version 5 have no checksum
+                if version >= 5 {
+                    verify(&mut reader, verify_checksum).await?;
+                }
+                break;
+            }
+            // auxiliary fields like redis-ver, nothing we need
+            0xFA => {
+                decode_string(&mut reader).await?;
+                decode_string(&mut reader).await?;
+            }
+            // database selector, the keys that follow belong to that database
+            0xFE => {
+                db_index = parse_size_encoding(&mut reader).await?;
+                if db_index >= NUM_DATABASES {
+                    return Err(anyhow::anyhow!("database index {db_index} is out of range"));
+                }
+            }
+            // hash table size hints
+            0xFB => {
+                parse_size_encoding(&mut reader).await?;
+                parse_size_encoding(&mut reader).await?;
+            }
+            0xFD => expiry = Some(reader.read_u32_le().await? as u64 * 1000),
+            0xFC => expiry = Some(reader.read_u64_le().await?),
+            // LRU idle time and LFU frequency of the next key
+            0xF8 => {
+                parse_size_encoding(&mut reader).await?;
+            }
+            0xF9 => {
+                reader.read_u8().await?;
+            }
+            value_type => {
+                let key = decode_string(&mut reader).await?;
+                let value = decode_value(&mut reader, value_type).await?;
+
+                let expires_at = match expiry.take() {
+                    // expired while the server was down
+                    Some(expiry) if expiry < now => continue,
+                    Some(expiry) => Some(Instant::now() + Duration::from_millis(expiry - now)),
+                    None => None,
+                };
+
+                dbs[db_index].insert(key, crate::DbItem::new(value, expires_at));
+            }
         }
-        rdb_contents.extend(parse_database_section(&mut reader).await?);
     }
 
-    Ok(rdb_contents)
+    Ok(dbs)
 }
 
-pub async fn parse_database_section(
-    reader: &mut BufReader<File>,
-) -> Result<HashMap<String, crate::DbItem>> {
-    let mut buffer = Vec::with_capacity(4);
-    let _ = reader.read_until(0xFB, &mut buffer).await?;
-    let num_kvs = parse_size_encoding(reader).await?;
-    let num_kvs_with_expiry = parse_size_encoding(reader).await?;
+async fn verify(reader: &mut RdbReader, verify_checksum: bool) -> Result<()> {
+    let computed = reader.crc;
+    let stored = reader.read_u64_le().await?;
+
+    // a zero checksum means the file was written with checksums turned off
+    if verify_checksum && stored != 0 && stored != computed {
+        return Err(anyhow::anyhow!(
+            "wrong RDB checksum, expected {stored:016x} but got {computed:016x}"
+        ));
+    }
 
-    let mut result: HashMap<String, crate::DbItem> = HashMap::new();
+    Ok(())
+}
 
-    for _ in 0..num_kvs_with_expiry {
-        let expiry_type = reader.read_u8().await?;
-        let expiry = match expiry_type {
-            0xFC => {
-                let expiry = reader.read_u64_le().await?;
-                expiry
+async fn decode_value(reader: &mut RdbReader, value_type: u8) -> Result<Value> {
+    let value = match value_type {
+        0 => Value::String(decode_bytes(reader).await?.into()),
+        1 => {
+            let len = parse_size_encoding(reader).await?;
+            let mut list = VecDeque::with_capacity(len.min(MAX_PREALLOCATION));
+            for _ in 0..len {
+                list.push_back(decode_string(reader).await?);
             }
-            0xFD => {
-                let expiry = reader.read_u32_le().await?;
-                expiry as u64
+            Value::List(list)
+        }
+        2 => {
+            let len = parse_size_encoding(reader).await?;
+            let mut set = HashSet::with_capacity(len.min(MAX_PREALLOCATION));
+            for _ in 0..len {
+                set.insert(decode_string(reader).await?);
             }
-            _ => Err(anyhow::anyhow!("invalid expiry type"))?,
-        };
+            Value::Set(set)
+        }
+        // sorted sets, with the scores written as strings in version 1
+        3 | 5 => {
+            let len = parse_size_encoding(reader).await?;
+            let mut sorted_set = SortedSet::new();
+            for _ in 0..len {
+                let member = decode_string(reader).await?;
+                let score = match value_type {
+                    3 => decode_string_score(reader).await?,
+                    _ => f64::from_bits(reader.read_u64_le().await?),
+                };
+                sorted_set.insert(member, score);
+            }
+            Value::SortedSet(sorted_set)
+        }
+        4 => {
+            let len = parse_size_encoding(reader).await?;
+            let mut hash = HashMap::with_capacity(len.min(MAX_PREALLOCATION));
+            for _ in 0..len {
+                let field = decode_string(reader).await?;
+                hash.insert(field, decode_string(reader).await?);
+            }
+            Value::Hash(hash)
+        }
+        // the compact encodings store the whole value as a single string
+        10 => Value::List(ziplist_entries(&decode_bytes(reader).await?)?.into()),
+        11 => Value::Set(
+            intset_entries(&decode_bytes(reader).await?)?
+                .into_iter()
+                .collect(),
+        ),
+        12 => sorted_set_from(ziplist_entries(&decode_bytes(reader).await?)?)?,
+        13 => Value::Hash(pairs(ziplist_entries(&decode_bytes(reader).await?)?).collect()),
+        14 => {
+            let len = parse_size_encoding(reader).await?;
+            let mut list = VecDeque::new();
+            for _ in 0..len {
+                list.extend(ziplist_entries(&decode_bytes(reader).await?)?);
+            }
+            Value::List(list)
+        }
+        16 => Value::Hash(pairs(listpack_entries(&decode_bytes(reader).await?)?).collect()),
+        17 => sorted_set_from(listpack_entries(&decode_bytes(reader).await?)?)?,
+        18 => {
+            let len = parse_size_encoding(reader).await?;
+            let mut list = VecDeque::new();
+            for _ in 0..len {
+                let container = parse_size_encoding(reader).await?;
+                let node = decode_bytes(reader).await?;
 
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as u64;
+                // a plain node holds a single large element as is
+                match container {
+                    1 => list.push_back(String::from_utf8(node)?),
+                    _ => list.extend(listpack_entries(&node)?),
+                }
+            }
+            Value::List(list)
+        }
+        20 => Value::Set(
+            listpack_entries(&decode_bytes(reader).await?)?
+                .into_iter()
+                .collect(),
+        ),
+        _ => return Err(anyhow::anyhow!("unsupported value type {value_type}")),
+    };
+
+    Ok(value)
+}
+
+async fn decode_string(reader: &mut RdbReader) -> Result<String> {
+    Ok(String::from_utf8(decode_bytes(reader).await?)?)
+}
+
+// strings can also be stored as integers or LZF compressed
+async fn decode_bytes(reader: &mut RdbReader) -> Result<Vec<u8>> {
+    let encode_pattern = reader.read_u8().await?;
+
+    if encode_pattern >> 6 != 0b11 {
+        let length = parse_size(reader, encode_pattern).await?;
+        return read_bytes(reader, length).await;
+    }
+
+    let bytes = match encode_pattern & 0b0011_1111 {
+        0 => reader.read_i8().await?.to_string().into_bytes(),
+        1 => reader.read_i16_le().await?.to_string().into_bytes(),
+        2 => reader.read_i32_le().await?.to_string().into_bytes(),
+        3 => {
+            let compressed_length = parse_size_encoding(reader).await?;
+            let length = parse_size_encoding(reader).await?;
+            let compressed = read_bytes(reader, compressed_length).await?;
+            lzf_decompress(&compressed, length)?
+        }
+        _ => return Err(anyhow::anyhow!("invalid string encoding")),
+    };
+
+    Ok(bytes)
+}
+
+async fn read_bytes(reader: &mut RdbReader, length: usize) -> Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(length.min(MAX_PREALLOCATION));
+    let read = reader.take(length as u64).read_to_end(&mut buffer).await?;
+
+    if read < length {
+        return Err(anyhow::anyhow!("unexpected end of RDB file"));
+    }
+
+    Ok(buffer)
+}
+
+// version 1 sorted sets spell special scores with a length byte
+async fn decode_string_score(reader: &mut RdbReader) -> Result<f64> {
+    let score = match reader.read_u8().await? {
+        253 => f64::NAN,
+        254 => f64::INFINITY,
+        255 => f64::NEG_INFINITY,
+        length => {
+            let buffer = read_bytes(reader, length as usize).await?;
+            String::from_utf8(buffer)?.parse::<f64>()?
+        }
+    };
+
+    Ok(score)
+}
+
+async fn parse_size_encoding(reader: &mut RdbReader) -> Result<usize> {
+    let encode_pattern = reader.read_u8().await?;
+
+    parse_size(reader, encode_pattern).await
+}
 
-        let _value_type = reader.read_u8().await?;
-        let key = decode_string(reader).await?;
-        let value = decode_string(reader).await?;
+async fn parse_size(reader: &mut RdbReader, encode_pattern: u8) -> Result<usize> {
+    match (encode_pattern >> 6, encode_pattern) {
+        (0b00, _) => Ok((encode_pattern & 0b0011_1111) as usize),
+        (0b01, _) => {
+            let next = reader.read_u8().await?;
+            Ok(u16::from_be_bytes([encode_pattern & 0b0011_1111, next]) as usize)
+        }
+        (0b10, 0x80) => Ok(reader.read_u32().await? as usize),
+        (0b10, 0x81) => Ok(reader.read_u64().await? as usize),
+        _ => Err(anyhow::anyhow!("invalid encoding pattern")),
+    }
+}
 
-        if expiry < now {
+fn lzf_decompress(input: &[u8], length: usize) -> Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(length.min(MAX_PREALLOCATION));
+    let mut pos = 0;
+
+    while pos < input.len() {
+        let ctrl = input[pos] as usize;
+        pos += 1;
+
+        // a run of literal bytes
+        if ctrl < 32 {
+            output.extend_from_slice(take(input, &mut pos, ctrl + 1)?);
             continue;
         }
 
-        result.insert(
-            key,
-            crate::DbItem::new(value, Instant::now(), (expiry - now) as usize),
-        );
+        // a back reference into what was decompressed so far
+        let mut len = ctrl >> 5;
+        if len == 7 {
+            len += take(input, &mut pos, 1)?[0] as usize;
+        }
+        let offset = ((ctrl & 0x1F) << 8) + take(input, &mut pos, 1)?[0] as usize + 1;
+        let start = output
+            .len()
+            .checked_sub(offset)
+            .ok_or_else(|| anyhow::anyhow!("invalid LZF back reference"))?;
+
+        // the reference may overlap the bytes it produces
+        for i in 0..len + 2 {
+            output.push(output[start + i]);
+        }
+    }
+
+    if output.len() != length {
+        return Err(anyhow::anyhow!("LZF string has the wrong length"));
+    }
+
+    Ok(output)
+}
+
+fn ziplist_entries(ziplist: &[u8]) -> Result<Vec<String>> {
+    // skip zlbytes, zltail and zllen
+    let mut pos = 10;
+    let mut entries = vec![];
+
+    loop {
+        // the length of the previous entry, one byte or 0xFE and four more
+        match take(ziplist, &mut pos, 1)?[0] {
+            0xFF => break,
+            0xFE => {
+                take(ziplist, &mut pos, 4)?;
+            }
+            _ => {}
+        }
+
+        let encoding = take(ziplist, &mut pos, 1)?[0];
+        let entry = match encoding >> 6 {
+            0b00 => {
+                let len = (encoding & 0b0011_1111) as usize;
+                take(ziplist, &mut pos, len)?.to_vec()
+            }
+            0b01 => {
+                let next = take(ziplist, &mut pos, 1)?[0];
+                let len = u16::from_be_bytes([encoding & 0b0011_1111, next]) as usize;
+                take(ziplist, &mut pos, len)?.to_vec()
+            }
+            0b10 => {
+                let len = take(ziplist, &mut pos, 4)?;
+                let len = u32::from_be_bytes(len.try_into()?) as usize;
+                take(ziplist, &mut pos, len)?.to_vec()
+            }
+            _ => {
+                let integer = match encoding {
+                    0xC0 => int_le(take(ziplist, &mut pos, 2)?),
+                    0xD0 => int_le(take(ziplist, &mut pos, 4)?),
+                    0xE0 => int_le(take(ziplist, &mut pos, 8)?),
+                    0xF0 => int_le(take(ziplist, &mut pos, 3)?),
+                    0xFE => int_le(take(ziplist, &mut pos, 1)?),
+                    // the value is in the encoding itself, from 0 to 12
+                    0xF1..=0xFD => (encoding & 0x0F) as i64 - 1,
+                    _ => return Err(anyhow::anyhow!("invalid ziplist entry")),
+                };
+                integer.to_string().into_bytes()
+            }
+        };
+
+        entries.push(String::from_utf8(entry)?);
     }
 
-    for _ in 0..num_kvs - num_kvs_with_expiry {
-        let _value_type = reader.read_u8().await?;
-        let key = decode_string(reader).await?;
-        let value = decode_string(reader).await?;
-        result.insert(key, crate::DbItem::new(value, Instant::now(), 0));
+    Ok(entries)
+}
+
+fn listpack_entries(listpack: &[u8]) -> Result<Vec<String>> {
+    // skip the total size and the number of elements
+    let mut pos = 6;
+    let mut entries = vec![];
+
+    loop {
+        let start = pos;
+        let encoding = take(listpack, &mut pos, 1)?[0];
+
+        let entry = match encoding {
+            0xFF => break,
+            0x00..=0x7F => (encoding as i64).to_string().into_bytes(),
+            0x80..=0xBF => {
+                let len = (encoding & 0b0011_1111) as usize;
+                take(listpack, &mut pos, len)?.to_vec()
+            }
+            0xC0..=0xDF => {
+                let next = take(listpack, &mut pos, 1)?[0];
+                // a 13 bit signed integer
+                let integer = (u16::from_be_bytes([encoding & 0b0001_1111, next]) << 3) as i16 >> 3;
+                integer.to_string().into_bytes()
+            }
+            0xE0..=0xEF => {
+                let next = take(listpack, &mut pos, 1)?[0];
+                let len = u16::from_be_bytes([encoding & 0b0000_1111, next]) as usize;
+                take(listpack, &mut pos, len)?.to_vec()
+            }
+            0xF0 => {
+                let len = take(listpack, &mut pos, 4)?;
+                let len = u32::from_le_bytes(len.try_into()?) as usize;
+                take(listpack, &mut pos, len)?.to_vec()
+            }
+            0xF1..=0xF4 => {
+                let width = match encoding {
+                    0xF1 => 2,
+                    0xF2 => 3,
+                    0xF3 => 4,
+                    _ => 8,
+                };
+                int_le(take(listpack, &mut pos, width)?)
+                    .to_string()
+                    .into_bytes()
+            }
+            _ => return Err(anyhow::anyhow!("invalid listpack entry")),
+        };
+
+        // every entry ends with its own length, for walking backwards
+        let backlen = match pos - start {
+            0..=127 => 1,
+            128..=16_383 => 2,
+            16_384..=2_097_151 => 3,
+            2_097_152..=268_435_455 => 4,
+            _ => 5,
+        };
+        take(listpack, &mut pos, backlen)?;
+
+        entries.push(String::from_utf8(entry)?);
     }
 
-    Ok(result)
+    Ok(entries)
 }
 
-async fn decode_string(reader: &mut BufReader<File>) -> Result<String> {
-    let length = parse_size_encoding(reader).await?;
-    let mut buffer = vec![0; length];
-    reader.read_exact(&mut buffer).await?;
-    Ok(String::from_utf8(buffer)?)
+fn intset_entries(intset: &[u8]) -> Result<Vec<String>> {
+    let mut pos = 0;
+    let width = u32::from_le_bytes(take(intset, &mut pos, 4)?.try_into()?) as usize;
+    let len = u32::from_le_bytes(take(intset, &mut pos, 4)?.try_into()?) as usize;
+
+    (0..len)
+        .map(|_| Ok(int_le(take(intset, &mut pos, width)?).to_string()))
+        .collect()
 }
 
-async fn parse_size_encoding(reader: &mut BufReader<File>) -> Result<usize> {
-    let mut buffer = Vec::with_capacity(4);
-    let encode_pattern = reader.read_u8().await?;
+fn sorted_set_from(entries: Vec<String>) -> Result<Value> {
+    let mut sorted_set = SortedSet::new();
+
+    for (member, score) in pairs(entries) {
+        sorted_set.insert(member, score.parse::<f64>()?);
+    }
+
+    Ok(Value::SortedSet(sorted_set))
+}
+
+fn pairs(entries: Vec<String>) -> impl Iterator<Item = (String, String)> {
+    let mut entries = entries.into_iter();
+
+    std::iter::from_fn(move || Some((entries.next()?, entries.next()?)))
+}
+
+// a signed little endian integer of up to 8 bytes
+fn int_le(bytes: &[u8]) -> i64 {
+    let mut buffer = [0; 8];
+    buffer[..bytes.len()].copy_from_slice(bytes);
+
+    // shift the sign bit into place and back to extend it
+    let shift = 64 - 8 * bytes.len() as u32;
+    (i64::from_le_bytes(buffer) << shift) >> shift
+}
+
+fn take<'a>(bytes: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8]> {
+    let taken = bytes
+        .get(*pos..*pos + len)
+        .ok_or_else(|| anyhow::anyhow!("unexpected end of encoded value"))?;
+    *pos += len;
+
+    Ok(taken)
+}
+
+pub async fn write_rdb_file(rdb_file: PathBuf, dbs: &[&Db]) -> Result<()> {
+    tokio::fs::write(rdb_file, encode_rdb(dbs)).await?;
+
+    Ok(())
+}
+
+// the databases by index, an empty one is left out of the file
+pub fn encode_rdb(dbs: &[&Db]) -> Vec<u8> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+
+    let mut buffer = RDB_HEADER.to_vec();
+
+    for (db_index, db) in dbs.iter().enumerate() {
+        let items: Vec<_> = db.iter().filter(|(_, item)| !item.is_expired()).collect();
+        if items.is_empty() {
+            continue;
+        }
+
+        let num_kvs_with_expiry = items
+            .iter()
+            .filter(|(_, item)| item.expires_at.is_some())
+            .count();
+
+        // database selector and resizedb info
+        buffer.push(0xFE);
+        encode_size(&mut buffer, db_index);
+        buffer.push(0xFB);
+        encode_size(&mut buffer, items.len());
+        encode_size(&mut buffer, num_kvs_with_expiry);
+
+        for (key, item) in items {
+            if let Some(expires_at) = item.expires_at {
+                let remaining = expires_at
+                    .saturating_duration_since(Instant::now())
+                    .as_millis() as u64;
+                buffer.push(0xFC);
+                buffer.extend((now + remaining).to_le_bytes());
+            }
+
+            encode_value(&mut buffer, key, &item.value);
+        }
+    }
+
+    buffer.push(0xFF);
+    let checksum = crc64(&buffer);
+    buffer.extend(checksum.to_le_bytes());
 
-    match encode_pattern >> 6 {
-        0b00 => {
-            buffer.push(encode_pattern & 0b0011_1111);
-            Ok(buffer[0] as usize)
+    buffer
+}
+
+// the value type and the key, followed by the value in its plain encoding
+fn encode_value(buffer: &mut Vec<u8>, key: &str, value: &Value) {
+    let value_type = match value {
+        Value::String(_) => 0,
+        Value::List(_) => 1,
+        Value::Set(_) => 2,
+        Value::Hash(_) => 4,
+        Value::SortedSet(_) => 5,
+    };
+    buffer.push(value_type);
+    encode_string(buffer, key.as_bytes());
+
+    match value {
+        Value::String(value) => encode_string(buffer, value),
+        Value::List(list) => {
+            encode_size(buffer, list.len());
+            for element in list {
+                encode_string(buffer, element.as_bytes());
+            }
         }
-        0b01 => {
-            buffer.push(encode_pattern & 0b0011_1111);
-            buffer.push(reader.read_u8().await?);
-            Ok(u16::from_be_bytes([buffer[0], buffer[1]]) as usize)
+        Value::Set(set) => {
+            encode_size(buffer, set.len());
+            for member in set {
+                encode_string(buffer, member.as_bytes());
+            }
         }
-        0b10 => {
-            for _ in 0..3 {
-                buffer.push(reader.read_u8().await?);
+        Value::Hash(hash) => {
+            encode_size(buffer, hash.len());
+            for (field, value) in hash {
+                encode_string(buffer, field.as_bytes());
+                encode_string(buffer, value.as_bytes());
             }
-            Ok(u32::from_be_bytes([buffer[0], buffer[1], buffer[2], 0]) as usize)
         }
-        _ => Err(anyhow::anyhow!("invalid encoding pattern")),
+        Value::SortedSet(sorted_set) => {
+            encode_size(buffer, sorted_set.len());
+            for (member, score) in sorted_set.iter() {
+                encode_string(buffer, member.as_bytes());
+                buffer.extend(score.to_le_bytes());
+            }
+        }
+    }
+}
+
+fn encode_string(buffer: &mut Vec<u8>, string: &[u8]) {
+    encode_size(buffer, string.len());
+    buffer.extend(string);
+}
+
+fn encode_size(buffer: &mut Vec<u8>, size: usize) {
+    match size {
+        0..=0x3F => buffer.push(size as u8),
+        0x40..=0x3FFF => buffer.extend([0b0100_0000 | (size >> 8) as u8, size as u8]),
+        _ => {
+            buffer.push(0b1000_0000);
+            buffer.extend((size as u32).to_be_bytes());
+        }
+    }
+}
+
+pub fn crc64(bytes: &[u8]) -> u64 {
+    crc64_update(0, bytes)
+}
+
+// continues a checksum over the bytes that follow the ones it covers
+fn crc64_update(mut crc: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        crc ^= *byte as u64;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ CRC64_POLY,
+                _ => crc >> 1,
+            };
+        }
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DbItem;
+    use std::path::Path;
+
+    fn string(value: &[u8]) -> DbItem {
+        DbItem::new(Value::String(Bytes::copy_from_slice(value)), None)
+    }
+
+    #[test]
+    fn crc64_matches_redis() {
+        // the check value from redis' own crc64 test
+        assert_eq!(crc64(b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+        assert_eq!(crc64(b""), 0);
+    }
+
+    #[test]
+    fn crc64_can_be_continued() {
+        let crc = crc64_update(crc64(b"1234"), b"56789");
+
+        assert_eq!(crc, crc64(b"123456789"));
+    }
+
+    #[tokio::test]
+    async fn every_type_survives_an_encode_and_parse() {
+        let mut db = Db::new();
+        db.insert("binary".to_string(), string(b"\x00\xff\r\n"));
+        db.insert("long".to_string(), string(&[b'x'; 20_000]));
+        db.insert(
+            "list".to_string(),
+            DbItem::new(
+                Value::List(VecDeque::from(["a".to_string(), "b".to_string()])),
+                None,
+            ),
+        );
+        db.insert(
+            "set".to_string(),
+            DbItem::new(Value::Set(HashSet::from(["m".to_string()])), None),
+        );
+        db.insert(
+            "hash".to_string(),
+            DbItem::new(
+                Value::Hash(HashMap::from([("f".to_string(), "v".to_string())])),
+                None,
+            ),
+        );
+        let mut sorted_set = SortedSet::new();
+        sorted_set.insert("one".to_string(), 1.5);
+        sorted_set.insert("two".to_string(), -2.0);
+        db.insert(
+            "zset".to_string(),
+            DbItem::new(Value::SortedSet(sorted_set), None),
+        );
+
+        let dbs = parse_rdb_bytes(encode_rdb(&[&db]).into()).await.unwrap();
+
+        assert_eq!(dbs.len(), NUM_DATABASES);
+        assert_eq!(dbs[0].len(), db.len());
+        for (key, item) in &db {
+            assert_eq!(dbs[0][key].value, item.value, "{key}");
+        }
+    }
+
+    #[tokio::test]
+    async fn keys_stay_in_their_database() {
+        let empty = Db::new();
+        let mut first = Db::new();
+        first.insert("a".to_string(), string(b"0"));
+        let mut third = Db::new();
+        third.insert("b".to_string(), string(b"2"));
+
+        let dbs = parse_rdb_bytes(encode_rdb(&[&first, &empty, &third]).into())
+            .await
+            .unwrap();
+
+        assert_eq!(dbs[0].keys().collect::<Vec<_>>(), vec!["a"]);
+        assert!(dbs[1].is_empty());
+        assert_eq!(dbs[2].keys().collect::<Vec<_>>(), vec!["b"]);
+    }
+
+    #[tokio::test]
+    async fn ttls_are_kept_and_expired_keys_left_out() {
+        let mut db = Db::new();
+        db.insert(
+            "later".to_string(),
+            DbItem::new(
+                Value::String("v".into()),
+                Some(Instant::now() + Duration::from_secs(100)),
+            ),
+        );
+        db.insert(
+            "gone".to_string(),
+            DbItem::new(Value::String("v".into()), Some(Instant::now())),
+        );
+
+        let dbs = parse_rdb_bytes(encode_rdb(&[&db]).into()).await.unwrap();
+
+        assert!(!dbs[0].contains_key("gone"));
+        let remaining = dbs[0]["later"]
+            .expires_at
+            .unwrap()
+            .saturating_duration_since(Instant::now());
+        assert!(remaining > Duration::from_secs(98) && remaining <= Duration::from_secs(100));
+    }
+
+    #[tokio::test]
+    async fn a_corrupt_file_fails_the_checksum() {
+        let mut db = Db::new();
+        db.insert("key".to_string(), string(b"value"));
+        let mut rdb = encode_rdb(&[&db]);
+        let last_value_byte = rdb.len() - 10;
+        rdb[last_value_byte] ^= 1;
+
+        assert!(parse_rdb_bytes(rdb.into()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn a_database_selector_out_of_range_is_an_error() {
+        let mut rdb = RDB_HEADER.to_vec();
+        rdb.push(0xFE);
+        encode_size(&mut rdb, NUM_DATABASES);
+        rdb.push(0xFF);
+        rdb.extend([0; 8]);
+
+        assert!(parse_rdb_bytes(rdb.into()).await.is_err());
+    }
+
+    #[test]
+    fn lzf_back_references() {
+        // a literal "ab" followed by a back reference copying it twice more
+        let compressed = [0x01, b'a', b'b', 0x40, 0x01];
+
+        assert_eq!(lzf_decompress(&compressed, 6).unwrap(), b"ababab");
+    }
+
+    fn fixture() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/dump.rdb")
+    }
+
+    fn unix_millis() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+
+    // laid out the way Redis 7.2 writes it: aux fields, TTLs in milliseconds
+    // and seconds, an integer and an LZF string, the listpack encodings and
+    // a second database. "future" and "seconds" expire at 2100-01-01
+    #[tokio::test]
+    async fn loads_a_file_written_by_redis() {
+        let dbs = parse_rdb_file(fixture(), true).await.unwrap();
+
+        let string = |key: &str| match &dbs[0][key].value {
+            Value::String(value) => value.clone(),
+            value => panic!("{key} holds {value:?}"),
+        };
+        assert_eq!(string("future"), "value");
+        assert_eq!(string("number"), "12345");
+        assert_eq!(string("lzf"), "ababab");
+        assert!(!dbs[0].contains_key("past"));
+
+        assert_eq!(
+            dbs[0]["hash"].value,
+            Value::Hash(HashMap::from([
+                ("field".to_string(), "value".to_string()),
+                ("count".to_string(), "7".to_string()),
+            ]))
+        );
+        assert_eq!(
+            dbs[0]["list"].value,
+            Value::List(VecDeque::from(["x", "y", "3"].map(String::from)))
+        );
+        assert_eq!(
+            dbs[0]["set"].value,
+            Value::Set(HashSet::from(["m", "n"].map(String::from)))
+        );
+        let Value::SortedSet(sorted_set) = &dbs[0]["zset"].value else {
+            panic!("zset isn't a sorted set");
+        };
+        assert_eq!(sorted_set.score("two"), Some(2.0));
+        assert_eq!(dbs[1]["other"].value, Value::String("db".into()));
+    }
+
+    #[tokio::test]
+    async fn stored_ttls_load_with_the_time_left() {
+        let dbs = parse_rdb_file(fixture(), true).await.unwrap();
+        let left = Duration::from_millis(4_102_444_800_000 - unix_millis());
+
+        for key in ["future", "seconds"] {
+            let remaining = dbs[0][key]
+                .expires_at
+                .unwrap()
+                .saturating_duration_since(Instant::now());
+
+            assert!(left.abs_diff(remaining) < Duration::from_secs(5), "{key}");
+        }
+        assert_eq!(dbs[0]["number"].expires_at, None);
+    }
+
+    #[tokio::test]
+    async fn a_large_file_loads_completely() {
+        let mut db = Db::new();
+        for i in 0..20_000 {
+            db.insert(format!("key:{i}"), string(&[b'v'; 100]));
+        }
+        let path = std::env::temp_dir().join(format!("large-{}.rdb", std::process::id()));
+        write_rdb_file(path.clone(), &[&db]).await.unwrap();
+
+        let dbs = parse_rdb_file(path.clone(), true).await.unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(dbs[0].len(), 20_000);
+        assert_eq!(dbs[0]["key:19999"].value, db["key:19999"].value);
     }
 }
@@ -1,6 +1,16 @@
 use core::fmt;
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use tokio::sync::Mutex;
 
 use crate::config::Config;
+use crate::frame::Frame;
+
+// how many writes the stream to the replicas buffers, a replica that falls
+// further behind is disconnected and resyncs from the backlog when it's back
+pub const STREAM_CAPACITY: usize = 1024;
 
 #[derive(Clone, PartialEq, Eq)]
 pub enum ReplRole {
@@ -17,28 +27,309 @@ impl fmt::Display for ReplRole {
     }
 }
 
+pub struct ReplicaHandle {
+    pub id: usize,
+    pub ip: String,
+    // the port the replica announced with REPLCONF listening-port
+    pub listening_port: Option<usize>,
+    // the last offset the replica acknowledged with REPLCONF ACK
+    pub offset: usize,
+}
+
+// how a replica that sent PSYNC gets in step with the master
+pub enum PsyncReply {
+    // a snapshot of the dataset, after which the stream starts at the offset
+    Full { rdb: Vec<u8>, offset: usize },
+    // the writes it missed, from the backlog
+    Partial { missed: Vec<Frame> },
+}
+
+// shared between the server and the handlers that report or change it
+pub type SharedReplicationConfig = Arc<Mutex<ReplicationConfig>>;
+
 pub struct ReplicationConfig {
     pub role: ReplRole,
+    pub run_id: String,
     pub master_replid: Option<String>,
     pub master_repl_offset: Option<usize>,
     pub slave_repl_offset: Option<usize>,
+    // where a replica's master lives, from --replicaof
+    pub master_host: Option<String>,
+    pub master_port: Option<usize>,
+    // whether a replica is connected to its master and in sync with it
+    pub master_link_up: bool,
+    pub replicas: Vec<ReplicaHandle>,
+    // the db the replication stream currently has selected, None
+    // after a snapshot, when the next write has to SELECT first
+    pub propagated_db: Option<usize>,
+    // the most recent writes along with the offset each starts at,
+    // so that a replica which reconnects can get just what it missed
+    backlog: VecDeque<(usize, Frame)>,
+    // how many bytes of writes the backlog holds, and may hold
+    backlog_len: usize,
+    backlog_size: usize,
+    next_replica_id: usize,
 }
 
 impl ReplicationConfig {
     pub fn from_config(config: &Config) -> Self {
+        let (master_host, master_port) = match &config.replicaof {
+            Some(replicaof) => match replicaof.rsplit_once(':') {
+                Some((host, port)) => (Some(host.to_string()), port.parse().ok()),
+                None => (Some(replicaof.clone()), None),
+            },
+            None => (None, None),
+        };
+
+        let run_id = random_id();
+
         match config.replicaof.is_some() {
             true => ReplicationConfig {
                 role: ReplRole::Slave,
+                run_id,
                 master_replid: None,
                 master_repl_offset: None,
                 slave_repl_offset: Some(0),
+                master_host,
+                master_port,
+                master_link_up: false,
+                replicas: vec![],
+                propagated_db: Some(0),
+                backlog: VecDeque::new(),
+                backlog_len: 0,
+                backlog_size: config.repl_backlog_size,
+                next_replica_id: 0,
             },
             false => ReplicationConfig {
                 role: ReplRole::Master,
-                master_replid: Some("8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string()),
+                // separate from the run id, like in Redis where a
+                // failover changes the replid but not the run id
+                master_replid: Some(random_id()),
+                run_id,
                 master_repl_offset: Some(0),
                 slave_repl_offset: None,
+                master_host,
+                master_port,
+                master_link_up: false,
+                replicas: vec![],
+                propagated_db: Some(0),
+                backlog: VecDeque::new(),
+                backlog_len: 0,
+                backlog_size: config.repl_backlog_size,
+                next_replica_id: 0,
             },
         }
     }
+
+    // "FULLRESYNC <replid> <offset>", the stream continues from that offset
+    pub fn apply_fullresync(&mut self, fullresync: &str) {
+        let mut parts = fullresync.split_whitespace().skip(1);
+
+        if let Some(master_replid) = parts.next() {
+            self.master_replid = Some(master_replid.to_string());
+        }
+        if let Some(offset) = parts.next().and_then(|offset| offset.parse().ok()) {
+            self.slave_repl_offset = Some(offset);
+        }
+    }
+
+    // "CONTINUE <replid>", the stream goes on from where it was
+    pub fn apply_continue(&mut self, reply: &str) {
+        if let Some(master_replid) = reply.split_whitespace().nth(1) {
+            self.master_replid = Some(master_replid.to_string());
+        }
+    }
+
+    // keeps a propagated write in the backlog and moves the offset past it
+    pub fn record_propagated(&mut self, frame: Frame, frame_len: usize) {
+        let Some(offset) = self.master_repl_offset else {
+            return;
+        };
+
+        self.backlog.push_back((offset, frame));
+        self.backlog_len += frame_len;
+        self.master_repl_offset = Some(offset + frame_len);
+
+        // the oldest writes make room, each one ends where the next starts
+        while self.backlog_len > self.backlog_size {
+            let Some((start, _)) = self.backlog.pop_front() else {
+                break;
+            };
+            let end = self
+                .backlog
+                .front()
+                .map_or(offset + frame_len, |(next, _)| *next);
+            self.backlog_len -= end - start;
+        }
+    }
+
+    // the writes after the offset, None when the replica was replicating
+    // from someone else or has fallen behind more than the backlog holds
+    pub fn backlog_since(&self, replid: &str, offset: usize) -> Option<Vec<Frame>> {
+        if self.master_replid.as_deref() != Some(replid) {
+            return None;
+        }
+        if self.master_repl_offset == Some(offset) {
+            return Some(vec![]);
+        }
+
+        let start = self
+            .backlog
+            .iter()
+            .position(|(start, _)| *start == offset)?;
+        Some(
+            self.backlog
+                .iter()
+                .skip(start)
+                .map(|(_, frame)| frame.clone())
+                .collect(),
+        )
+    }
+
+    pub fn register_replica(&mut self, ip: String, listening_port: Option<usize>) -> usize {
+        let replica_id = self.next_replica_id;
+        self.next_replica_id += 1;
+
+        self.replicas.push(ReplicaHandle {
+            id: replica_id,
+            ip,
+            listening_port,
+            offset: 0,
+        });
+
+        replica_id
+    }
+
+    pub fn unregister_replica(&mut self, replica_id: usize) {
+        self.replicas.retain(|replica| replica.id != replica_id);
+    }
+
+    pub fn update_replica_offset(&mut self, replica_id: usize, offset: usize) {
+        if let Some(replica) = self.replicas.iter_mut().find(|r| r.id == replica_id) {
+            replica.offset = offset;
+        }
+    }
+
+    pub fn acked_replicas(&self, offset: usize) -> usize {
+        self.replicas
+            .iter()
+            .filter(|replica| replica.offset >= offset)
+            .count()
+    }
+}
+
+// 40 hex characters like the ids Redis uses, different for every run.
+// std has no RNG, but every RandomState is seeded with fresh random keys
+fn random_id() -> String {
+    (0..3)
+        .map(|_| format!("{:016x}", RandomState::new().build_hasher().finish()))
+        .collect::<String>()[..40]
+        .to_string()
+}
+
+// what a replica reports to its master, both on GETACK and after applying writes
+pub fn replconf_ack(offset: usize) -> Frame {
+    Frame::Array(vec![
+        Frame::BulkString("REPLCONF".into()),
+        Frame::BulkString("ACK".into()),
+        Frame::BulkString(offset.to_string().into()),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::ServiceArguments;
+    use clap::Parser;
+
+    // how long write() serializes to, "*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\nv\r\n"
+    const WRITE_LEN: usize = 27;
+
+    fn master(repl_backlog_size: usize) -> ReplicationConfig {
+        let args = ServiceArguments::parse_from([
+            "redis",
+            "--repl-backlog-size",
+            &repl_backlog_size.to_string(),
+        ]);
+
+        ReplicationConfig::from_config(&Config::from_args(args).unwrap())
+    }
+
+    fn write(key: &str) -> Frame {
+        Frame::Array(vec![
+            Frame::BulkString("SET".into()),
+            Frame::BulkString(key.to_string().into()),
+            Frame::BulkString("v".into()),
+        ])
+    }
+
+    #[test]
+    fn replconf_ack_bytes() {
+        assert_eq!(
+            replconf_ack(154).serialize(),
+            b"*3\r\n$8\r\nREPLCONF\r\n$3\r\nACK\r\n$3\r\n154\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn ids_are_random() {
+        let (first, second) = (master(1), master(1));
+
+        assert_eq!(first.run_id.len(), 40);
+        assert_ne!(first.run_id, second.run_id);
+        assert_ne!(first.master_replid, second.master_replid);
+        assert_ne!(first.master_replid.as_ref(), Some(&first.run_id));
+    }
+
+    #[test]
+    fn propagated_writes_move_the_offset() {
+        let mut replication = master(10);
+
+        for key in ["a", "b"] {
+            let frame = write(key);
+            let len = frame.serialize().len();
+            replication.record_propagated(frame, len);
+        }
+
+        assert_eq!(replication.master_repl_offset, Some(2 * WRITE_LEN));
+    }
+
+    #[test]
+    fn backlog_since_returns_what_was_missed() {
+        // room for two of the writes, not three
+        let mut replication = master(3 * WRITE_LEN - 1);
+        let replid = replication.master_replid.clone().unwrap();
+
+        for key in ["a", "b", "c"] {
+            let frame = write(key);
+            let len = frame.serialize().len();
+            replication.record_propagated(frame, len);
+        }
+
+        assert_eq!(
+            replication.backlog_since(&replid, WRITE_LEN),
+            Some(vec![write("b"), write("c")])
+        );
+        assert_eq!(
+            replication.backlog_since(&replid, 3 * WRITE_LEN),
+            Some(vec![])
+        );
+        // "a" has been pushed out of the backlog
+        assert_eq!(replication.backlog_since(&replid, 0), None);
+        assert_eq!(replication.backlog_since("someone else", WRITE_LEN), None);
+    }
+
+    #[test]
+    fn acked_replicas_count_those_caught_up() {
+        let mut replication = master(1);
+        let first = replication.register_replica("127.0.0.1".to_string(), Some(6380));
+        let second = replication.register_replica("127.0.0.1".to_string(), Some(6381));
+
+        replication.update_replica_offset(first, 100);
+        replication.update_replica_offset(second, 50);
+        assert_eq!(replication.acked_replicas(60), 1);
+
+        replication.unregister_replica(first);
+        assert_eq!(replication.acked_replicas(0), 1);
+    }
 }
@@ -1,57 +1,325 @@
-use std::{collections::HashMap, path::Path, sync::Arc};
+use std::{
+    future::{pending, poll_fn},
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::Poll,
+    time::{Duration, SystemTime},
+};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use bytes::Bytes;
 use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::{broadcast::Sender, Mutex},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    sync::{
+        broadcast::{Receiver, Sender},
+        mpsc, watch, Mutex, MutexGuard, RwLock,
+    },
 };
 
 use crate::{
-    config::Config,
-    connection::Connection,
-    db::{Db, DbItem},
-    frame::Frame,
+    clients::{ClientInfo, Clients},
+    commands,
+    config::{Config, MaxmemoryPolicy},
+    connection::{Connection, ConnectionKind},
+    db::{
+        db_size, evict_expired, keys_size, lock_all, next_version, watch_version, BlockedClients,
+        Db, ExpiryRing, ENTRY_OVERHEAD,
+    },
+    frame::{Frame, ProtocolError},
     handlers::{
-        extract_command, handle_config, handle_echo, handle_get, handle_info, handle_keys,
-        handle_ping, handle_psync, handle_replconf, handle_set,
+        extract_command, handle_append, handle_auth, handle_bgsave, handle_blpop, handle_brpop,
+        handle_client, handle_command, handle_config, handle_copy, handle_dbsize, handle_debug,
+        handle_decr, handle_decrby, handle_del, handle_discard, handle_echo, handle_exists,
+        handle_expire, handle_expireat, handle_flushall, handle_flushdb, handle_get, handle_getdel,
+        handle_getset, handle_hdel, handle_hello, handle_hexists, handle_hget, handle_hgetall,
+        handle_hincrby, handle_hkeys, handle_hlen, handle_hscan, handle_hset, handle_hvals,
+        handle_incr, handle_incrby, handle_info, handle_keys, handle_lindex, handle_llen,
+        handle_lpop, handle_lpush, handle_lrange, handle_mget, handle_mset, handle_multi,
+        handle_object, handle_persist, handle_pexpire, handle_pexpireat, handle_ping,
+        handle_psubscribe, handle_psync, handle_pttl, handle_publish, handle_punsubscribe,
+        handle_quit, handle_replconf, handle_reset, handle_rpop, handle_rpush, handle_sadd,
+        handle_save, handle_scan, handle_scard, handle_sdiff, handle_select, handle_set,
+        handle_setex, handle_setnx, handle_shutdown, handle_sinter, handle_sismember,
+        handle_smembers, handle_srem, handle_sscan, handle_strlen, handle_subscribe, handle_sunion,
+        handle_ttl, handle_type, handle_unsubscribe, handle_unwatch, handle_wait, handle_watch,
+        handle_zadd, handle_zrange, handle_zrangebyscore, handle_zrank, handle_zscore,
+        parse_copy_options, propagate, psync_resume_point, unpack_bulk_str, wrong_arity,
     },
+    pubsub::Channels,
     rdb,
-    replication::{ReplRole, ReplicationConfig},
+    replication::{replconf_ack, PsyncReply, ReplRole, ReplicationConfig, SharedReplicationConfig},
 };
 
+// accepts a connection from whichever of the listeners gets one first
+pub async fn accept(listeners: &[TcpListener]) -> std::io::Result<(TcpStream, SocketAddr)> {
+    poll_fn(|cx| {
+        for listener in listeners {
+            if let Poll::Ready(accepted) = listener.poll_accept(cx) {
+                return Poll::Ready(accepted);
+            }
+        }
+
+        Poll::Pending
+    })
+    .await
+}
+
+// like `accept` for the optional Unix socket listener, never resolving without one
+pub async fn accept_unix(listener: &Option<UnixListener>) -> std::io::Result<UnixStream> {
+    match listener {
+        Some(listener) => listener.accept().await.map(|(stream, _)| stream),
+        None => pending().await,
+    }
+}
+
+// how many keys per database one background expiry pass looks at,
+// which keeps the time spent holding the lock short
+const ACTIVE_EXPIRE_SAMPLE: usize = 20;
+
+// how long a replica waits before reconnecting to its master, at first and at most
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+const MASTER_CLOSED: &str = "the master closed the connection";
+
+// all a RESP2 client may send while it's subscribed to something
+const SUBSCRIBE_MODE_COMMANDS: [&str; 7] = [
+    "SUBSCRIBE",
+    "UNSUBSCRIBE",
+    "PSUBSCRIBE",
+    "PUNSUBSCRIBE",
+    "PING",
+    "QUIT",
+    "RESET",
+];
+
 pub struct RedisServer {
-    pub replication: Arc<Mutex<ReplicationConfig>>,
-    config: Config,
-    db: Arc<Mutex<HashMap<String, DbItem>>>,
+    pub replication: SharedReplicationConfig,
+    config: Arc<Mutex<Config>>,
+    dbs: Vec<Arc<Mutex<Db>>>,
+    rdb_last_save: Arc<Mutex<SystemTime>>,
+    blocked: Arc<Mutex<BlockedClients>>,
+    channels: Arc<Mutex<Channels>>,
+    patterns: Arc<Mutex<Channels>>,
+    // the clients connected to us, not counting the master link
+    clients: Arc<Mutex<Clients>>,
+    // how many of those count against maxclients
+    connected: AtomicUsize,
+    // per database, the version given out when a key was last deleted from it
+    deleted: Vec<AtomicU64>,
+    // per database, the keys for the background expiry to look at
+    expiring: Vec<Mutex<ExpiryRing>>,
+    // per database, roughly how much memory its keys take while maxmemory
+    // is on; a write holds it from before it runs until it's measured
+    used_memory: Vec<Arc<Mutex<usize>>>,
+    // held for reading by every command and for writing by EXEC
+    transaction_lock: RwLock<()>,
+    // flips to true once, on SHUTDOWN or Ctrl-C
+    shutdown: watch::Sender<bool>,
+}
+
+struct ClientSlot<'a>(&'a AtomicUsize);
+
+impl Drop for ClientSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// takes a client out of the registry however its connection ends
+struct Registered {
+    clients: Arc<Mutex<Clients>>,
+    id: usize,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        if let Ok(mut clients) = self.clients.try_lock() {
+            clients.remove(&self.id);
+            return;
+        }
+
+        // there's no waiting for the lock in a drop, so it's left for later
+        let (clients, id) = (Arc::clone(&self.clients), self.id);
+        tokio::spawn(async move { clients.lock().await.remove(&id) });
+    }
 }
 
 impl RedisServer {
-    pub fn new(config: Config, db: Arc<Mutex<Db>>) -> Self {
+    pub fn new(config: Config, dbs: Vec<Arc<Mutex<Db>>>) -> Self {
+        let deleted = dbs.iter().map(|_| AtomicU64::new(0)).collect();
+        let expiring = dbs.iter().map(|_| Mutex::default()).collect();
+        let used_memory = dbs.iter().map(|_| Arc::default()).collect();
+
         RedisServer {
             replication: Arc::new(Mutex::new(ReplicationConfig::from_config(&config))),
-            config,
-            db,
+            config: Arc::new(Mutex::new(config)),
+            dbs,
+            rdb_last_save: Arc::new(Mutex::new(SystemTime::now())),
+            blocked: Arc::new(Mutex::new(BlockedClients::new())),
+            channels: Arc::new(Mutex::new(Channels::new())),
+            patterns: Arc::new(Mutex::new(Channels::new())),
+            clients: Arc::new(Mutex::new(Clients::new())),
+            connected: AtomicUsize::new(0),
+            deleted,
+            expiring,
+            used_memory,
+            transaction_lock: RwLock::new(()),
+            shutdown: watch::channel(false).0,
+        }
+    }
+
+    pub fn request_shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    pub async fn shutdown_requested(&self) {
+        let _ = self
+            .shutdown
+            .subscribe()
+            .wait_for(|shutdown| *shutdown)
+            .await;
+    }
+
+    // the lazy expiry on access never gets to keys nobody reads,
+    // so those are swept a few keys at a time in the background
+    pub async fn expire_keys(&self) {
+        loop {
+            let hz = self.config.lock().await.hz;
+            tokio::time::sleep(Duration::from_millis(1000 / hz as u64)).await;
+
+            for (db_index, db) in self.dbs.iter().enumerate() {
+                let mut used = self.used_memory[db_index].lock().await;
+                let mut db = db.lock().await;
+                let mut ring = self.expiring[db_index].lock().await;
+
+                if evict_expired(&mut db, &mut ring, ACTIVE_EXPIRE_SAMPLE, &mut used) > 0 {
+                    self.deleted[db_index].store(next_version(), Ordering::Relaxed);
+                }
+            }
         }
     }
 
+    pub async fn maxclients(&self) -> usize {
+        self.config.lock().await.maxclients
+    }
+
     pub async fn is_master(&self) -> bool {
         self.replication.lock().await.role == ReplRole::Master
     }
 
-    pub async fn listen(&self) -> TcpListener {
-        let addr = format!("127.0.0.1:{}", self.config.port);
-        let listener = TcpListener::bind(addr.clone()).await.unwrap();
+    pub async fn listen(&self) -> Result<Vec<TcpListener>> {
+        let (bind, port) = {
+            let config = self.config.lock().await;
+            (config.bind.clone(), config.port)
+        };
+
+        let mut listeners = vec![];
+        for host in bind.split_whitespace() {
+            let addr = match host.contains(':') {
+                true => format!("[{host}]:{port}"),
+                false => format!("{host}:{port}"),
+            };
+
+            let listener = TcpListener::bind(&addr)
+                .await
+                .with_context(|| format!("Could not bind to {addr}"))?;
+
+            println!("Ready to roll at: {addr}");
+            listeners.push(listener);
+        }
+
+        Ok(listeners)
+    }
+
+    pub async fn listen_unix(&self) -> Result<Option<UnixListener>> {
+        let Some(path) = self.config.lock().await.unixsocket.clone() else {
+            return Ok(None);
+        };
+
+        // a socket file left behind by a previous run would make the bind fail
+        if Path::new(&path).exists() {
+            std::fs::remove_file(&path)?;
+        }
+
+        let listener =
+            UnixListener::bind(&path).with_context(|| format!("Could not bind to {path}"))?;
+
+        println!("Ready to roll at: {path}");
+        Ok(Some(listener))
+    }
+
+    pub async fn remove_unix_socket(&self) {
+        if let Some(path) = self.config.lock().await.unixsocket.clone() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    // keeps a replica connected to its master, reconnecting whenever the link
+    // drops, with the delay between attempts doubling up to a limit
+    pub async fn replicate(self: Arc<Self>, sender: Arc<Sender<Frame>>) {
+        let mut backoff = MIN_RECONNECT_BACKOFF;
+        // the stream goes on in the db it had selected when it's resumed
+        let mut db_index = 0;
+
+        loop {
+            let stream = match self.connect_to_master().await {
+                Ok(Some(stream)) => stream,
+                Ok(None) => return,
+                Err(e) => {
+                    println!("Could not connect to master: {e:#}");
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+                    continue;
+                }
+            };
+
+            let mut link = Connection::new(stream);
+            link.kind = ConnectionKind::MasterLink;
+            link.db_index = db_index;
+
+            match self.handshake_master(&mut link, Arc::clone(&sender)).await {
+                Ok(()) => {
+                    self.replication.lock().await.master_link_up = true;
+                    backoff = MIN_RECONNECT_BACKOFF;
+
+                    // a task of its own, so that a panic while applying the
+                    // stream is just one more way for the link to drop
+                    let server = Arc::clone(&self);
+                    let sender = Arc::clone(&sender);
+                    let applied = tokio::spawn(async move {
+                        server.handle_connection(&mut link, sender).await;
+                        link
+                    });
+
+                    match applied.await {
+                        Ok(link) => db_index = link.db_index,
+                        // we can't tell where the stream stopped, so ask for a snapshot
+                        Err(_) => self.replication.lock().await.master_replid = None,
+                    }
+                }
+                Err(e) => println!("Handshake with master failed: {e:#}"),
+            }
 
-        println!("Ready to roll at: {addr}");
-        listener
+            self.replication.lock().await.master_link_up = false;
+            println!("Lost the link to master, reconnecting");
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+        }
     }
 
     pub async fn connect_to_master(&self) -> Result<Option<TcpStream>> {
-        if let Some(replicaof) = self.config.replicaof.clone() {
-            println!(
-                "replica at {} connecting to master at {}",
-                self.config.port, replicaof
-            );
+        let (port, replicaof) = {
+            let config = self.config.lock().await;
+            (config.port, config.replicaof.clone())
+        };
+
+        if let Some(replicaof) = replicaof {
+            println!("replica at {} connecting to master at {}", port, replicaof);
             let stream = TcpStream::connect(replicaof).await?;
             return Ok(Some(stream));
         }
@@ -60,39 +328,140 @@ impl RedisServer {
     }
 
     pub async fn load_rdb(&self) {
-        if let (Some(dir), Some(dbfilename)) =
-            (self.config.dir.clone(), self.config.dbfilename.clone())
-        {
+        let (dir, dbfilename, ignore_rdb_checksum) = {
+            let config = self.config.lock().await;
+            (
+                config.dir.clone(),
+                config.dbfilename.clone(),
+                config.ignore_rdb_checksum,
+            )
+        };
+
+        if let (Some(dir), Some(dbfilename)) = (dir, dbfilename) {
             let filename = format!("{dir}/{dbfilename}");
             let path = Path::new(&filename);
 
-            let rdb_contents = rdb::parse_rdb_file(path.to_path_buf()).await.unwrap();
-            let mut db = self.db.lock().await;
-            *db = rdb_contents;
+            // nothing has been saved yet, the same as an empty dataset
+            if !path.exists() {
+                println!("No RDB file at {filename}, starting empty");
+                return;
+            }
 
-            println!("Loaded the RDB file successfully");
+            // a corrupt file shouldn't keep the server from starting
+            match rdb::parse_rdb_file(path.to_path_buf(), !ignore_rdb_checksum).await {
+                Ok(rdb_contents) => {
+                    self.replace_dbs(rdb_contents).await;
+                    println!("Loaded the RDB file successfully");
+                }
+                Err(e) => eprintln!("Could not load {filename}, starting empty: {e:#}"),
+            }
         }
     }
 
-    pub async fn handle_connection(
-        &self,
-        mut conn: Connection,
-        sender: Arc<Sender<Frame>>,
-        respond: bool,
-    ) {
-        println!("handling new connection...");
+    // a client from accept to close, turned away if maxclients are connected
+    pub async fn serve(self: Arc<Self>, mut conn: Connection, sender: Arc<Sender<Frame>>) {
+        let connected = self.connected.fetch_add(1, Ordering::Relaxed);
+        // given back on drop, so a handler that panics doesn't keep it
+        let _slot = ClientSlot(&self.connected);
+
+        if connected >= self.maxclients().await {
+            let resp_frame = Frame::Error("ERR max number of clients reached".to_string());
+            let _ = conn.write_frame(&resp_frame).await;
+            return;
+        }
+
+        self.handle_connection(&mut conn, sender).await;
+    }
+
+    pub async fn handle_connection(&self, conn: &mut Connection, sender: Arc<Sender<Frame>>) {
+        // the master link is trusted, everyone else has to AUTH if there's a password
+        let is_client = conn.kind == ConnectionKind::Client;
+        let _registered = if is_client {
+            conn.authenticated = self.config.lock().await.requirepass.is_none();
+
+            let client = ClientInfo::of(conn, "NULL");
+            self.clients.lock().await.insert(conn.id, client);
+            Some(Registered {
+                clients: Arc::clone(&self.clients),
+                id: conn.id,
+            })
+        } else {
+            None
+        };
+
+        let (messages, mut published) = mpsc::unbounded_channel();
+        conn.messages = Some(messages);
 
         loop {
-            let Ok(Some(frames)) = conn.read_frames().await else {
-                println!("got nothing, stopping reading");
-                break;
+            // read again every time, CONFIG SET may have changed it
+            if is_client {
+                let timeout = self.config.lock().await.timeout;
+                conn.idle_timeout = (timeout > 0).then(|| Duration::from_secs(timeout));
+            }
+
+            let read = tokio::select! {
+                read = conn.read_frames() => read,
+                Some(message) = published.recv() => {
+                    if conn.write_frame(&message).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                // the commands read so far have all been run by now
+                _ = self.shutdown_requested() => break,
+            };
+
+            let frames = match read {
+                Ok(Some(frames)) => frames,
+                Ok(None) => break,
+                Err(e) if e.is::<ProtocolError>() => {
+                    println!("closing connection: {e}");
+                    let _ = conn.write_frame(&Frame::Error(format!("ERR {e}"))).await;
+                    break;
+                }
+                // the connection itself failed, so there's no one to tell
+                Err(e) => {
+                    println!("closing connection: {e}");
+                    break;
+                }
             };
 
+            // a GETACK in the batch is already answered with the current offset
+            let has_getack = frames.iter().any(|(frame, _)| is_getack(frame));
+            let has_commands = frames
+                .iter()
+                .any(|(frame, _)| !matches!(frame, Frame::RDBContents(_)));
+
             for (frame, consumed_bytes) in frames {
                 let sender = Arc::clone(&sender);
+                let command = command_name(&frame);
 
-                self.process_frame(&mut conn, frame, consumed_bytes, sender, respond)
+                conn.begin_command();
+                self.process_frame(conn, frame, consumed_bytes, sender)
                     .await;
+                conn.muted = false;
+
+                if let (true, Some(command)) = (is_client, command) {
+                    let client = ClientInfo::of(conn, &command);
+                    self.clients.lock().await.insert(conn.id, client);
+                }
+
+                // whatever came after QUIT is never run
+                if conn.quit {
+                    break;
+                }
+            }
+
+            if conn.quit {
+                break;
+            }
+
+            // on the master link, report how far we got after applying its writes
+            if conn.kind == ConnectionKind::MasterLink && has_commands && !has_getack {
+                let offset = self.replication.lock().await.slave_repl_offset.unwrap_or(0);
+                if conn.write_frame(&replconf_ack(offset)).await.is_err() {
+                    break;
+                }
             }
         }
     }
@@ -103,101 +472,2324 @@ impl RedisServer {
         frame: Frame,
         consumed_bytes: usize,
         sender: Arc<Sender<Frame>>,
-        respond: bool,
     ) {
-        println!("Processing frame: {:?}", frame);
+        // a new snapshot from the master, after the replica fell behind
+        if conn.kind == ConnectionKind::MasterLink {
+            match frame {
+                Frame::SimpleString(fullresync) if fullresync.starts_with("FULLRESYNC") => {
+                    conn.db_index = 0;
+                    return self.replication.lock().await.apply_fullresync(&fullresync);
+                }
+                Frame::SimpleString(reply) if reply.starts_with("CONTINUE") => {
+                    return self.replication.lock().await.apply_continue(&reply);
+                }
+                Frame::RDBContents(contents) => return self.load_master_rdb(contents).await,
+                _ => {}
+            }
+        }
+
+        if matches!(frame, Frame::RDBContents(_)) {
+            return;
+        }
+
+        // an empty inline line, Redis just skips those
+        if matches!(&frame, Frame::Array(items) if items.is_empty()) {
+            return;
+        }
+
+        // commands are arrays of bulk strings, anything else can't be run
+        let Ok((command, args)) = extract_command(frame.clone()) else {
+            let resp_frame = Frame::Error("ERR Protocol error".to_string());
+            return conn.write_frame(&resp_frame).await.unwrap();
+        };
+        let command = command.to_uppercase();
 
-        if matches!(frame, Frame::RDBContents()) {
-            println!("Got RDB Frame. Ignoring");
+        // the handlers count on their arguments being there
+        let spec = commands::lookup(&command);
+        if spec.is_some_and(|spec| !spec.arity_matches(args.len() + 1)) {
+            conn.write_frame(&wrong_arity(&command)).await.unwrap();
             return;
         }
 
+        // keys, members and options are all kept as strings
+        let binary = args.iter().enumerate().any(|(index, arg)| {
+            matches!(arg, Frame::BulkString(bytes) if std::str::from_utf8(bytes).is_err())
+                && !commands::is_binary_arg(&command, index)
+        });
+        if binary {
+            let resp_frame = Frame::Error(
+                "ERR only string values and messages may be arbitrary bytes".to_string(),
+            );
+            return conn.write_frame(&resp_frame).await.unwrap();
+        }
+
+        let no_auth = matches!(
+            command.as_str(),
+            "AUTH" | "HELLO" | "PING" | "RESET" | "QUIT"
+        );
+        if !conn.authenticated && !no_auth {
+            let resp_frame = Frame::Error("NOAUTH Authentication required.".to_string());
+            return conn.write_frame(&resp_frame).await.unwrap();
+        }
+
+        // a RESP2 connection with subscriptions can only receive messages,
+        // RESP3 has push frames to tell them apart from replies
+        let subscribe_mode = conn.protocol == 2 && conn.subscription_count() > 0;
+        if subscribe_mode && !SUBSCRIBE_MODE_COMMANDS.contains(&command.as_str()) {
+            let resp_frame = Frame::Error(format!(
+                "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT / RESET \
+                 are allowed in this context",
+                command.to_lowercase()
+            ));
+            return conn.write_frame(&resp_frame).await.unwrap();
+        }
+
+        // only the master link may write to a replica, blocking pops included
+        let writes = spec.is_some_and(|spec| spec.has_flag(commands::WRITE));
+        if conn.kind == ConnectionKind::Client && writes && !self.is_master().await {
+            let message = "READONLY You can't write against a read only replica.";
+            return conn
+                .write_frame(&Frame::Error(message.to_string()))
+                .await
+                .unwrap();
+        }
+
+        // inside MULTI everything up to EXEC or DISCARD is only queued
+        if let Some(queued) = &mut conn.queued {
+            if !matches!(
+                command.as_str(),
+                "EXEC" | "DISCARD" | "MULTI" | "WATCH" | "RESET" | "QUIT"
+            ) {
+                // these would block or hand over the connection while EXEC
+                // holds the transaction lock
+                if spec.is_some_and(|spec| spec.has_flag(commands::NO_MULTI)) {
+                    let message = "ERR Command not allowed inside a transaction";
+                    return conn
+                        .write_frame(&Frame::Error(message.to_string()))
+                        .await
+                        .unwrap();
+                }
+                queued.push(frame);
+                conn.write_frame(&Frame::SimpleString("QUEUED".to_string()))
+                    .await
+                    .unwrap();
+                return;
+            }
+        }
+
+        if command == "EXEC" {
+            return self.exec(conn, sender).await;
+        }
+
+        // commands that can wait indefinitely must not hold up an EXEC
+        let _guard = match command.as_str() {
+            "PSYNC" | "WAIT" | "BLPOP" | "BRPOP" => None,
+            _ => Some(self.transaction_lock.read().await),
+        };
+
+        self.execute_frame(conn, frame, consumed_bytes, sender)
+            .await;
+    }
+
+    // runs the queued commands of a transaction while no other
+    // command can run, replying with all of their replies at once
+    async fn exec(&self, conn: &mut Connection, sender: Arc<Sender<Frame>>) {
+        let Some(queued) = conn.queued.take() else {
+            let resp_frame = Frame::Error("ERR EXEC without MULTI".to_string());
+            return conn.write_frame(&resp_frame).await.unwrap();
+        };
+
+        let _guard = self.transaction_lock.write().await;
+
+        // a WATCHed key that changed since aborts the whole transaction
+        let mut watched_changed = false;
+        for (db_index, key, version) in conn.watched.drain(..) {
+            let db = self.dbs[db_index].lock().await;
+            if watch_version(&db, &self.deleted[db_index], &key) != version {
+                watched_changed = true;
+            }
+        }
+
+        if watched_changed {
+            return conn.write_frame(&Frame::NullArray).await.unwrap();
+        }
+
+        conn.captured = Some(vec![]);
+        for frame in queued {
+            self.execute_frame(conn, frame, 0, Arc::clone(&sender))
+                .await;
+        }
+        let replies = conn.captured.take().unwrap_or_default();
+
+        conn.write_frame(&Frame::Array(replies)).await.unwrap();
+    }
+
+    async fn execute_frame(
+        &self,
+        conn: &mut Connection,
+        frame: Frame,
+        consumed_bytes: usize,
+        sender: Arc<Sender<Frame>>,
+    ) {
+        let respond = conn.kind.replies();
         let (command, args) = extract_command(frame.clone()).unwrap();
+        let db = Arc::clone(&self.dbs[conn.db_index]);
+
+        // a replica leaves eviction to its master, it just applies the writes
+        let grows =
+            commands::lookup(&command).is_some_and(|spec| spec.has_flag(commands::DENY_OOM));
+        if grows && conn.kind == ConnectionKind::Client && !self.free_memory(&args).await {
+            let resp_frame =
+                Frame::Error("OOM command not allowed when used memory > 'maxmemory'.".to_string());
+            return conn.write_frame(&resp_frame).await.unwrap();
+        }
+
+        let measured_keys = measured_keys(conn.db_index, &command, &args);
+        let measured = match &measured_keys {
+            Some((db_index, keys)) => self.measure_before(*db_index, keys).await,
+            None => None,
+        };
 
         match command.to_uppercase().as_str() {
             "PING" => handle_ping(conn, respond).await,
+            "MULTI" => handle_multi(conn).await,
+            "DISCARD" => handle_discard(conn).await,
+            "SUBSCRIBE" => handle_subscribe(conn, Arc::clone(&self.channels), &args).await,
+            "UNSUBSCRIBE" => handle_unsubscribe(conn, Arc::clone(&self.channels), &args).await,
+            "PSUBSCRIBE" => handle_psubscribe(conn, Arc::clone(&self.patterns), &args).await,
+            "PUNSUBSCRIBE" => handle_punsubscribe(conn, Arc::clone(&self.patterns), &args).await,
+            "PUBLISH" => {
+                let (channel, message) = (args[0].clone(), args[1].clone());
+                let (channels, patterns) = (Arc::clone(&self.channels), Arc::clone(&self.patterns));
+                handle_publish(conn, channels, patterns, channel, message).await
+            }
+            "WATCH" => {
+                let deleted = &self.deleted[conn.db_index];
+                handle_watch(conn, Arc::clone(&db), deleted, &args).await
+            }
+            "UNWATCH" => handle_unwatch(conn).await,
             "ECHO" => handle_echo(conn, args.first().unwrap().clone()).await,
-            "SET" => handle_set(conn, Arc::clone(&self.db), frame, sender, respond).await,
-            "GET" => handle_get(conn, Arc::clone(&self.db), args[0].clone()).await,
-            "CONFIG" => handle_config(conn, &self.config, args[0].clone(), args[1].clone()).await,
-            "KEYS" => handle_keys(conn, Arc::clone(&self.db)).await,
-            "INFO" => handle_info(conn, Arc::clone(&self.replication)).await,
+            "SET" => handle_set(conn, Arc::clone(&db), frame.clone(), respond).await,
+            "SETNX" => {
+                let (key, value) = (args[0].clone(), args[1].clone());
+                handle_setnx(conn, Arc::clone(&db), key, value, respond).await
+            }
+            "SETEX" => handle_setex(conn, Arc::clone(&db), &args, respond).await,
+            "GET" => handle_get(conn, Arc::clone(&db), args[0].clone()).await,
+            "MSET" => handle_mset(conn, Arc::clone(&db), &args, respond).await,
+            "MGET" => handle_mget(conn, Arc::clone(&db), &args).await,
+            "APPEND" => {
+                let (key, value) = (args[0].clone(), args[1].clone());
+                handle_append(conn, Arc::clone(&db), key, value, respond).await
+            }
+            "STRLEN" => handle_strlen(conn, Arc::clone(&db), args[0].clone()).await,
+            "GETSET" => {
+                let (key, value) = (args[0].clone(), args[1].clone());
+                handle_getset(conn, Arc::clone(&db), key, value, respond).await
+            }
+            "GETDEL" => handle_getdel(conn, Arc::clone(&db), args[0].clone(), respond).await,
+            "DEL" => handle_del(conn, Arc::clone(&db), &args, respond).await,
+            "EXISTS" => handle_exists(conn, Arc::clone(&db), &args).await,
+            "INCR" => handle_incr(conn, Arc::clone(&db), args[0].clone(), respond).await,
+            "DECR" => handle_decr(conn, Arc::clone(&db), args[0].clone(), respond).await,
+            "INCRBY" => {
+                let (key, increment) = (args[0].clone(), args[1].clone());
+                handle_incrby(conn, Arc::clone(&db), key, increment, respond).await
+            }
+            "DECRBY" => {
+                let (key, decrement) = (args[0].clone(), args[1].clone());
+                handle_decrby(conn, Arc::clone(&db), key, decrement, respond).await
+            }
+            "EXPIRE" => {
+                let (key, seconds) = (args[0].clone(), args[1].clone());
+                handle_expire(conn, Arc::clone(&db), key, seconds, respond).await
+            }
+            "PEXPIRE" => {
+                let (key, ms) = (args[0].clone(), args[1].clone());
+                handle_pexpire(conn, Arc::clone(&db), key, ms, respond).await
+            }
+            "EXPIREAT" => {
+                let (key, timestamp) = (args[0].clone(), args[1].clone());
+                handle_expireat(conn, Arc::clone(&db), key, timestamp, respond).await
+            }
+            "PEXPIREAT" => {
+                let (key, timestamp_ms) = (args[0].clone(), args[1].clone());
+                handle_pexpireat(conn, Arc::clone(&db), key, timestamp_ms, respond).await
+            }
+            "PERSIST" => handle_persist(conn, Arc::clone(&db), args[0].clone(), respond).await,
+            "LPUSH" => {
+                handle_lpush(conn, Arc::clone(&db), &args, respond).await;
+                self.wake_blocked(conn.db_index, args[0].clone()).await
+            }
+            "RPUSH" => {
+                handle_rpush(conn, Arc::clone(&db), &args, respond).await;
+                self.wake_blocked(conn.db_index, args[0].clone()).await
+            }
+            "LPOP" => handle_lpop(conn, Arc::clone(&db), &args, respond).await,
+            "RPOP" => handle_rpop(conn, Arc::clone(&db), &args, respond).await,
+            "BLPOP" => {
+                let used_memory = Arc::clone(&self.used_memory[conn.db_index]);
+                let blocked = Arc::clone(&self.blocked);
+                if let Some(key) =
+                    handle_blpop(conn, Arc::clone(&db), used_memory, blocked, &args).await
+                {
+                    self.touch_keys(conn.db_index, std::slice::from_ref(&key))
+                        .await;
+                    self.propagate_pop("LPOP", conn.db_index, key, &sender)
+                        .await;
+                }
+            }
+            "BRPOP" => {
+                let used_memory = Arc::clone(&self.used_memory[conn.db_index]);
+                let blocked = Arc::clone(&self.blocked);
+                if let Some(key) =
+                    handle_brpop(conn, Arc::clone(&db), used_memory, blocked, &args).await
+                {
+                    self.touch_keys(conn.db_index, std::slice::from_ref(&key))
+                        .await;
+                    self.propagate_pop("RPOP", conn.db_index, key, &sender)
+                        .await;
+                }
+            }
+            "LLEN" => handle_llen(conn, Arc::clone(&db), args[0].clone()).await,
+            "LINDEX" => {
+                let (key, index) = (args[0].clone(), args[1].clone());
+                handle_lindex(conn, Arc::clone(&db), key, index).await
+            }
+            "LRANGE" => {
+                let (key, start, stop) = (args[0].clone(), args[1].clone(), args[2].clone());
+                handle_lrange(conn, Arc::clone(&db), key, start, stop).await
+            }
+            "HSET" => handle_hset(conn, Arc::clone(&db), &args, respond).await,
+            "HGET" => {
+                let (key, field) = (args[0].clone(), args[1].clone());
+                handle_hget(conn, Arc::clone(&db), key, field).await
+            }
+            "HGETALL" => handle_hgetall(conn, Arc::clone(&db), args[0].clone()).await,
+            "HDEL" => handle_hdel(conn, Arc::clone(&db), &args, respond).await,
+            "HINCRBY" => handle_hincrby(conn, Arc::clone(&db), &args, respond).await,
+            "HKEYS" => handle_hkeys(conn, Arc::clone(&db), args[0].clone()).await,
+            "HVALS" => handle_hvals(conn, Arc::clone(&db), args[0].clone()).await,
+            "HLEN" => handle_hlen(conn, Arc::clone(&db), args[0].clone()).await,
+            "HEXISTS" => {
+                let (key, field) = (args[0].clone(), args[1].clone());
+                handle_hexists(conn, Arc::clone(&db), key, field).await
+            }
+            "SADD" => handle_sadd(conn, Arc::clone(&db), &args, respond).await,
+            "SREM" => handle_srem(conn, Arc::clone(&db), &args, respond).await,
+            "SMEMBERS" => handle_smembers(conn, Arc::clone(&db), args[0].clone()).await,
+            "SISMEMBER" => {
+                let (key, member) = (args[0].clone(), args[1].clone());
+                handle_sismember(conn, Arc::clone(&db), key, member).await
+            }
+            "SCARD" => handle_scard(conn, Arc::clone(&db), args[0].clone()).await,
+            "SINTER" => handle_sinter(conn, Arc::clone(&db), &args).await,
+            "SUNION" => handle_sunion(conn, Arc::clone(&db), &args).await,
+            "SDIFF" => handle_sdiff(conn, Arc::clone(&db), &args).await,
+            "ZADD" => handle_zadd(conn, Arc::clone(&db), &args, respond).await,
+            "ZSCORE" => {
+                let (key, member) = (args[0].clone(), args[1].clone());
+                handle_zscore(conn, Arc::clone(&db), key, member).await
+            }
+            "ZRANK" => {
+                let (key, member) = (args[0].clone(), args[1].clone());
+                handle_zrank(conn, Arc::clone(&db), key, member).await
+            }
+            "ZRANGE" => handle_zrange(conn, Arc::clone(&db), &args).await,
+            "ZRANGEBYSCORE" => handle_zrangebyscore(conn, Arc::clone(&db), &args).await,
+            "COPY" => {
+                if let Some(db_index) = handle_copy(conn, &self.dbs, &args, respond).await {
+                    let destination = unpack_bulk_str(args[1].clone()).unwrap();
+                    self.touch_keys(db_index, &[destination]).await;
+                    self.wake_blocked(db_index, args[1].clone()).await
+                }
+            }
+            "OBJECT" => handle_object(conn, Arc::clone(&db), &args).await,
+            "TYPE" => handle_type(conn, Arc::clone(&db), args[0].clone()).await,
+            "TTL" => handle_ttl(conn, Arc::clone(&db), args[0].clone()).await,
+            "PTTL" => handle_pttl(conn, Arc::clone(&db), args[0].clone()).await,
+            "SELECT" => handle_select(conn, args[0].clone(), respond).await,
+            "DBSIZE" => handle_dbsize(conn, Arc::clone(&db)).await,
+            "FLUSHDB" => {
+                handle_flushdb(conn, Arc::clone(&db), respond).await;
+                self.deleted[conn.db_index].store(next_version(), Ordering::Relaxed);
+                self.recount_memory(conn.db_index).await;
+            }
+            "FLUSHALL" => {
+                handle_flushall(conn, &self.dbs, respond).await;
+                for deleted in &self.deleted {
+                    deleted.store(next_version(), Ordering::Relaxed);
+                }
+                for db_index in 0..self.dbs.len() {
+                    self.recount_memory(db_index).await;
+                }
+            }
+            "SAVE" => {
+                handle_save(
+                    conn,
+                    &self.dbs,
+                    Arc::clone(&self.config),
+                    Arc::clone(&self.rdb_last_save),
+                )
+                .await
+            }
+            "BGSAVE" => {
+                handle_bgsave(
+                    conn,
+                    &self.dbs,
+                    Arc::clone(&self.config),
+                    Arc::clone(&self.rdb_last_save),
+                )
+                .await
+            }
+            "SHUTDOWN" => {
+                let should_shutdown = handle_shutdown(
+                    conn,
+                    &self.dbs,
+                    Arc::clone(&self.config),
+                    Arc::clone(&self.rdb_last_save),
+                    &args,
+                )
+                .await;
+
+                if should_shutdown {
+                    self.request_shutdown();
+                }
+            }
+            "CONFIG" => {
+                handle_config(conn, Arc::clone(&self.config), &args).await;
+
+                // nothing is counted while maxmemory is off
+                let arg = |i: usize| unpack_bulk_str(args[i].clone()).unwrap_or_default();
+                let sets_maxmemory = args.len() == 3
+                    && arg(0).eq_ignore_ascii_case("SET")
+                    && arg(1).eq_ignore_ascii_case("maxmemory");
+                if sets_maxmemory {
+                    for db_index in 0..self.dbs.len() {
+                        self.recount_memory(db_index).await;
+                    }
+                }
+            }
+            "SCAN" => handle_scan(conn, Arc::clone(&db), &args).await,
+            "HSCAN" => handle_hscan(conn, Arc::clone(&db), &args).await,
+            "SSCAN" => handle_sscan(conn, Arc::clone(&db), &args).await,
+            "KEYS" => handle_keys(conn, Arc::clone(&db), args[0].clone()).await,
+            "HELLO" => {
+                let repl_conf = Arc::clone(&self.replication);
+                handle_hello(conn, repl_conf, Arc::clone(&self.config), &args).await
+            }
+            "QUIT" => handle_quit(conn).await,
+            "RESET" => {
+                let channels = Arc::clone(&self.channels);
+                let patterns = Arc::clone(&self.patterns);
+                handle_reset(conn, channels, patterns, Arc::clone(&self.config)).await
+            }
+            "CLIENT" => handle_client(conn, Arc::clone(&self.clients), &args).await,
+            "AUTH" => handle_auth(conn, Arc::clone(&self.config), &args).await,
+            "COMMAND" => handle_command(conn, &args).await,
+            "DEBUG" => handle_debug(conn, &args).await,
+            "INFO" => {
+                let repl_conf = Arc::clone(&self.replication);
+                handle_info(conn, repl_conf, Arc::clone(&self.config), &args).await
+            }
             "REPLCONF" => {
                 handle_replconf(conn, Arc::clone(&self.replication), &args, respond).await
             }
-            "PSYNC" => handle_psync(conn, Arc::clone(&self.replication), sender).await,
-            c => panic!("Cannot handle command {}", c),
+            "WAIT" => {
+                let repl_conf = Arc::clone(&self.replication);
+                handle_wait(
+                    conn,
+                    repl_conf,
+                    Arc::clone(&sender),
+                    args[0].clone(),
+                    args[1].clone(),
+                )
+                .await
+            }
+            // a replica that falls too far behind gets a new snapshot, which costs
+            // a full copy of the dataset but is the only way to not lose writes
+            "PSYNC" => {
+                let mut resume = psync_resume_point(&args);
+
+                // PSYNC doesn't return until the replica goes away, so CLIENT LIST
+                // has to learn about it now
+                conn.kind = ConnectionKind::Replica;
+                let client = ClientInfo::of(conn, "psync");
+                self.clients.lock().await.insert(conn.id, client);
+
+                loop {
+                    let (sync, receiver) = self.sync_replica(resume.take(), &sender).await;
+
+                    let repl_conf = Arc::clone(&self.replication);
+                    if !handle_psync(conn, repl_conf, sync, receiver).await {
+                        break;
+                    }
+                }
+            }
+            _ => {
+                // worded like Redis, which lists the arguments too
+                let args_beginning: String = args
+                    .iter()
+                    .filter_map(|arg| unpack_bulk_str(arg.clone()).ok())
+                    .map(|arg| format!("'{arg}' "))
+                    .collect();
+                let resp_frame = Frame::Error(format!(
+                    "ERR unknown command '{command}', with args beginning with: {args_beginning}"
+                ));
+                conn.write_frame(&resp_frame).await.unwrap();
+            }
         };
 
+        if let (Some((db_index, keys)), Some((mut used, before))) = (measured_keys, measured) {
+            let after = keys_size(&*self.dbs[db_index].lock().await, &keys);
+            *used = (*used + after).saturating_sub(before);
+        }
+
+        if is_write_command(&command) {
+            self.touch_keys(conn.db_index, &written_keys(&command, &args))
+                .await;
+        }
+
+        if is_write_command(&command) && self.is_master().await {
+            self.propagate_write(conn.db_index, frame, &sender).await;
+        }
+
         {
             let mut repl_conf = self.replication.lock().await;
 
-            if repl_conf.role == ReplRole::Slave {
+            // only the replication stream from the master moves the offset,
+            // commands from regular clients of the replica don't
+            if repl_conf.role == ReplRole::Slave && conn.kind == ConnectionKind::MasterLink {
                 repl_conf.slave_repl_offset = repl_conf
                     .slave_repl_offset
                     .map_or(Some(consumed_bytes), |offset| Some(offset + consumed_bytes));
             }
         }
+    }
+
+    // gives the keys a new version, or the db one for those that are
+    // gone now, see WATCH
+    async fn touch_keys(&self, db_index: usize, keys: &[String]) {
+        let mut db = self.dbs[db_index].lock().await;
+        let mut ring = self.expiring[db_index].lock().await;
 
-        println!("Frame response has been sent");
+        for key in keys {
+            match db.get_mut(key) {
+                Some(db_item) => {
+                    db_item.version = next_version();
+                    if db_item.expires_at.is_some() {
+                        ring.track(key);
+                    }
+                }
+                None => self.deleted[db_index].store(next_version(), Ordering::Relaxed),
+            }
+        }
     }
 
-    pub async fn handshake_master(&self, conn: &mut Connection, sender: Arc<Sender<Frame>>) {
-        println!("Starting handshake with master...");
+    // what an RDB file (or the master) had, by database index
+    // makes room for a write that may take more memory, evicting the least
+    // recently used keys of any database if the policy allows it
+    async fn free_memory(&self, args: &[Frame]) -> bool {
+        let (maxmemory, policy) = {
+            let config = self.config.lock().await;
+            (config.maxmemory, config.maxmemory_policy)
+        };
+        if maxmemory == 0 {
+            return true;
+        }
 
-        // Step 1: Send PING
-        let ping_cmd = Frame::Array(vec![Frame::BulkString("PING".to_string())]);
-        conn.write_frame(&ping_cmd)
-            .await
-            .expect("PING didn't succeed");
+        // roughly what the write adds, taken to be its arguments
+        let incoming = ENTRY_OVERHEAD
+            + args
+                .iter()
+                .map(|arg| match arg {
+                    Frame::BulkString(arg) => arg.len(),
+                    _ => 0,
+                })
+                .sum::<usize>();
 
-        let _frame = conn.read_frames().await.unwrap();
-        println!("[Handshake Step 1] PING succeeded");
+        let mut used = Vec::with_capacity(self.used_memory.len());
+        for used_memory in &self.used_memory {
+            used.push(used_memory.lock().await);
+        }
+        if used.iter().map(|used| **used).sum::<usize>() + incoming <= maxmemory {
+            return true;
+        }
+        if policy == MaxmemoryPolicy::NoEviction {
+            return false;
+        }
 
-        // Step 2.1: Send REPLCONF listening-port <port>
-        let replconf_cmd = Frame::Array(vec![
-            Frame::BulkString("REPLCONF".to_string()),
-            Frame::BulkString("listening-port".to_string()),
-            Frame::BulkString(self.config.port.to_string()),
-        ]);
-        conn.write_frame(&replconf_cmd)
-            .await
-            .expect("REPLCONF with listening port didn't succeed");
+        let mut dbs = lock_all(&self.dbs).await;
+        while used.iter().map(|used| **used).sum::<usize>() + incoming > maxmemory {
+            let Some((db_index, key)) = dbs
+                .iter()
+                .enumerate()
+                .flat_map(|(db_index, db)| {
+                    db.iter()
+                        .map(move |(key, db_item)| (db_index, key, db_item))
+                })
+                .min_by_key(|(_, _, db_item)| db_item.last_access)
+                .map(|(db_index, key, _)| (db_index, key.clone()))
+            else {
+                return false;
+            };
 
-        let _frame = conn.read_frames().await.unwrap();
-        println!("Handshake Step 2.1 [REPLCONF with listening port] succeeded");
+            let db_item = dbs[db_index].remove(&key).unwrap();
+            *used[db_index] = used[db_index].saturating_sub(db_item.estimated_size(&key));
+            self.deleted[db_index].store(next_version(), Ordering::Relaxed);
+        }
 
-        // Step 2.2: Send REPLCONF capa psync2
-        let replconf_cmd = Frame::Array(vec![
-            Frame::BulkString("REPLCONF".to_string()),
-            Frame::BulkString("capa".to_string()),
-            Frame::BulkString("psync2".to_string()),
-        ]);
-        conn.write_frame(&replconf_cmd)
-            .await
-            .expect("REPLCONF with capabilities didn't succeed");
+        true
+    }
 
-        let _frame = conn.read_frames().await.unwrap();
-        println!("Handshake Step 2.2 [REPLCONF with capabilities] succeeded");
+    // locks the memory count of the database for a write that's about to
+    // change the given keys, along with what they take before it runs
+    async fn measure_before(
+        &self,
+        db_index: usize,
+        keys: &[String],
+    ) -> Option<(MutexGuard<'_, usize>, usize)> {
+        let used = self.used_memory[db_index].lock().await;
+        if self.config.lock().await.maxmemory == 0 {
+            return None;
+        }
 
-        // Step 3: Send PSYNC
-        let psync_cmd = Frame::Array(vec![
-            Frame::BulkString("PSYNC".to_string()),
-            Frame::BulkString("?".to_string()),
-            Frame::BulkString("-1".to_string()),
-        ]);
-        conn.write_frame(&psync_cmd)
-            .await
-            .expect("PSYNC didn't succeed");
+        let before = keys_size(&*self.dbs[db_index].lock().await, keys);
+        Some((used, before))
+    }
+
+    // counts the memory of the whole database again
+    async fn recount_memory(&self, db_index: usize) {
+        let mut used = self.used_memory[db_index].lock().await;
+        *used = db_size(&*self.dbs[db_index].lock().await);
+    }
 
-        let Ok(Some(frames)) = conn.read_frames().await else {
-            panic!("Handshake failed after sending PSYNC.");
+    async fn replace_dbs(&self, contents: Vec<Db>) {
+        for (db_index, contents) in contents.into_iter().enumerate() {
+            let mut used = self.used_memory[db_index].lock().await;
+            let mut db = self.dbs[db_index].lock().await;
+            let mut ring = self.expiring[db_index].lock().await;
+
+            *used = db_size(&contents);
+
+            *ring = ExpiryRing::default();
+            for (key, db_item) in &contents {
+                if db_item.expires_at.is_some() {
+                    ring.track(key);
+                }
+            }
+
+            *db = contents;
+            self.deleted[db_index].store(next_version(), Ordering::Relaxed);
+        }
+    }
+
+    async fn wake_blocked(&self, db_index: usize, key: Frame) {
+        let key = match key {
+            Frame::BulkString(key) => String::from_utf8(key.to_vec()).unwrap(),
+            _ => return,
         };
 
-        for (frame, consumed_bytes) in frames.into_iter().skip(2) {
-            self.process_frame(conn, frame, consumed_bytes, Arc::clone(&sender), false)
-                .await;
+        if let Some(waiters) = self.blocked.lock().await.get(&(db_index, key)) {
+            for waiter in waiters {
+                waiter.notify_one();
+            }
         }
-        println!("Handshake Step 3 [PSYNC] succeeded");
+    }
+
+    // a replica must not block, so blocking pops travel as plain pops
+    async fn propagate_pop(
+        &self,
+        command: &str,
+        db_index: usize,
+        key: String,
+        sender: &Sender<Frame>,
+    ) {
+        if self.is_master().await {
+            let frame = Frame::Array(vec![
+                Frame::BulkString(command.to_string().into()),
+                Frame::BulkString(key.into()),
+            ]);
+            self.propagate_write(db_index, frame, sender).await;
+        }
+    }
+
+    // replicas apply writes to whatever db the master link has selected,
+    // so a SELECT goes out first whenever the write targets another db
+    async fn propagate_write(&self, db_index: usize, frame: Frame, sender: &Sender<Frame>) {
+        let switch_db = {
+            let mut repl_conf = self.replication.lock().await;
+            let switch_db = repl_conf.propagated_db != Some(db_index);
+            repl_conf.propagated_db = Some(db_index);
+            switch_db
+        };
+
+        if switch_db {
+            let select = Frame::Array(vec![
+                Frame::BulkString("SELECT".into()),
+                Frame::BulkString(db_index.to_string().into()),
+            ]);
+            propagate(sender, &self.replication, select).await;
+        }
+
+        propagate(sender, &self.replication, frame).await;
+    }
+
+    // what a replica at the given offset is missing, along with the
+    // stream of the writes that come after it
+    async fn sync_replica(
+        &self,
+        resume: Option<(String, usize)>,
+        sender: &Sender<Frame>,
+    ) -> (PsyncReply, Receiver<Frame>) {
+        // no command may run in between, so every write is
+        // either in the snapshot or in the stream that follows it
+        let _guard = self.transaction_lock.write().await;
+
+        // BLPOP, BRPOP and WAIT propagate without the transaction lock, but
+        // not without this one, so subscribing under it can't miss a frame
+        let mut repl_conf = self.replication.lock().await;
+        let receiver = sender.subscribe();
+
+        let missed = resume.and_then(|(replid, offset)| repl_conf.backlog_since(&replid, offset));
+        if let Some(missed) = missed {
+            return (PsyncReply::Partial { missed }, receiver);
+        }
+
+        let rdb = {
+            let dbs = lock_all(&self.dbs).await;
+            rdb::encode_rdb(&dbs.iter().map(|db| &**db).collect::<Vec<_>>())
+        };
+
+        // the replica starts out in db 0, whatever the stream had selected
+        repl_conf.propagated_db = None;
+        let offset = repl_conf.master_repl_offset.unwrap_or(0);
+        (PsyncReply::Full { rdb, offset }, receiver)
+    }
+
+    async fn load_master_rdb(&self, contents: Bytes) {
+        match rdb::parse_rdb_bytes(contents).await {
+            Ok(rdb_contents) => self.replace_dbs(rdb_contents).await,
+            Err(e) => eprintln!("Could not load the RDB from master: {e:#}"),
+        }
+    }
+
+    pub async fn handshake_master(
+        &self,
+        conn: &mut Connection,
+        sender: Arc<Sender<Frame>>,
+    ) -> Result<()> {
+        println!("Starting handshake with master...");
+
+        // Step 1: Send PING
+        let ping_cmd = Frame::Array(vec![Frame::BulkString("PING".into())]);
+        conn.write_frame(&ping_cmd)
+            .await
+            .context("PING didn't succeed")?;
+
+        conn.read_frames().await?.context(MASTER_CLOSED)?;
+        println!("[Handshake Step 1] PING succeeded");
+
+        // Step 2.1: Send REPLCONF listening-port <port>
+        let replconf_cmd = Frame::Array(vec![
+            Frame::BulkString("REPLCONF".into()),
+            Frame::BulkString("listening-port".into()),
+            Frame::BulkString(self.config.lock().await.port.to_string().into()),
+        ]);
+        conn.write_frame(&replconf_cmd)
+            .await
+            .context("REPLCONF with listening port didn't succeed")?;
+
+        conn.read_frames().await?.context(MASTER_CLOSED)?;
+        println!("Handshake Step 2.1 [REPLCONF with listening port] succeeded");
+
+        // Step 2.2: Send REPLCONF capa psync2
+        let replconf_cmd = Frame::Array(vec![
+            Frame::BulkString("REPLCONF".into()),
+            Frame::BulkString("capa".into()),
+            Frame::BulkString("psync2".into()),
+        ]);
+        conn.write_frame(&replconf_cmd)
+            .await
+            .context("REPLCONF with capabilities didn't succeed")?;
+
+        conn.read_frames().await?.context(MASTER_CLOSED)?;
+        println!("Handshake Step 2.2 [REPLCONF with capabilities] succeeded");
+
+        // Step 3: Send PSYNC, asking to pick up where we left off if we were
+        // replicating before, so the master can send only what we missed
+        let (replid, offset) = {
+            let repl_conf = self.replication.lock().await;
+            match (&repl_conf.master_replid, repl_conf.slave_repl_offset) {
+                (Some(replid), Some(offset)) => (replid.clone(), (offset + 1).to_string()),
+                _ => ("?".to_string(), "-1".to_string()),
+            }
+        };
+        let psync_cmd = Frame::Array(vec![
+            Frame::BulkString("PSYNC".into()),
+            Frame::BulkString(replid.into()),
+            Frame::BulkString(offset.into()),
+        ]);
+        conn.write_frame(&psync_cmd)
+            .await
+            .context("PSYNC didn't succeed")?;
+
+        // the FULLRESYNC reply and the RDB snapshot may arrive in separate reads,
+        // possibly followed by the first propagated commands, or after CONTINUE
+        // just the commands we missed. Those commands are
+        // the start of the replication stream, so they count toward the offset.
+        let mut synced = false;
+        while !synced {
+            let frames = conn.read_frames().await?.context(MASTER_CLOSED)?;
+
+            for (frame, consumed_bytes) in frames {
+                synced |= match &frame {
+                    Frame::RDBContents(_) => true,
+                    Frame::SimpleString(reply) => reply.starts_with("CONTINUE"),
+                    _ => false,
+                };
+
+                self.process_frame(conn, frame, consumed_bytes, Arc::clone(&sender))
+                    .await
+            }
+        }
+        println!("Handshake Step 3 [PSYNC] succeeded");
+        Ok(())
+    }
+}
+
+// commands that mutate the dataset and therefore have to reach the replicas
+// blocking pops are left out, they reach the replicas as plain pops
+fn is_write_command(command: &str) -> bool {
+    commands::lookup(command)
+        .is_some_and(|spec| spec.has_flag(commands::WRITE) && !spec.has_flag(commands::BLOCKING))
+}
+
+// the keys a write command changes; FLUSHDB and FLUSHALL don't
+// need any since the keys they remove have no version left, nor
+// does COPY whose destination is a new key with a version of its own
+fn written_keys(command: &str, args: &[Frame]) -> Vec<String> {
+    let keys = match command.to_uppercase().as_str() {
+        "DEL" => args.iter().collect(),
+        "MSET" => args.iter().step_by(2).collect(),
+        "FLUSHDB" | "FLUSHALL" | "COPY" => vec![],
+        _ => args.iter().take(1).collect::<Vec<_>>(),
+    };
+
+    keys.into_iter()
+        .filter_map(|key| match key {
+            Frame::BulkString(key) => String::from_utf8(key.to_vec()).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+// the database and keys whose memory a write changes, FLUSHDB and FLUSHALL
+// are counted again instead and blocking pops account for themselves
+fn measured_keys(db_index: usize, command: &str, args: &[Frame]) -> Option<(usize, Vec<String>)> {
+    match command.to_uppercase().as_str() {
+        "FLUSHDB" | "FLUSHALL" => None,
+        "COPY" => {
+            let db_index =
+                parse_copy_options(db_index, &args[2..]).map_or(db_index, |(db_index, _)| db_index);
+            let destination = unpack_bulk_str(args[1].clone()).ok()?;
+            Some((db_index, vec![destination]))
+        }
+        _ if is_write_command(command) => Some((db_index, written_keys(command, args))),
+        _ => None,
+    }
+}
+
+fn command_name(frame: &Frame) -> Option<String> {
+    match frame {
+        Frame::Array(items) => match items.first() {
+            Some(Frame::BulkString(command)) => String::from_utf8(command.to_vec()).ok(),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_getack(frame: &Frame) -> bool {
+    match extract_command(frame.clone()) {
+        Ok((command, args)) => {
+            command.eq_ignore_ascii_case("REPLCONF")
+                && matches!(args.first(), Some(Frame::BulkString(s)) if s.eq_ignore_ascii_case(b"GETACK"))
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::ServiceArguments;
+    use crate::db::NUM_DATABASES;
+    use crate::replication::STREAM_CAPACITY;
+    use clap::Parser;
+    use std::collections::{HashMap, VecDeque};
+    use tokio::sync::broadcast;
+
+    // long enough for anything a test waits on, short enough not to hang the run
+    const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+    // a server on a port of its own, set up and serving connections like main does
+    async fn start(args: &[&str]) -> (Arc<RedisServer>, SocketAddr) {
+        let args = std::iter::once("redis").chain(args.iter().copied());
+        let config = Config::from_args(ServiceArguments::parse_from(args)).unwrap();
+        let (sender, _) = broadcast::channel(STREAM_CAPACITY);
+        let sender = Arc::new(sender);
+        let dbs = (0..NUM_DATABASES)
+            .map(|_| Arc::new(Mutex::new(HashMap::new())))
+            .collect();
+        let server = Arc::new(RedisServer::new(config, dbs));
+
+        if server.is_master().await {
+            server.load_rdb().await;
+        } else {
+            tokio::spawn(Arc::clone(&server).replicate(Arc::clone(&sender)));
+        }
+
+        let expiring_server = Arc::clone(&server);
+        tokio::spawn(async move { expiring_server.expire_keys().await });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepting_server = Arc::clone(&server);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let server = Arc::clone(&accepting_server);
+                tokio::spawn(server.serve(Connection::new(stream), Arc::clone(&sender)));
+            }
+        });
+
+        (server, addr)
+    }
+
+    struct Client {
+        conn: Connection,
+        // replies read along with an earlier one
+        replies: VecDeque<Frame>,
+    }
+
+    impl Client {
+        async fn connect(addr: SocketAddr) -> Client {
+            Client {
+                conn: Connection::new(TcpStream::connect(addr).await.unwrap()),
+                replies: VecDeque::new(),
+            }
+        }
+
+        // the arguments are split on spaces, each one sent as a bulk string
+        async fn send(&mut self, command: &str) -> Frame {
+            self.write(command).await;
+            self.read().await
+        }
+
+        async fn write(&mut self, command: &str) {
+            let args = command
+                .split_whitespace()
+                .map(|arg| Frame::BulkString(arg.to_string().into()))
+                .collect();
+            self.write_args(args).await;
+        }
+
+        async fn write_args(&mut self, args: Vec<Frame>) {
+            self.conn.write_frame(&Frame::Array(args)).await.unwrap();
+        }
+
+        async fn read(&mut self) -> Frame {
+            if let Some(reply) = self.replies.pop_front() {
+                return reply;
+            }
+
+            let frames = tokio::time::timeout(REPLY_TIMEOUT, self.conn.read_frames())
+                .await
+                .expect("no reply in time")
+                .unwrap()
+                .expect("the server closed the connection");
+            self.replies
+                .extend(frames.into_iter().map(|(frame, _)| frame));
+
+            self.replies.pop_front().unwrap()
+        }
+
+        // whether the server closes the connection without sending anything more
+        async fn closed(&mut self) -> bool {
+            let read = tokio::time::timeout(REPLY_TIMEOUT, self.conn.read_frames()).await;
+
+            self.replies.is_empty() && matches!(read, Ok(Ok(None)) | Ok(Err(_)))
+        }
+    }
+
+    fn ok() -> Frame {
+        Frame::SimpleString("OK".to_string())
+    }
+
+    fn bulk(value: &str) -> Frame {
+        Frame::BulkString(value.to_string().into())
+    }
+
+    fn array(values: &[&str]) -> Frame {
+        Frame::Array(values.iter().map(|value| bulk(value)).collect())
+    }
+
+    // the elements of an array reply in lexicographic order, for sets and hashes
+    fn sorted(frame: Frame) -> Vec<Frame> {
+        let Frame::Array(mut values) = frame else {
+            panic!("not an array: {frame:?}");
+        };
+        values.sort_by_key(|value| format!("{value:?}"));
+
+        values
+    }
+
+    fn error(message: &str) -> Frame {
+        Frame::Error(message.to_string())
+    }
+
+    fn is_error(frame: &Frame, prefix: &str) -> bool {
+        matches!(frame, Frame::Error(message) if message.starts_with(prefix))
+    }
+
+    #[tokio::test]
+    async fn multibyte_values_come_back_byte_for_byte() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        assert_eq!(client.send("SET greeting héllo→🦀").await, ok());
+        assert_eq!(client.send("GET greeting").await, bulk("héllo→🦀"));
+        assert_eq!(client.send("STRLEN greeting").await, Frame::Integer(13));
+    }
+
+    #[tokio::test]
+    async fn binary_values_round_trip() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+        let value = Frame::BulkString(Bytes::from_static(b"a\x00b\xff"));
+
+        client
+            .write_args(vec![bulk("SET"), bulk("bin"), value.clone()])
+            .await;
+        assert_eq!(client.read().await, ok());
+        assert_eq!(client.send("GET bin").await, value);
+    }
+
+    #[tokio::test]
+    async fn values_may_start_like_a_snapshot() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        assert_eq!(client.send("SET k REDIS1234567").await, ok());
+        assert_eq!(client.send("GET k").await, bulk("REDIS1234567"));
+    }
+
+    #[tokio::test]
+    async fn incr_and_decr() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        assert_eq!(client.send("INCR counter").await, Frame::Integer(1));
+        assert_eq!(client.send("DECR counter").await, Frame::Integer(0));
+        assert_eq!(client.send("DECR fresh").await, Frame::Integer(-1));
+        assert_eq!(client.send("INCRBY counter 10").await, Frame::Integer(10));
+
+        client.send("SET word abc").await;
+        assert_eq!(
+            client.send("INCR word").await,
+            error("ERR value is not an integer or out of range")
+        );
+    }
+
+    #[tokio::test]
+    async fn incr_overflow_is_an_error() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        client.send("SET max 9223372036854775807").await;
+        assert!(is_error(&client.send("INCR max").await, "ERR"));
+        assert_eq!(client.send("GET max").await, bulk("9223372036854775807"));
+
+        client.send("SET min -9223372036854775808").await;
+        assert!(is_error(&client.send("DECR min").await, "ERR"));
+    }
+
+    #[tokio::test]
+    async fn ttl_of_missing_persistent_and_expiring_keys() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        assert_eq!(client.send("TTL missing").await, Frame::Integer(-2));
+        client.send("SET forever v").await;
+        assert_eq!(client.send("TTL forever").await, Frame::Integer(-1));
+        assert_eq!(client.send("PTTL forever").await, Frame::Integer(-1));
+
+        client.send("SET soon v EX 100").await;
+        assert_eq!(client.send("TTL soon").await, Frame::Integer(100));
+        let Frame::Integer(pttl) = client.send("PTTL soon").await else {
+            panic!("PTTL didn't reply with an integer");
+        };
+        assert!(pttl > 99_000 && pttl <= 100_000);
+    }
+
+    #[tokio::test]
+    async fn set_with_px_expires() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        assert_eq!(client.send("SET key v PX 50").await, ok());
+        assert_eq!(client.send("GET key").await, bulk("v"));
+
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert_eq!(client.send("GET key").await, Frame::NullBulkString);
+    }
+
+    #[tokio::test]
+    async fn deleting_an_expired_key_counts_for_nothing() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        assert_eq!(client.send("SET gone v PX 50").await, ok());
+        assert_eq!(client.send("SET kept v").await, ok());
+
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert_eq!(client.send("DEL gone kept").await, Frame::Integer(1));
+    }
+
+    #[tokio::test]
+    async fn expireat_in_the_past_and_future() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+        let now = SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        client.send("SET past v").await;
+        assert_eq!(
+            client.send(&format!("EXPIREAT past {}", now - 10)).await,
+            Frame::Integer(1)
+        );
+        assert_eq!(client.send("EXISTS past").await, Frame::Integer(0));
+
+        client.send("SET future v").await;
+        assert_eq!(
+            client
+                .send(&format!("PEXPIREAT future {}", (now + 100) * 1000))
+                .await,
+            Frame::Integer(1)
+        );
+        let Frame::Integer(ttl) = client.send("TTL future").await else {
+            panic!("TTL didn't reply with an integer");
+        };
+        assert!(ttl > 98 && ttl <= 100);
+
+        assert_eq!(
+            client.send(&format!("EXPIREAT missing {now}")).await,
+            Frame::Integer(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn getset_and_getdel() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        assert_eq!(client.send("GETSET key a").await, Frame::NullBulkString);
+        assert_eq!(client.send("GETSET key b").await, bulk("a"));
+        assert_eq!(client.send("GETDEL key").await, bulk("b"));
+        assert_eq!(client.send("GETDEL key").await, Frame::NullBulkString);
+        assert_eq!(client.send("EXISTS key").await, Frame::Integer(0));
+    }
+
+    #[tokio::test]
+    async fn setnx_and_setex() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        assert_eq!(client.send("SETNX key a").await, Frame::Integer(1));
+        assert_eq!(client.send("SETNX key b").await, Frame::Integer(0));
+        assert_eq!(client.send("GET key").await, bulk("a"));
+
+        assert_eq!(client.send("SETEX key 100 c").await, ok());
+        assert_eq!(client.send("GET key").await, bulk("c"));
+        assert_eq!(client.send("TTL key").await, Frame::Integer(100));
+        assert!(is_error(&client.send("SETEX key 0 c").await, "ERR"));
+        assert_eq!(
+            client.send("SETEX key 9223372036854775807 c").await,
+            error("ERR invalid expire time in 'setex' command")
+        );
+    }
+
+    #[tokio::test]
+    async fn commands_against_the_wrong_type() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        client.send("SET string v").await;
+        client.send("LPUSH list a").await;
+
+        for command in [
+            "LPUSH string a",
+            "SADD string a",
+            "HGET string f",
+            "ZADD string 1 a",
+            "GET list",
+            "INCR list",
+            "APPEND list x",
+        ] {
+            assert!(
+                is_error(&client.send(command).await, "WRONGTYPE"),
+                "{command}"
+            );
+        }
+
+        // commands that work on any type
+        assert_eq!(
+            client.send("TYPE list").await,
+            Frame::SimpleString("list".to_string())
+        );
+        assert_eq!(client.send("DEL list").await, Frame::Integer(1));
+        assert_eq!(client.send("SET string other").await, ok());
+    }
+
+    #[tokio::test]
+    async fn too_few_arguments_are_rejected_cleanly() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        for command in [
+            "GET",
+            "SET key",
+            "LPUSH list",
+            "HSET hash field",
+            "ZADD zset 1",
+            "REPLCONF",
+        ] {
+            let name = command.split_whitespace().next().unwrap().to_lowercase();
+            assert_eq!(
+                client.send(command).await,
+                error(&format!(
+                    "ERR wrong number of arguments for '{name}' command"
+                ))
+            );
+        }
+
+        // and the connection keeps working
+        assert_eq!(
+            client.send("PING").await,
+            Frame::SimpleString("PONG".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn object_encoding_of_strings() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        client.send("SET number 12345").await;
+        client.send("SET short hello").await;
+        client.send(&format!("SET long {}", "x".repeat(45))).await;
+
+        assert_eq!(client.send("OBJECT ENCODING number").await, bulk("int"));
+        assert_eq!(client.send("OBJECT ENCODING short").await, bulk("embstr"));
+        assert_eq!(client.send("OBJECT ENCODING long").await, bulk("raw"));
+        assert_eq!(
+            client.send("OBJECT ENCODING missing").await,
+            error("ERR no such key")
+        );
+    }
+
+    #[tokio::test]
+    async fn copy_with_and_without_replace() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        client.send("SET source a").await;
+        client.send("SET target b").await;
+
+        assert_eq!(client.send("COPY source target").await, Frame::Integer(0));
+        assert_eq!(client.send("GET target").await, bulk("b"));
+        assert_eq!(
+            client.send("COPY source target REPLACE").await,
+            Frame::Integer(1)
+        );
+        assert_eq!(client.send("GET target").await, bulk("a"));
+
+        assert_eq!(
+            client.send("COPY source other DB 1").await,
+            Frame::Integer(1)
+        );
+        client.send("SELECT 1").await;
+        assert_eq!(client.send("GET other").await, bulk("a"));
+        assert_eq!(client.send("EXISTS source").await, Frame::Integer(0));
+    }
+
+    #[tokio::test]
+    async fn pushes_and_ranges() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        assert_eq!(client.send("RPUSH list b c").await, Frame::Integer(2));
+        assert_eq!(client.send("LPUSH list a").await, Frame::Integer(3));
+
+        assert_eq!(
+            client.send("LRANGE list 0 -1").await,
+            array(&["a", "b", "c"])
+        );
+        assert_eq!(client.send("LRANGE list -2 -1").await, array(&["b", "c"]));
+        assert_eq!(client.send("LRANGE list 1 100").await, array(&["b", "c"]));
+        assert_eq!(client.send("LRANGE list -100 0").await, array(&["a"]));
+        assert_eq!(client.send("LRANGE list 5 10").await, array(&[]));
+        assert_eq!(client.send("LRANGE list 2 1").await, array(&[]));
+        assert_eq!(client.send("LRANGE missing 0 -1").await, array(&[]));
+    }
+
+    #[tokio::test]
+    async fn llen_and_lindex() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        client.send("RPUSH list a b c").await;
+
+        assert_eq!(client.send("LLEN list").await, Frame::Integer(3));
+        assert_eq!(client.send("LLEN missing").await, Frame::Integer(0));
+        assert_eq!(client.send("LINDEX list 0").await, bulk("a"));
+        assert_eq!(client.send("LINDEX list -1").await, bulk("c"));
+        assert_eq!(client.send("LINDEX list 3").await, Frame::NullBulkString);
+    }
+
+    #[tokio::test]
+    async fn pops_remove_the_empty_list() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        client.send("RPUSH list a b").await;
+
+        assert_eq!(client.send("LPOP list").await, bulk("a"));
+        assert_eq!(client.send("RPOP list").await, bulk("b"));
+        assert_eq!(client.send("LPOP list").await, Frame::NullBulkString);
+        assert_eq!(client.send("EXISTS list").await, Frame::Integer(0));
+    }
+
+    #[tokio::test]
+    async fn blpop_wakes_up_on_a_push_from_another_client() {
+        let (_, addr) = start(&[]).await;
+        let mut blocked = Client::connect(addr).await;
+        let mut pusher = Client::connect(addr).await;
+
+        blocked.write("BLPOP queue 0").await;
+        // give the BLPOP time to register before pushing
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(pusher.send("RPUSH queue job").await, Frame::Integer(1));
+
+        assert_eq!(blocked.read().await, array(&["queue", "job"]));
+        assert_eq!(pusher.send("LLEN queue").await, Frame::Integer(0));
+    }
+
+    #[tokio::test]
+    async fn blpop_returns_at_once_or_times_out() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        client.send("RPUSH queue a b").await;
+        assert_eq!(
+            client.send("BRPOP empty queue 1").await,
+            array(&["queue", "b"])
+        );
+
+        assert_eq!(client.send("BLPOP empty 0.05").await, Frame::NullArray);
+    }
+
+    #[tokio::test]
+    async fn hashes() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        assert_eq!(
+            client.send("HSET user name ann age 30").await,
+            Frame::Integer(2)
+        );
+        assert_eq!(client.send("HSET user age 31").await, Frame::Integer(0));
+
+        assert_eq!(client.send("HGET user age").await, bulk("31"));
+        assert_eq!(
+            client.send("HGET user missing").await,
+            Frame::NullBulkString
+        );
+        assert_eq!(
+            client.send("HGET missing name").await,
+            Frame::NullBulkString
+        );
+        assert_eq!(client.send("HLEN user").await, Frame::Integer(2));
+        let Frame::Array(pairs) = client.send("HGETALL user").await else {
+            panic!("HGETALL didn't reply with an array");
+        };
+        let mut pairs: Vec<_> = pairs.chunks(2).map(|pair| pair.to_vec()).collect();
+        pairs.sort_by_key(|pair| format!("{pair:?}"));
+        assert_eq!(
+            pairs,
+            vec![
+                vec![bulk("age"), bulk("31")],
+                vec![bulk("name"), bulk("ann")]
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn duplicate_set_members_are_counted_once() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        assert_eq!(client.send("SADD set a b a").await, Frame::Integer(2));
+        assert_eq!(client.send("SADD set b c").await, Frame::Integer(1));
+        assert_eq!(client.send("SCARD set").await, Frame::Integer(3));
+
+        assert_eq!(client.send("SISMEMBER set a").await, Frame::Integer(1));
+        assert_eq!(client.send("SISMEMBER set z").await, Frame::Integer(0));
+        assert_eq!(client.send("SREM set a z").await, Frame::Integer(1));
+        assert_eq!(
+            sorted(client.send("SMEMBERS set").await),
+            vec![bulk("b"), bulk("c")]
+        );
+    }
+
+    #[tokio::test]
+    async fn set_operations_over_three_sets() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        client.send("SADD first a b c d").await;
+        client.send("SADD second b c e").await;
+        client.send("SADD third c d e").await;
+
+        assert_eq!(
+            sorted(client.send("SINTER first second third").await),
+            vec![bulk("c")]
+        );
+        assert_eq!(
+            sorted(client.send("SUNION first second third").await),
+            ["a", "b", "c", "d", "e"].map(bulk)
+        );
+        assert_eq!(
+            sorted(client.send("SDIFF first second third").await),
+            vec![bulk("a")]
+        );
+        assert_eq!(client.send("SINTER first missing").await, array(&[]));
+    }
+
+    #[tokio::test]
+    async fn updating_a_score_changes_the_rank() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        assert_eq!(
+            client.send("ZADD board 10 ann 20 bob 30 cid").await,
+            Frame::Integer(3)
+        );
+        assert_eq!(client.send("ZRANK board ann").await, Frame::Integer(0));
+
+        assert_eq!(client.send("ZADD board 40 ann").await, Frame::Integer(0));
+        assert_eq!(client.send("ZRANK board ann").await, Frame::Integer(2));
+        assert_eq!(client.send("ZRANK board bob").await, Frame::Integer(0));
+        assert_eq!(client.send("ZSCORE board ann").await, bulk("40"));
+        assert_eq!(
+            client.send("ZSCORE board nobody").await,
+            Frame::NullBulkString
+        );
+        assert_eq!(
+            client.send("ZRANK board nobody").await,
+            Frame::NullBulkString
+        );
+    }
+
+    #[tokio::test]
+    async fn zrange_and_zrangebyscore() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        client.send("ZADD board 1 a 2 b 3 c 4.5 d").await;
+
+        assert_eq!(
+            client.send("ZRANGE board 0 -1").await,
+            array(&["a", "b", "c", "d"])
+        );
+        assert_eq!(client.send("ZRANGE board -2 10").await, array(&["c", "d"]));
+        assert_eq!(
+            client.send("ZRANGE board 0 0 WITHSCORES").await,
+            array(&["a", "1"])
+        );
+
+        assert_eq!(
+            client.send("ZRANGEBYSCORE board (1 3").await,
+            array(&["b", "c"])
+        );
+        assert_eq!(
+            client.send("ZRANGEBYSCORE board -inf +inf").await,
+            array(&["a", "b", "c", "d"])
+        );
+        assert_eq!(
+            client.send("ZRANGEBYSCORE board (4 inf WITHSCORES").await,
+            array(&["d", "4.5"])
+        );
+        assert_eq!(client.send("ZRANGEBYSCORE board (3 (3").await, array(&[]));
+    }
+
+    #[tokio::test]
+    async fn scan_visits_every_key_once() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        for i in 0..1000 {
+            client.send(&format!("SET key:{i} v")).await;
+        }
+
+        let mut cursor = "0".to_string();
+        let mut seen = vec![];
+        loop {
+            let Frame::Array(reply) = client.send(&format!("SCAN {cursor} COUNT 100")).await else {
+                panic!("SCAN didn't reply with an array");
+            };
+            let [Frame::BulkString(next_cursor), Frame::Array(keys)] = &reply[..] else {
+                panic!("unexpected SCAN reply: {reply:?}");
+            };
+
+            seen.extend(keys.iter().cloned());
+            cursor = String::from_utf8(next_cursor.to_vec()).unwrap();
+            if cursor == "0" {
+                break;
+            }
+        }
+
+        seen.sort_by_key(|key| format!("{key:?}"));
+        seen.dedup();
+        assert_eq!(seen.len(), 1000);
+    }
+
+    #[tokio::test]
+    async fn hscan_and_sscan() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        client.send("HSET hash a 1 b 2").await;
+        client.send("SADD set x y").await;
+
+        let Frame::Array(reply) = client.send("HSCAN hash 0 MATCH a").await else {
+            panic!("HSCAN didn't reply with an array");
+        };
+        assert_eq!(reply, vec![bulk("0"), array(&["a", "1"])]);
+
+        let Frame::Array(reply) = client.send("SSCAN set 0").await else {
+            panic!("SSCAN didn't reply with an array");
+        };
+        assert_eq!(reply[0], bulk("0"));
+        assert_eq!(sorted(reply[1].clone()), vec![bulk("x"), bulk("y")]);
+
+        assert_eq!(
+            client.send("SSCAN missing 0").await,
+            Frame::Array(vec![bulk("0"), array(&[])])
+        );
+    }
+
+    fn queued() -> Frame {
+        Frame::SimpleString("QUEUED".to_string())
+    }
+
+    #[tokio::test]
+    async fn exec_runs_the_queued_commands() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        assert_eq!(client.send("MULTI").await, ok());
+        assert_eq!(client.send("SET a 1").await, queued());
+        assert_eq!(client.send("SET b 2").await, queued());
+        assert_eq!(client.send("INCR a").await, queued());
+        assert_eq!(
+            client.send("EXEC").await,
+            Frame::Array(vec![ok(), ok(), Frame::Integer(2)])
+        );
+
+        assert_eq!(client.send("MGET a b").await, array(&["2", "2"]));
+    }
+
+    #[tokio::test]
+    async fn discard_drops_the_queue() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        client.send("MULTI").await;
+        client.send("SET a 1").await;
+        assert_eq!(client.send("DISCARD").await, ok());
+        assert_eq!(client.send("GET a").await, Frame::NullBulkString);
+
+        assert_eq!(client.send("EXEC").await, error("ERR EXEC without MULTI"));
+        assert_eq!(
+            client.send("DISCARD").await,
+            error("ERR DISCARD without MULTI")
+        );
+    }
+
+    #[tokio::test]
+    async fn commands_that_block_are_refused_inside_multi() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+        let mut other = Client::connect(addr).await;
+
+        let refused = error("ERR Command not allowed inside a transaction");
+        client.send("MULTI").await;
+        assert_eq!(client.send("PSYNC ? -1").await, refused);
+        assert_eq!(client.send("BLPOP queue 0").await, refused);
+        assert_eq!(client.send("WAIT 1 0").await, refused);
+        assert_eq!(client.send("SUBSCRIBE news").await, refused);
+        assert_eq!(client.send("SET key value").await, queued());
+        assert_eq!(client.send("EXEC").await, Frame::Array(vec![ok()]));
+
+        // and the server isn't stuck holding every database
+        assert_eq!(
+            other.send("PING").await,
+            Frame::SimpleString("PONG".to_string())
+        );
+        assert_eq!(other.send("GET key").await, bulk("value"));
+    }
+
+    #[tokio::test]
+    async fn a_write_to_a_watched_key_aborts_exec() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+        let mut other = Client::connect(addr).await;
+
+        client.send("SET balance 10").await;
+        assert_eq!(client.send("WATCH balance").await, ok());
+        other.send("SET balance 20").await;
+
+        client.send("MULTI").await;
+        client.send("SET balance 0").await;
+        assert_eq!(client.send("EXEC").await, Frame::NullArray);
+        assert_eq!(client.send("GET balance").await, bulk("20"));
+
+        // the watch ended with that EXEC
+        client.send("MULTI").await;
+        client.send("SET balance 0").await;
+        assert_eq!(client.send("EXEC").await, Frame::Array(vec![ok()]));
+    }
+
+    #[tokio::test]
+    async fn deleting_or_flushing_a_watched_key_aborts_exec() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+        let mut other = Client::connect(addr).await;
+
+        for write in ["DEL key", "FLUSHDB", "FLUSHALL"] {
+            client.send("SET key v").await;
+            client.send("WATCH key").await;
+            other.send(write).await;
+
+            client.send("MULTI").await;
+            client.send("SET key w").await;
+            assert_eq!(client.send("EXEC").await, Frame::NullArray, "{write}");
+        }
+    }
+
+    #[tokio::test]
+    async fn unwatch_forgets_the_watched_keys() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+        let mut other = Client::connect(addr).await;
+
+        client.send("WATCH key").await;
+        other.send("SET key v").await;
+        assert_eq!(client.send("UNWATCH").await, ok());
+
+        client.send("MULTI").await;
+        client.send("GET key").await;
+        assert_eq!(client.send("EXEC").await, Frame::Array(vec![bulk("v")]));
+    }
+
+    #[tokio::test]
+    async fn reset_clears_a_transaction() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        client.send("SELECT 1").await;
+        client.send("MULTI").await;
+        client.send("SET a 1").await;
+        assert_eq!(
+            client.send("RESET").await,
+            Frame::SimpleString("RESET".to_string())
+        );
+
+        assert_eq!(client.send("EXEC").await, error("ERR EXEC without MULTI"));
+        assert_eq!(client.send("GET a").await, Frame::NullBulkString);
+        client.send("SELECT 1").await;
+        assert_eq!(client.send("GET a").await, Frame::NullBulkString);
+    }
+
+    fn subscribed(kind: &str, channel: &str, count: i64) -> Frame {
+        Frame::Array(vec![bulk(kind), bulk(channel), Frame::Integer(count)])
+    }
+
+    #[tokio::test]
+    async fn a_message_reaches_every_subscriber() {
+        let (_, addr) = start(&[]).await;
+        let mut first = Client::connect(addr).await;
+        let mut second = Client::connect(addr).await;
+        let mut publisher = Client::connect(addr).await;
+
+        assert_eq!(
+            first.send("SUBSCRIBE news").await,
+            subscribed("subscribe", "news", 1)
+        );
+        assert_eq!(
+            second.send("PSUBSCRIBE n*").await,
+            subscribed("psubscribe", "n*", 1)
+        );
+
+        assert_eq!(
+            publisher.send("PUBLISH news hello").await,
+            Frame::Integer(2)
+        );
+        assert_eq!(
+            publisher.send("PUBLISH other hello").await,
+            Frame::Integer(0)
+        );
+
+        assert_eq!(first.read().await, array(&["message", "news", "hello"]));
+        assert_eq!(
+            second.read().await,
+            array(&["pmessage", "n*", "news", "hello"])
+        );
+    }
+
+    #[tokio::test]
+    async fn a_pattern_only_gets_the_channels_it_matches() {
+        let (_, addr) = start(&[]).await;
+        let mut news = Client::connect(addr).await;
+        let mut weather = Client::connect(addr).await;
+        let mut publisher = Client::connect(addr).await;
+
+        news.send("PSUBSCRIBE news.*").await;
+        weather.send("PSUBSCRIBE weather.*").await;
+
+        assert_eq!(
+            publisher.send("PUBLISH news.sports goal").await,
+            Frame::Integer(1)
+        );
+        assert_eq!(
+            news.read().await,
+            array(&["pmessage", "news.*", "news.sports", "goal"])
+        );
+
+        // messages arrive in order, so this being the first one
+        // means nothing was delivered for news.sports
+        publisher.send("PUBLISH weather.today sun").await;
+        assert_eq!(
+            weather.read().await,
+            array(&["pmessage", "weather.*", "weather.today", "sun"])
+        );
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_stops_the_messages() {
+        let (_, addr) = start(&[]).await;
+        let mut subscriber = Client::connect(addr).await;
+        let mut publisher = Client::connect(addr).await;
+
+        subscriber.send("SUBSCRIBE a b").await;
+        assert_eq!(subscriber.read().await, subscribed("subscribe", "b", 2));
+        assert_eq!(
+            subscriber.send("UNSUBSCRIBE a").await,
+            subscribed("unsubscribe", "a", 1)
+        );
+
+        assert_eq!(publisher.send("PUBLISH a hello").await, Frame::Integer(0));
+        assert_eq!(publisher.send("PUBLISH b hello").await, Frame::Integer(1));
+        assert_eq!(subscriber.read().await, array(&["message", "b", "hello"]));
+    }
+
+    #[tokio::test]
+    async fn only_subscribe_commands_are_allowed_while_subscribed() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        client.send("SUBSCRIBE news").await;
+
+        assert_eq!(
+            client.send("GET key").await,
+            error(
+                "ERR Can't execute 'get': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT / \
+                 RESET are allowed in this context"
+            )
+        );
+        assert_eq!(client.send("PING").await, array(&["pong", ""]));
+
+        client.send("UNSUBSCRIBE").await;
+        assert_eq!(client.send("GET key").await, Frame::NullBulkString);
+    }
+
+    #[tokio::test]
+    async fn resp3_subscribers_get_pushes_and_may_run_anything() {
+        let (_, addr) = start(&[]).await;
+        let mut subscriber = Client::connect(addr).await;
+        let mut publisher = Client::connect(addr).await;
+
+        subscriber.send("HELLO 3").await;
+        assert_eq!(
+            subscriber.send("SUBSCRIBE news").await,
+            Frame::Push(vec![bulk("subscribe"), bulk("news"), Frame::Integer(1)])
+        );
+        assert_eq!(subscriber.send("GET key").await, Frame::NullBulkString);
+
+        publisher.send("PUBLISH news hello").await;
+        assert_eq!(
+            subscriber.read().await,
+            Frame::Push(vec![bulk("message"), bulk("news"), bulk("hello")])
+        );
+    }
+
+    #[tokio::test]
+    async fn quit_closes_the_connection() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        // the SET after QUIT is never run
+        client.conn.write(b"QUIT\r\nSET key v\r\n").await.unwrap();
+        assert_eq!(client.read().await, ok());
+        assert!(client.closed().await);
+
+        let mut other = Client::connect(addr).await;
+        assert_eq!(other.send("GET key").await, Frame::NullBulkString);
+    }
+
+    fn text(frame: Frame) -> String {
+        let Frame::BulkString(text) = frame else {
+            panic!("not a bulk string: {frame:?}");
+        };
+
+        String::from_utf8(text.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn info_sections() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        let server = text(client.send("INFO server").await);
+        assert!(server.starts_with("# Server\r\n"));
+        assert!(server.contains("run_id:"));
+        assert!(!server.contains("# Replication") && !server.contains("role:"));
+
+        let replication = text(client.send("INFO REPLICATION").await);
+        assert!(replication.starts_with("# Replication\r\n"));
+        assert!(replication.contains("role:master"));
+
+        let all = text(client.send("INFO").await);
+        assert!(all.contains("# Server") && all.contains("# Replication"));
+    }
+
+    #[tokio::test]
+    async fn command_count_matches_the_table() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        assert_eq!(
+            client.send("COMMAND COUNT").await,
+            Frame::Integer(commands::COMMANDS.len() as i64)
+        );
+        let Frame::Array(info) = client.send("COMMAND INFO get nosuchcommand").await else {
+            panic!("COMMAND INFO didn't reply with an array");
+        };
+        assert!(matches!(&info[0], Frame::Array(spec) if spec[0] == bulk("get")));
+        assert_eq!(info[1], Frame::NullArray);
+    }
+
+    #[tokio::test]
+    async fn debug_sleep_and_jmap() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        let started = std::time::Instant::now();
+        assert_eq!(client.send("DEBUG SLEEP 0.05").await, ok());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        for seconds in ["1e300", "inf", "-1"] {
+            assert_eq!(
+                client.send(&format!("DEBUG SLEEP {seconds}")).await,
+                error("ERR value is not a valid float")
+            );
+        }
+        assert_eq!(client.send("DEBUG JMAP").await, ok());
+    }
+
+    #[tokio::test]
+    async fn garbage_gets_an_error_before_the_close() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        client.conn.write(b"*1\r\n$x\r\n").await.unwrap();
+
+        assert!(is_error(&client.read().await, "ERR Protocol error"));
+        assert!(client.closed().await);
+    }
+
+    #[tokio::test]
+    async fn inline_commands_are_run() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        client
+            .conn
+            .write(b"SET foo bar\r\nGET foo\r\n")
+            .await
+            .unwrap();
+
+        assert_eq!(client.read().await, ok());
+        assert_eq!(client.read().await, bulk("bar"));
+    }
+
+    #[tokio::test]
+    async fn expired_keys_nobody_reads_are_swept() {
+        let (server, addr) = start(&["--hz", "100"]).await;
+        let mut client = Client::connect(addr).await;
+
+        client.send("SET stay v").await;
+        for i in 0..50 {
+            client.send(&format!("SET key:{i} v PX 200")).await;
+        }
+        assert_eq!(client.send("DBSIZE").await, Frame::Integer(51));
+
+        // DBSIZE leaves expired keys out, so look at what's still stored
+        let started = std::time::Instant::now();
+        while server.dbs[0].lock().await.len() > 1 {
+            assert!(
+                started.elapsed() < REPLY_TIMEOUT,
+                "expired keys are never swept"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(client.send("DBSIZE").await, Frame::Integer(1));
+    }
+
+    #[tokio::test]
+    async fn a_missing_rdb_file_starts_empty() {
+        let dir = std::env::temp_dir().to_string_lossy().to_string();
+        let (_, addr) = start(&["--dir", &dir, "--dbfilename", "no-such-file.rdb"]).await;
+        let mut client = Client::connect(addr).await;
+
+        assert_eq!(client.send("DBSIZE").await, Frame::Integer(0));
+        assert_eq!(client.send("SET key v").await, ok());
+    }
+
+    #[tokio::test]
+    async fn save_and_load_every_database() {
+        let dir = std::env::temp_dir().to_string_lossy().to_string();
+        let dbfilename = format!("save-test-{}.rdb", std::process::id());
+        let args = ["--dir", &dir, "--dbfilename", &dbfilename];
+
+        let (_, addr) = start(&args).await;
+        let mut client = Client::connect(addr).await;
+        client.send("SET a 1").await;
+        client.send("SELECT 3").await;
+        client.send("RPUSH b x y").await;
+        client.send("SET c v EX 100").await;
+        assert_eq!(client.send("SAVE").await, ok());
+
+        let (_, addr) = start(&args).await;
+        let mut client = Client::connect(addr).await;
+        assert_eq!(client.send("GET a").await, bulk("1"));
+        client.send("SELECT 3").await;
+        assert_eq!(client.send("LRANGE b 0 -1").await, array(&["x", "y"]));
+        assert_eq!(client.send("TTL c").await, Frame::Integer(100));
+
+        std::fs::remove_file(Path::new(&dir).join(dbfilename)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_keys_make_room() {
+        let (_, addr) = start(&["--maxmemory", "4000", "--maxmemory-policy", "allkeys-lru"]).await;
+        let mut client = Client::connect(addr).await;
+        let value = "x".repeat(100);
+
+        for i in 0..100 {
+            assert_eq!(client.send(&format!("SET key:{i} {value}")).await, ok());
+        }
+
+        let Frame::Integer(dbsize) = client.send("DBSIZE").await else {
+            panic!("DBSIZE didn't reply with an integer");
+        };
+        assert!(dbsize < 100);
+        assert_eq!(client.send("EXISTS key:0").await, Frame::Integer(0));
+        assert_eq!(client.send("EXISTS key:99").await, Frame::Integer(1));
+    }
+
+    #[tokio::test]
+    async fn writes_over_maxmemory_are_refused_without_a_policy() {
+        let (_, addr) = start(&["--maxmemory", "1000"]).await;
+        let mut client = Client::connect(addr).await;
+
+        let reply = client.send(&format!("SET key {}", "x".repeat(2000))).await;
+        assert!(is_error(&reply, "OOM"));
+        assert_eq!(client.send("GET key").await, Frame::NullBulkString);
+    }
+
+    #[tokio::test]
+    async fn maxmemory_counts_every_db_and_every_write() {
+        let (_, addr) = start(&["--maxmemory", "1000"]).await;
+        let mut client = Client::connect(addr).await;
+        let value = "x".repeat(600);
+
+        client.send("SELECT 1").await;
+        assert_eq!(client.send(&format!("SET key {value}")).await, ok());
+        client.send("SELECT 0").await;
+
+        for write in [
+            format!("LPUSH list {value}"),
+            format!("HSET hash field {value}"),
+            format!("SADD set {value}"),
+            format!("ZADD zset 1 {value}"),
+            format!("APPEND key {value}"),
+        ] {
+            assert!(is_error(&client.send(&write).await, "OOM"), "{write}");
+        }
+        // a write that frees memory still goes through, and makes room
+        client.send("SELECT 1").await;
+        assert_eq!(client.send("DEL key").await, Frame::Integer(1));
+        client.send("SELECT 0").await;
+        assert_eq!(
+            client.send(&format!("LPUSH list {value}")).await,
+            Frame::Integer(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn auth_with_a_right_and_wrong_password() {
+        let (_, addr) = start(&["--requirepass", "secret"]).await;
+        let mut client = Client::connect(addr).await;
+
+        assert!(is_error(&client.send("GET key").await, "NOAUTH"));
+        assert!(is_error(&client.send("AUTH wrong").await, "WRONGPASS"));
+        assert!(is_error(&client.send("GET key").await, "NOAUTH"));
+
+        assert_eq!(client.send("AUTH secret").await, ok());
+        assert_eq!(client.send("GET key").await, Frame::NullBulkString);
+        assert_eq!(client.send("AUTH default secret").await, ok());
+    }
+
+    #[tokio::test]
+    async fn idle_clients_are_dropped() {
+        let (_, addr) = start(&["--timeout", "1"]).await;
+        let started = std::time::Instant::now();
+        let mut idle = Client::connect(addr).await;
+        let mut subscriber = Client::connect(addr).await;
+        subscriber.send("SUBSCRIBE news").await;
+
+        assert!(idle.closed().await);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+
+        // subscribers are only listening, so they may stay
+        let mut publisher = Client::connect(addr).await;
+        assert_eq!(publisher.send("PUBLISH news hi").await, Frame::Integer(1));
+    }
+
+    #[tokio::test]
+    async fn clients_past_maxclients_are_turned_away() {
+        let (_, addr) = start(&["--maxclients", "2"]).await;
+        let mut first = Client::connect(addr).await;
+        let mut second = Client::connect(addr).await;
+        first.send("PING").await;
+        second.send("PING").await;
+
+        let mut third = Client::connect(addr).await;
+        assert_eq!(
+            third.read().await,
+            error("ERR max number of clients reached")
+        );
+        assert!(third.closed().await);
+
+        // the slot is given back once a client leaves
+        drop(first);
+        let deadline = std::time::Instant::now() + REPLY_TIMEOUT;
+        loop {
+            let mut next = Client::connect(addr).await;
+            if next.send("PING").await == Frame::SimpleString("PONG".to_string()) {
+                break;
+            }
+            assert!(
+                std::time::Instant::now() < deadline,
+                "the slot was never given back"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn client_names() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        assert_eq!(client.send("CLIENT GETNAME").await, Frame::NullBulkString);
+        assert_eq!(client.send("CLIENT SETNAME worker-1").await, ok());
+        assert_eq!(client.send("CLIENT GETNAME").await, bulk("worker-1"));
+        client
+            .write_args(vec![bulk("CLIENT"), bulk("SETNAME"), bulk("a b")])
+            .await;
+        assert!(is_error(&client.read().await, "ERR"));
+        assert_eq!(
+            client.send("CLIENT SETNAME a b").await,
+            error("ERR wrong number of arguments for 'client|setname' command")
+        );
+
+        let list = text(client.send("CLIENT LIST").await);
+        assert!(list.contains("name=worker-1"));
+    }
+
+    #[tokio::test]
+    async fn client_reply_off_and_skip() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        client.write("CLIENT REPLY OFF").await;
+        client.write("SET a 1").await;
+        assert_eq!(client.send("CLIENT REPLY ON").await, ok());
+
+        client.write("CLIENT REPLY SKIP").await;
+        client.write("SET b 2").await;
+        assert_eq!(client.send("MGET a b").await, array(&["1", "2"]));
+
+        assert_eq!(client.send("CLIENT NO-EVICT on").await, ok());
+        assert_eq!(client.send("CLIENT NO-TOUCH off").await, ok());
+    }
+
+    async fn start_replica(master: SocketAddr) -> (Arc<RedisServer>, SocketAddr) {
+        start(&["--replicaof", &format!("127.0.0.1 {}", master.port())]).await
+    }
+
+    // keeps sending the command until it gets the expected reply,
+    // for whatever reaches a replica only after a while
+    async fn eventually(client: &mut Client, command: &str, expected: Frame) {
+        let started = std::time::Instant::now();
+
+        loop {
+            let reply = client.send(command).await;
+            if reply == expected {
+                return;
+            }
+
+            assert!(
+                started.elapsed() < REPLY_TIMEOUT,
+                "{command} still replies {reply:?}"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    async fn info_field(client: &mut Client, name: &str) -> String {
+        let info = text(client.send("INFO replication").await);
+
+        info.lines()
+            .find_map(|line| line.strip_prefix(&format!("{name}:")))
+            .unwrap_or_else(|| panic!("no {name} in {info}"))
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn writes_without_replicas_move_the_offset() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        assert_eq!(info_field(&mut client, "master_repl_offset").await, "0");
+        assert_eq!(client.send("SET a 1").await, ok());
+        assert_eq!(info_field(&mut client, "master_repl_offset").await, "27");
+        assert_eq!(client.send("SET b 2").await, ok());
+        assert_eq!(info_field(&mut client, "master_repl_offset").await, "54");
+
+        // reads aren't propagated
+        client.send("GET a").await;
+        assert_eq!(info_field(&mut client, "master_repl_offset").await, "54");
+    }
+
+    #[tokio::test]
+    async fn a_replica_gets_the_snapshot_and_the_writes_after_it() {
+        let (_, master) = start(&[]).await;
+        let mut client = Client::connect(master).await;
+        client.send("SET before v").await;
+        client.send("SELECT 2").await;
+        client.send("SET other db").await;
+
+        let (_, replica) = start_replica(master).await;
+        let mut reader = Client::connect(replica).await;
+        eventually(&mut reader, "GET before", bulk("v")).await;
+        reader.send("SELECT 2").await;
+        eventually(&mut reader, "GET other", bulk("db")).await;
+
+        client.send("SET after w").await;
+        client.send("SELECT 0").await;
+        client.send("RPUSH list a b").await;
+        eventually(&mut reader, "GET after", bulk("w")).await;
+        reader.send("SELECT 0").await;
+        eventually(&mut reader, "LRANGE list 0 -1", array(&["a", "b"])).await;
+
+        assert_eq!(info_field(&mut reader, "role").await, "slave");
+        assert_eq!(info_field(&mut reader, "master_link_status").await, "up");
+    }
+
+    #[tokio::test]
+    async fn a_replica_only_takes_writes_from_its_master() {
+        let (_, master) = start(&[]).await;
+        let (_, replica) = start_replica(master).await;
+        let mut client = Client::connect(master).await;
+        let mut reader = Client::connect(replica).await;
+
+        eventually(&mut reader, "DBSIZE", Frame::Integer(0)).await;
+        assert_eq!(
+            reader.send("SET key v").await,
+            error("READONLY You can't write against a read only replica.")
+        );
+
+        client.send("SET key v").await;
+        eventually(&mut reader, "GET key", bulk("v")).await;
+    }
+
+    #[tokio::test]
+    async fn wait_with_a_negative_timeout_is_an_error() {
+        let (_, addr) = start(&[]).await;
+        let mut client = Client::connect(addr).await;
+
+        assert_eq!(
+            client.send("WAIT 1 -1").await,
+            error("ERR timeout is negative")
+        );
+        assert_eq!(client.send("WAIT 0 0").await, Frame::Integer(0));
+    }
+
+    #[tokio::test]
+    async fn replica_offsets_catch_up_with_the_master() {
+        let (_, master) = start(&[]).await;
+        let (_, replica) = start_replica(master).await;
+        let mut client = Client::connect(master).await;
+        let mut reader = Client::connect(replica).await;
+
+        eventually(&mut client, "WAIT 1 0", Frame::Integer(1)).await;
+        for i in 0..5 {
+            client.send(&format!("SET key:{i} v")).await;
+        }
+
+        let offset = info_field(&mut client, "master_repl_offset").await;
+        assert_ne!(offset, "0");
+
+        // the replica acknowledges the writes, so WAIT doesn't have to time out
+        assert_eq!(client.send("WAIT 1 5000").await, Frame::Integer(1));
+        assert!(info_field(&mut client, "slave0")
+            .await
+            .ends_with(&format!("offset={offset},lag=0")));
+
+        // the GETACK that WAIT sent counts too, once the replica has processed it
+        let offset = info_field(&mut client, "master_repl_offset").await;
+        let mut replica_offset = String::new();
+        for _ in 0..100 {
+            replica_offset = info_field(&mut reader, "slave_repl_offset").await;
+            if replica_offset == offset {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(replica_offset, offset);
+    }
+
+    #[tokio::test]
+    async fn every_replica_ends_up_with_every_write() {
+        let (_, master) = start(&[]).await;
+        let (_, first) = start_replica(master).await;
+        let (_, second) = start_replica(master).await;
+        let mut client = Client::connect(master).await;
+
+        eventually(&mut client, "WAIT 2 0", Frame::Integer(2)).await;
+        for i in 0..1000 {
+            client.write(&format!("SET key:{i} {i}")).await;
+        }
+        for _ in 0..1000 {
+            assert_eq!(client.read().await, ok());
+        }
+
+        for replica in [first, second] {
+            let mut reader = Client::connect(replica).await;
+            eventually(&mut reader, "DBSIZE", Frame::Integer(1000)).await;
+            assert_eq!(reader.send("GET key:999").await, bulk("999"));
+        }
+    }
+
+    #[tokio::test]
+    async fn a_reconnecting_replica_gets_only_what_it_missed() {
+        let (_, master) = start(&[]).await;
+        let mut client = Client::connect(master).await;
+        client.send("SET a 1").await;
+        let replid = info_field(&mut client, "master_replid").await;
+        client.send("SET b 2").await;
+        client.send("SET c 3").await;
+
+        // as a replica that had applied the first write would ask
+        let mut replica = Client::connect(master).await;
+        replica.send("REPLCONF listening-port 6380").await;
+        assert_eq!(
+            replica.send(&format!("PSYNC {replid} 28")).await,
+            Frame::SimpleString(format!("CONTINUE {replid}"))
+        );
+        assert_eq!(replica.read().await, array(&["SET", "b", "2"]));
+        assert_eq!(replica.read().await, array(&["SET", "c", "3"]));
+
+        // and one replicating from someone else needs a snapshot
+        let mut stranger = Client::connect(master).await;
+        let Frame::SimpleString(reply) = stranger.send("PSYNC otherid 28").await else {
+            panic!("PSYNC didn't reply with a simple string");
+        };
+        assert!(reply.starts_with(&format!("FULLRESYNC {replid} ")));
+    }
+
+    #[tokio::test]
+    async fn a_replica_reconnects_when_the_link_drops() {
+        let master = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (_, replica) = start_replica(master.local_addr().unwrap()).await;
+        let replid = "a".repeat(40);
+        let rdb = rdb::encode_rdb(&[]);
+
+        let mut psyncs = vec![];
+        for attempt in 1..=2 {
+            let (stream, _) = tokio::time::timeout(REPLY_TIMEOUT, master.accept())
+                .await
+                .expect("the replica didn't reconnect")
+                .unwrap();
+            let mut link = Connection::new(stream);
+
+            // PING, the two REPLCONFs and PSYNC, each one waiting for its reply
+            while psyncs.len() < attempt {
+                for (frame, _) in link.read_frames().await.unwrap().unwrap() {
+                    let (command, args) = extract_command(frame).unwrap();
+
+                    match command.as_str() {
+                        "PING" => link.write(b"+PONG\r\n").await.unwrap(),
+                        "REPLCONF" => link.write(b"+OK\r\n").await.unwrap(),
+                        "PSYNC" => {
+                            psyncs.push(args);
+                            link.write(format!("+FULLRESYNC {replid} 0\r\n").as_bytes())
+                                .await
+                                .unwrap();
+                            link.write(format!("${}\r\n", rdb.len()).as_bytes())
+                                .await
+                                .unwrap();
+                            link.write(&rdb).await.unwrap();
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            // dropping the link here makes the replica reconnect
+        }
+
+        assert_eq!(psyncs[0], vec![bulk("?"), bulk("-1")]);
+        assert_eq!(psyncs[1], vec![bulk(&replid), bulk("1")]);
+
+        let mut reader = Client::connect(replica).await;
+        assert_eq!(
+            reader.send("PING").await,
+            Frame::SimpleString("PONG".to_string())
+        );
     }
 }
@@ -0,0 +1,124 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+// members kept ordered by score, ties broken by comparing the members,
+// along with a lookup from member to score
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SortedSet {
+    scores: HashMap<String, f64>,
+    ordered: Vec<(f64, String)>,
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // returns true when the member wasn't in the set before
+    pub fn insert(&mut self, member: String, score: f64) -> bool {
+        let is_new = match self.scores.insert(member.clone(), score) {
+            Some(old_score) => {
+                let index = self.position(old_score, &member).unwrap();
+                self.ordered.remove(index);
+                false
+            }
+            None => true,
+        };
+
+        let index = self.position(score, &member).unwrap_err();
+        self.ordered.insert(index, (score, member));
+
+        is_new
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    pub fn rank(&self, member: &str) -> Option<usize> {
+        let score = self.score(member)?;
+
+        self.position(score, member).ok()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ordered.len()
+    }
+
+    // members with their scores, lowest score first
+    pub fn iter(&self) -> impl Iterator<Item = (&String, f64)> {
+        self.ordered.iter().map(|(score, member)| (member, *score))
+    }
+
+    fn position(&self, score: f64, member: &str) -> Result<usize, usize> {
+        self.ordered
+            .binary_search_by(|(other_score, other_member)| {
+                compare(*other_score, other_member, score, member)
+            })
+    }
+}
+
+fn compare(score: f64, member: &str, other_score: f64, other_member: &str) -> Ordering {
+    score
+        .total_cmp(&other_score)
+        .then_with(|| member.cmp(other_member))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(sorted_set: &SortedSet) -> Vec<(&str, f64)> {
+        sorted_set
+            .iter()
+            .map(|(member, score)| (member.as_str(), score))
+            .collect()
+    }
+
+    #[test]
+    fn ordered_by_score_then_member() {
+        let mut sorted_set = SortedSet::new();
+        assert!(sorted_set.insert("b".to_string(), 2.0));
+        assert!(sorted_set.insert("c".to_string(), 1.0));
+        assert!(sorted_set.insert("a".to_string(), 2.0));
+
+        assert_eq!(
+            members(&sorted_set),
+            vec![("c", 1.0), ("a", 2.0), ("b", 2.0)]
+        );
+        assert_eq!(sorted_set.rank("c"), Some(0));
+        assert_eq!(sorted_set.rank("b"), Some(2));
+        assert_eq!(sorted_set.rank("d"), None);
+    }
+
+    #[test]
+    fn update_changes_the_rank() {
+        let mut sorted_set = SortedSet::new();
+        sorted_set.insert("a".to_string(), 1.0);
+        sorted_set.insert("b".to_string(), 2.0);
+
+        assert!(!sorted_set.insert("a".to_string(), 3.0));
+
+        assert_eq!(sorted_set.len(), 2);
+        assert_eq!(sorted_set.score("a"), Some(3.0));
+        assert_eq!(sorted_set.rank("a"), Some(1));
+        assert_eq!(sorted_set.rank("b"), Some(0));
+    }
+
+    #[test]
+    fn infinite_scores() {
+        let mut sorted_set = SortedSet::new();
+        sorted_set.insert("max".to_string(), f64::INFINITY);
+        sorted_set.insert("min".to_string(), f64::NEG_INFINITY);
+        sorted_set.insert("zero".to_string(), 0.0);
+
+        assert_eq!(
+            members(&sorted_set),
+            vec![
+                ("min", f64::NEG_INFINITY),
+                ("zero", 0.0),
+                ("max", f64::INFINITY)
+            ]
+        );
+    }
+}